@@ -6,8 +6,9 @@ use clap::*;
 use serde::{Serialize, Deserialize};
 use colored::Colorize;
 use hashbrown::HashMap;
-use image::{DynamicImage, ImageBuffer, Rgba};
+use image::{DynamicImage, ImageBuffer, Rgba, RgbaImage};
 use rayon::prelude::*;
+use crate::atlas_packing;
 use crate::places;
 use crate::log;
 use crate::log::macro_deps::*;
@@ -37,16 +38,85 @@ struct PackOrder {
     order: Vec<String>,
 }
 
+/// Empty pixels around every texture in the block atlas, filled with copies of the texture's edges.
+/// Filtering and mip levels blend neighboring pixels together, so this keeps textures from bleeding into each other.
+pub const ATLAS_PADDING: u32 = 8;
+
+/// Biggest the block atlas can get on either side. (The most that every GPU wgpu runs on supports.)
+pub const ATLAS_MAX_SIZE: u32 = 8192;
+
 #[derive(Debug, Serialize, Deserialize, Clone, Copy, Eq, PartialEq)]
 pub struct AtlasUVMapElement {
-    pub corner: (u32, u32), // Texture top-left corner.
-    pub size: (u32, u32), // Texture size.
+    pub corner: (u32, u32), // Texture top-left corner. (Inside of the padding.)
+    pub size: (u32, u32), // Texture size. (Without the padding.)
+    #[serde(default)]
+    pub padding: u32, // Gutter around the texture, on every side.
 }
 
-#[derive(Debug, Serialize, Deserialize, Clone, Eq, PartialEq)]
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
 pub struct BlockAtlasInfo {
     pub size: (u32, u32), // Atlas size.
     pub uv_map: HashMap<String, AtlasUVMapElement>,
+    #[serde(default = "default_mip_levels")]
+    pub mip_levels: u32, // Amount of mip levels, including the full size atlas. (See atlas_mip_file_name())
+    #[serde(default)]
+    pub animations: HashMap<String, AtlasAnimation>, // Animated textures. (Their uv_map entry shows the current frame.)
+}
+
+fn default_mip_levels() -> u32 {
+    return 1;
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub struct AtlasAnimation {
+    pub frames: Vec<AtlasUVMapElement>, // Where every frame is, in the order they play.
+    pub durations: Vec<f32>, // How long every frame shows, in seconds.
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub struct BlockTextureArrayInfo {
+    pub size: (u32, u32), // Size of every layer.
+    pub layers: Vec<String>, // Texture name of every layer.
+    #[serde(default)]
+    pub animations: HashMap<String, ArrayAnimation>, // Animated textures. (Their layer shows the current frame.)
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub struct ArrayAnimation {
+    pub frames: Vec<u32>, // Layer of every frame, in the order they play.
+    pub durations: Vec<f32>, // How long every frame shows, in seconds.
+}
+
+/// How long every frame of an animated texture shows, if its animation file doesn't say.
+pub const DEFAULT_FRAME_SECONDS: f32 = 0.1;
+
+// Animation file that sits next to an animated block texture. (Like water.png and water.toml)
+// The texture is a strip of frames, from top to bottom.
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct TextureAnimationFile {
+    frame_height: u32, // Height of every frame, in pixels.
+    #[serde(default)]
+    frames: Vec<u32>, // Order the frames play in, counting from the top. (Every frame from top to bottom if empty.)
+    #[serde(default)]
+    durations: Vec<f32>, // How long every frame shows, in seconds. (One for all of them, or one for every entry of frames.)
+}
+
+// A block texture, split up into its frames.
+struct BlockTexture {
+    name: String,
+    frames: Vec<RgbaImage>, // Every frame, from top to bottom. (Just the texture if it isn't animated.)
+    animation: Option<(Vec<usize>, Vec<f32>)>, // Frame order and durations, if it is animated.
+}
+
+impl BlockTexture {
+    // The frame that shows before the animation starts.
+    fn first_frame(&self) -> &RgbaImage {
+        return match &self.animation {
+            Some((order, _)) => &self.frames[order[0]],
+            None => &self.frames[0],
+        };
+    }
 }
 
 #[derive(Debug, Serialize, Deserialize, Default)]
@@ -71,93 +141,306 @@ impl PackInfo {
     }
 }
 
-// Build the block texture atlas.
-fn build_block_atlas_texture() -> Result<(), io::Error> {
-    let texture_files: Vec<Path> = directory::list_items(&places::assets().add_str("textures/block"))?
+// Open every block texture, and split the animated ones up into their frames. (Sorted by name, so builds always come out the same.)
+fn load_block_textures() -> Result<Vec<BlockTexture>, io::Error> {
+    let mut texture_files: Vec<Path> = directory::list_items(&places::assets().add_str("textures/block"))?
         .into_iter()
         .filter(|x| x.path_type() == PathType::File && x.to_string().ends_with(".png"))
         .collect();
 
-    let mut column_map: HashMap<u32, Vec<usize>> = HashMap::new();
-    let mut column_size_map: HashMap<u32, u32> = HashMap::new();
+    texture_files.sort_by_key(|x| x.basename());
 
-    for i in 0..texture_files.len() {
-        let texture = open_image(&texture_files[i])?;
+    let mut textures: Vec<BlockTexture> = Vec::new();
 
-        let width: u32 = texture.width();
-        let height: u32 = texture.height();
+    for f in texture_files.iter() {
+        let name: String = f.basename().replace(".png", "");
+        let image: RgbaImage = open_image(f)?.into_rgba8();
+        let animation_path = f.parent_path().add_str(&format!("{}.toml", name));
 
-        if column_map.contains_key(&width) == false {
-            column_map.insert(width, Vec::new());
-            column_size_map.insert(width, 0);
+        if animation_path.exists() == false {
+            textures.push(BlockTexture { name, frames: vec![image], animation: None });
+
+            continue;
         }
 
-        let mut index_array: Vec<usize> = column_map.get(&width).unwrap().iter().map(|x| *x).collect(); // Guarenteed value.
-        let mut column_size = *column_size_map.get(&width).unwrap(); // Guarenteed value.
+        let invalid = |reason: &str| io::Error::new(io::ErrorKind::InvalidData, format!("Invalid animation for block texture '{}': {}", name, reason));
 
-        index_array.push(i);
-        column_size += height;
+        let animation: TextureAnimationFile = match toml::from_str(&file::read(&animation_path)?) {
+            Ok(o) => o,
+            Err(e) => return Err(invalid(&e.to_string())),
+        };
+
+        if animation.frame_height == 0 || image.height() % animation.frame_height != 0 {
+            return Err(invalid("the texture height isn't a multiple of frame_height"));
+        }
+
+        let frame_count = image.height() / animation.frame_height;
+
+        let frames: Vec<RgbaImage> = (0..frame_count)
+            .map(|i| image::imageops::crop_imm(&image, 0, i * animation.frame_height, image.width(), animation.frame_height).to_image())
+            .collect();
 
-        column_map.insert(width, index_array);
-        column_size_map.insert(width, column_size);
+        let order: Vec<usize> = match animation.frames.is_empty() {
+            true => (0..frame_count as usize).collect(),
+            false => animation.frames.iter().map(|x| *x as usize).collect(),
+        };
+
+        if order.iter().any(|x| *x >= frames.len()) {
+            return Err(invalid("frames has a frame that isn't in the texture"));
+        }
+
+        let durations: Vec<f32> = match animation.durations.len() {
+            0 => vec![DEFAULT_FRAME_SECONDS; order.len()],
+            1 => vec![animation.durations[0]; order.len()],
+            _ => animation.durations,
+        };
+
+        if durations.len() != order.len() {
+            return Err(invalid("durations needs one entry, or one for every frame"));
+        }
+
+        if durations.iter().any(|x| *x <= 0.0) {
+            return Err(invalid("durations have to be more than 0"));
+        }
+
+        textures.push(BlockTexture { name, frames, animation: Some((order, durations)) });
+    }
+
+    return Ok(textures);
+}
+
+// Build the block texture atlas.
+// Every texture gets a gutter of ATLAS_PADDING pixels around it, and every mip level is shrunk one tile at a time.
+fn build_block_atlas_texture() -> Result<(), io::Error> {
+    let textures: Vec<BlockTexture> = load_block_textures()?;
+
+    // Every texture gets a tile that shows its current frame, and animated textures get one more for every frame. (So there is always something to copy the next frame from.)
+    let mut padded_textures: Vec<RgbaImage> = Vec::new();
+    // Tile of the current frame, and tile of every frame, of every texture.
+    let mut texture_tiles: Vec<(usize, Vec<usize>)> = Vec::new();
+
+    for t in textures.iter() {
+        let tile = padded_textures.len();
+
+        padded_textures.push(pad_texture(t.first_frame(), ATLAS_PADDING));
+
+        let mut frame_tiles: Vec<usize> = Vec::new();
+
+        if t.animation.is_some() {
+            for f in t.frames.iter() {
+                frame_tiles.push(padded_textures.len());
+                padded_textures.push(pad_texture(f, ATLAS_PADDING));
+            }
+        }
+
+        texture_tiles.push((tile, frame_tiles));
     }
 
-    let mut atlas_size: (u32, u32) = (0, 0);
+    let sizes: Vec<(u32, u32)> = padded_textures.iter().map(|x| x.dimensions()).collect();
+
+    let packing = match atlas_packing::pack(&sizes, ATLAS_MAX_SIZE) {
+        Ok(o) => o,
+        Err(e) => return Err(io::Error::new(io::ErrorKind::Other, format!("Failed to pack the block atlas: {}", e))),
+    };
+
+    let atlas_size: (u32, u32) = packing.size;
+
+    let texture_area: u64 = padded_textures.iter().map(|x| (x.width() - ATLAS_PADDING * 2) as u64 * (x.height() - ATLAS_PADDING * 2) as u64).sum();
+
+    log::info!(
+        "Packed {} textures into a {}x{} block atlas, {:.1}% of it is used ({:.1}% without padding).",
+        textures.len(),
+        atlas_size.0,
+        atlas_size.1,
+        packing.efficiency(&sizes) * 100.0,
+        texture_area as f32 / (atlas_size.0 as u64 * atlas_size.1 as u64) as f32 * 100.0,
+    );
 
-    atlas_size.1 = *column_size_map.values().max().unwrap();
+    // Every level halves the gutters, so stop before they run out, or before a tile would start halfway through a pixel.
+    let mut mip_levels: u32 = 1;
 
-    for i in column_size_map.keys() {
-        atlas_size.0 += i;
+    while ATLAS_PADDING >> mip_levels > 0 && padded_textures.iter().all(|x| x.width() % (1 << mip_levels) == 0 && x.height() % (1 << mip_levels) == 0) {
+        mip_levels += 1;
     }
 
     // <Texture Name, (Top-Left Corner Pixel Coordinates, Texture Size In Pixels)>
     let mut uv_map: HashMap<String, AtlasUVMapElement> = HashMap::new();
 
-    let mut pixel_map: HashMap<(u32, u32), Rgba<u8>> = HashMap::new();
+    let mut mips: Vec<RgbaImage> = (0..mip_levels)
+        .map(|level| ImageBuffer::from_pixel(atlas_size.0 >> level, atlas_size.1 >> level, Rgba::from([0, 0, 0, 0])))
+        .collect();
 
-    let mut pixel_offset: (u32, u32) = (0, 0);
+    // UVs point to the texture itself, not its gutter.
+    let uv_element = |i: usize| AtlasUVMapElement {
+        corner: (packing.positions[i].0 + ATLAS_PADDING, packing.positions[i].1 + ATLAS_PADDING),
+        size: (padded_textures[i].width() - ATLAS_PADDING * 2, padded_textures[i].height() - ATLAS_PADDING * 2),
+        padding: ATLAS_PADDING,
+    };
 
-    for c in column_map.keys() {
-        for i in column_map.get(c).unwrap().iter() {
-            let texture: DynamicImage = open_image(&texture_files[*i])?;
+    let mut animations: HashMap<String, AtlasAnimation> = HashMap::new();
 
-            let texture_name: String = texture_files[*i].basename().replace(".png", "");
+    for (t, (tile, frame_tiles)) in textures.iter().zip(texture_tiles.iter()) {
+        uv_map.insert(t.name.clone(), uv_element(*tile));
 
-            let width: u32 = texture.width();
-            let height: u32 = texture.height();
+        if let Some((order, durations)) = &t.animation {
+            animations.insert(t.name.clone(), AtlasAnimation {
+                frames: order.iter().map(|f| uv_element(frame_tiles[*f])).collect(),
+                durations: durations.clone(),
+            });
+        }
+    }
 
-            uv_map.insert(texture_name, AtlasUVMapElement { corner: pixel_offset, size: (width, height) });
+    for (texture, position) in padded_textures.iter().zip(packing.positions.iter()) {
+        // Each tile is shrunk by itself, so its pixels never get mixed with the tiles next to it.
+        for (level, mip) in generate_mips(texture).iter().take(mip_levels as usize).enumerate() {
+            image::imageops::replace(&mut mips[level], mip, (position.0 >> level) as i64, (position.1 >> level) as i64);
+        }
+    }
 
-            for x in 0..width {
-                for y in 0..height {
-                    pixel_map.insert((x + pixel_offset.0, y + pixel_offset.1), *texture.clone().into_rgba8().get_pixel(x, y));
-                }
+    for (level, mip) in mips.iter().enumerate() {
+        match mip.save(places::custom_built_assets().add_str(&atlas_mip_file_name(level as u32)).to_string()) {
+            Ok(_) => (),
+            Err(_) => return Err(io::Error::new(io::ErrorKind::Other, "Failed to save block atlas!")),
+        };
+    }
+
+    file::write(match toml::to_string(&BlockAtlasInfo { size: atlas_size, uv_map, mip_levels, animations }) {
+        Ok(o) => o,
+        Err(_) => {
+            return Err(io::Error::new(io::ErrorKind::Other, "Failed to serialize atlas UV map!"));
+        },
+    }.as_str(), &places::custom_built_assets().add_str("block_atlas.toml"))?;
+
+    return Ok(());
+}
+
+/// File name of one mip level of the block atlas, in the built assets. (Level 0 is the full size atlas.)
+pub fn atlas_mip_file_name(level: u32) -> String {
+    return match level {
+        0 => String::from("block_atlas.png"),
+        _ => format!("block_atlas_mip{}.png", level),
+    };
+}
+
+// Surround a texture with copies of its edge pixels. (Corners get filled with the corner pixels.)
+fn pad_texture(texture: &RgbaImage, padding: u32) -> RgbaImage {
+    let (width, height) = texture.dimensions();
+
+    return ImageBuffer::from_fn(width + padding * 2, height + padding * 2, |x, y| {
+        let source_x = x.saturating_sub(padding).min(width - 1);
+        let source_y = y.saturating_sub(padding).min(height - 1);
+
+        *texture.get_pixel(source_x, source_y)
+    });
+}
+
+// Build the block texture array, by stacking every block texture on top of each other. (One layer per texture, plus the frames of animated ones)
+// Only works if all the textures are the same size, otherwise the game has to use the atlas.
+fn build_block_texture_array() -> Result<(), io::Error> {
+    let image_path = places::custom_built_assets().add_str("block_textures.png");
+    let info_path = places::custom_built_assets().add_str("block_textures.toml");
+
+    // Don't leave an old texture array around, if this build can't make one.
+    for p in [&image_path, &info_path] {
+        if p.exists() {
+            fs_action::delete(p)?;
+        }
+    }
+
+    let textures: Vec<BlockTexture> = load_block_textures()?;
+
+    // Like the atlas, every texture gets a layer for its current frame, and animated textures get one more for every frame.
+    let mut layer_images: Vec<&RgbaImage> = Vec::new();
+    let mut layers: Vec<String> = Vec::new();
+    let mut animations: HashMap<String, ArrayAnimation> = HashMap::new();
+
+    for t in textures.iter() {
+        layer_images.push(t.first_frame());
+        layers.push(t.name.clone());
+
+        if let Some((order, durations)) = &t.animation {
+            let first_layer = layers.len() as u32;
+
+            for (i, f) in t.frames.iter().enumerate() {
+                layer_images.push(f);
+                layers.push(format!("{}#{}", t.name, i));
             }
 
-            pixel_offset.1 += height;
+            animations.insert(t.name.clone(), ArrayAnimation {
+                frames: order.iter().map(|f| first_layer + *f as u32).collect(),
+                durations: durations.clone(),
+            });
         }
+    }
+
+    let size: (u32, u32) = match layer_images.first() {
+        Some(s) => s.dimensions(),
+        None => return Ok(()),
+    };
+
+    if layer_images.iter().any(|x| x.dimensions() != size) {
+        log::warning!("Block textures aren't all the same size, so the block texture array can't be built. (The block atlas still works.)");
 
-        pixel_offset.1 = 0;
-        pixel_offset.0 += c;
+        return Ok(());
     }
 
-    let atlas = ImageBuffer::from_fn(atlas_size.0, atlas_size.1, |x, y| { *pixel_map.get(&(x, y)).unwrap_or(&Rgba::from([0, 0, 0, 0])) });
+    let mut array: RgbaImage = ImageBuffer::new(size.0, size.1 * layer_images.len() as u32);
 
-    match atlas.save(places::custom_built_assets().add_str("block_atlas.png").to_string()) {
+    for (i, t) in layer_images.iter().enumerate() {
+        for (x, y, pixel) in t.enumerate_pixels() {
+            array.put_pixel(x, y + size.1 * i as u32, *pixel);
+        }
+    }
+
+    match array.save(image_path.to_string()) {
         Ok(_) => (),
-        Err(_) => return Err(io::Error::new(io::ErrorKind::Other, "Failed to save block atlas!")),
+        Err(_) => return Err(io::Error::new(io::ErrorKind::Other, "Failed to save block texture array!")),
     };
 
-    file::write(match toml::to_string(&BlockAtlasInfo { size: atlas.dimensions(), uv_map }) {
+    file::write(match toml::to_string(&BlockTextureArrayInfo { size, layers, animations }) {
         Ok(o) => o,
         Err(_) => {
-            return Err(io::Error::new(io::ErrorKind::Other, "Failed to serialize atlas UV map!"));
+            return Err(io::Error::new(io::ErrorKind::Other, "Failed to serialize block texture array info!"));
         },
-    }.as_str(), &places::custom_built_assets().add_str("block_atlas.toml"))?;
+    }.as_str(), &info_path)?;
 
     return Ok(());
 }
 
+/// Every mip level of an image, starting with the image itself and halving until it is 1x1.
+/// Every pixel is the average of the 2x2 pixels above it. (Odd sizes drop their last row or column.)
+pub fn generate_mips(image: &RgbaImage) -> Vec<RgbaImage> {
+    let mut mips: Vec<RgbaImage> = vec![image.clone()];
+
+    while let Some(last) = mips.last() {
+        let (width, height) = last.dimensions();
+
+        if width <= 1 && height <= 1 {
+            break;
+        }
+
+        let (new_width, new_height) = ((width / 2).max(1), (height / 2).max(1));
+
+        let next = ImageBuffer::from_fn(new_width, new_height, |x, y| {
+            let mut sum = [0u32; 4];
+
+            for (dx, dy) in [(0, 0), (1, 0), (0, 1), (1, 1)] {
+                let pixel = last.get_pixel((x * 2 + dx).min(width - 1), (y * 2 + dy).min(height - 1));
+
+                for c in 0..4 {
+                    sum[c] += pixel[c] as u32;
+                }
+            }
+
+            Rgba(sum.map(|c| ((c + 2) / 4) as u8))
+        });
+
+        mips.push(next);
+    }
+
+    return mips;
+}
+
 /// Get textures in a directory. Vec<(Image, LocalPathName)>
 pub fn get_textures_in(path: &Path) -> Result<(Vec<DynamicImage>, Vec<String>), io::Error> {
     let files: Vec<Path> = directory::list_items(path)?
@@ -326,6 +609,11 @@ pub fn build_assets() -> Result<(), io::Error> {
         fs_action::mv(i, &creation_path)?;
     }
 
+    // Build block texture array. (Before the atlas, since the game reloads both once the atlas info changes.)
+    log::generic!("Building block texture array...");
+
+    build_block_texture_array()?;
+
     // Build block atlas.
     log::generic!("Building block atlas...");
 
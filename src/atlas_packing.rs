@@ -0,0 +1,271 @@
+#![allow(dead_code)]
+
+use std::io;
+
+/// Where every rectangle ended up, and how big the area they were packed into is.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Packing {
+    /// Always a power of two on both sides.
+    pub size: (u32, u32),
+    /// Top-left corner of every rectangle, in the same order they were given in.
+    pub positions: Vec<(u32, u32)>,
+}
+
+impl Packing {
+    /// How much of the area is covered by the rectangles. (0.0 to 1.0)
+    pub fn efficiency(&self, sizes: &[(u32, u32)]) -> f32 {
+        let used: u64 = sizes.iter().map(|x| x.0 as u64 * x.1 as u64).sum();
+
+        return used as f32 / (self.size.0 as u64 * self.size.1 as u64) as f32;
+    }
+}
+
+// One flat piece of the skyline. Everything below it is taken.
+#[derive(Debug, Clone, Copy)]
+struct Segment {
+    x: u32,
+    y: u32,
+    width: u32,
+}
+
+/// Pack rectangles into the smallest power of two area that fits them, no bigger than max_size on either side.
+/// Uses the skyline bottom-left heuristic, and always gives the same layout for the same rectangles.
+pub fn pack(sizes: &[(u32, u32)], max_size: u32) -> Result<Packing, io::Error> {
+    if sizes.is_empty() {
+        return Ok(Packing { size: (1, 1), positions: Vec::new() });
+    }
+
+    let area: u64 = sizes.iter().map(|x| x.0 as u64 * x.1 as u64).sum();
+    let widest = sizes.iter().map(|x| x.0).max().unwrap(); // Guarenteed value.
+    let tallest = sizes.iter().map(|x| x.1).max().unwrap(); // Guarenteed value.
+
+    // Every power of two area that could fit, smallest first. (Wider than tall when it isn't square.)
+    let mut candidates: Vec<(u32, u32)> = Vec::new();
+
+    let mut height = tallest.next_power_of_two();
+
+    while height <= max_size {
+        for width in [height, height * 2] {
+            if width >= widest && width <= max_size && width as u64 * height as u64 >= area {
+                candidates.push((width, height));
+            }
+        }
+
+        height *= 2;
+    }
+
+    candidates.sort_by_key(|x| (x.0 as u64 * x.1 as u64, x.0));
+
+    // Tallest first (then widest), which leaves the least gaps under the skyline. Ties keep their original order.
+    let mut order: Vec<usize> = (0..sizes.len()).collect();
+
+    order.sort_by_key(|i| (u32::MAX - sizes[*i].1, u32::MAX - sizes[*i].0, *i));
+
+    for size in candidates {
+        if let Some(positions) = pack_into(sizes, &order, size) {
+            return Ok(Packing { size, positions });
+        }
+    }
+
+    return Err(io::Error::new(io::ErrorKind::Other, format!("Textures don't fit in a {}x{} atlas!", max_size, max_size)));
+}
+
+// Try to pack every rectangle into an area of a certain size. (None if they don't fit.)
+fn pack_into(sizes: &[(u32, u32)], order: &[usize], size: (u32, u32)) -> Option<Vec<(u32, u32)>> {
+    let mut skyline: Vec<Segment> = vec![Segment { x: 0, y: 0, width: size.0 }];
+    let mut positions: Vec<(u32, u32)> = vec![(0, 0); sizes.len()];
+
+    for i in order.iter() {
+        let (width, height) = sizes[*i];
+
+        // Segment to start at, and how far down the rectangle would sit. (The skyline grows downwards, like image coordinates.)
+        let mut best: Option<(usize, u32)> = None;
+
+        for start in 0..skyline.len() {
+            let y = match fit(&skyline, start, width, size.0) {
+                Some(s) => s,
+                None => continue,
+            };
+
+            if y + height > size.1 {
+                continue;
+            }
+
+            // Closest to the top wins, then the leftmost spot. (Segments go left to right, so the first one found is the leftmost.)
+            let better = match best {
+                Some((_, best_y)) => y < best_y,
+                None => true,
+            };
+
+            if better {
+                best = Some((start, y));
+            }
+        }
+
+        let (s, y) = best?;
+        let x = skyline[s].x;
+
+        positions[*i] = (x, y);
+
+        place(&mut skyline, s, Segment { x, y: y + height, width });
+    }
+
+    return Some(positions);
+}
+
+// How far down a rectangle would sit if its left edge starts at a segment. (None if it would stick out of the side.)
+fn fit(skyline: &[Segment], start: usize, width: u32, area_width: u32) -> Option<u32> {
+    let x = skyline[start].x;
+
+    if x + width > area_width {
+        return None;
+    }
+
+    let mut y: u32 = 0;
+    let mut covered: u32 = 0;
+
+    for segment in skyline[start..].iter() {
+        if covered >= width {
+            break;
+        }
+
+        y = y.max(segment.y);
+        covered += segment.width;
+    }
+
+    return Some(y);
+}
+
+// Put a new segment on top of the skyline, starting at a segment, and cut away everything it covers.
+fn place(skyline: &mut Vec<Segment>, start: usize, new: Segment) {
+    skyline.insert(start, new);
+
+    let end = new.x + new.width;
+
+    // Shrink (or remove) the segments that the new one covers.
+    while start + 1 < skyline.len() {
+        let next = &mut skyline[start + 1];
+
+        if next.x >= end {
+            break;
+        }
+
+        let next_end = next.x + next.width;
+
+        if next_end <= end {
+            skyline.remove(start + 1);
+        } else {
+            next.width = next_end - end;
+            next.x = end;
+
+            break;
+        }
+    }
+
+    // Neighbors at the same height are really one segment.
+    let mut i = 0;
+
+    while i + 1 < skyline.len() {
+        if skyline[i].y == skyline[i + 1].y {
+            skyline[i].width += skyline[i + 1].width;
+            skyline.remove(i + 1);
+        } else {
+            i += 1;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::atlas_packing::{pack, Packing};
+
+    // A mix of 16x16 and 32x32 tiles, plus some that aren't square.
+    fn mixed_sizes() -> Vec<(u32, u32)> {
+        let mut sizes: Vec<(u32, u32)> = Vec::new();
+
+        for i in 0..40 {
+            sizes.push(match i % 5 {
+                0 | 1 => (16, 16),
+                2 => (32, 32),
+                3 => (16, 48),
+                _ => (64, 16),
+            });
+        }
+
+        return sizes;
+    }
+
+    fn assert_valid(sizes: &[(u32, u32)], packing: &Packing, max_size: u32) {
+        assert_eq!(packing.positions.len(), sizes.len());
+        assert!(packing.size.0.is_power_of_two() && packing.size.1.is_power_of_two());
+        assert!(packing.size.0 <= max_size && packing.size.1 <= max_size);
+
+        let rects: Vec<(u32, u32, u32, u32)> = sizes.iter()
+            .zip(packing.positions.iter())
+            .map(|(s, p)| (p.0, p.1, p.0 + s.0, p.1 + s.1))
+            .collect();
+
+        for (i, a) in rects.iter().enumerate() {
+            assert!(a.2 <= packing.size.0 && a.3 <= packing.size.1, "Rectangle {} is out of bounds: {:?}", i, a);
+
+            for (j, b) in rects.iter().enumerate().skip(i + 1) {
+                let overlaps = a.0 < b.2 && b.0 < a.2 && a.1 < b.3 && b.1 < a.3;
+
+                assert!(overlaps == false, "Rectangles {} and {} overlap: {:?} {:?}", i, j, a, b);
+            }
+        }
+    }
+
+    #[test]
+    fn same_input_same_layout() {
+        let sizes = mixed_sizes();
+
+        let first = pack(&sizes, 1024).unwrap();
+
+        for _ in 0..5 {
+            assert_eq!(pack(&sizes, 1024).unwrap(), first);
+        }
+    }
+
+    #[test]
+    fn uniform_tiles() {
+        let sizes = vec![(16, 16); 64];
+        let packing = pack(&sizes, 1024).unwrap();
+
+        assert_valid(&sizes, &packing, 1024);
+
+        // Tiles that are all the same size pack perfectly.
+        assert_eq!(packing.size, (128, 128));
+    }
+
+    #[test]
+    fn mixed_tiles() {
+        let sizes = mixed_sizes();
+        let packing = pack(&sizes, 1024).unwrap();
+
+        assert_valid(&sizes, &packing, 1024);
+    }
+
+    #[test]
+    fn empty() {
+        let packing = pack(&[], 1024).unwrap();
+
+        assert!(packing.positions.is_empty());
+    }
+
+    #[test]
+    fn too_big() {
+        // Too wide on its own.
+        assert!(pack(&[(128, 16)], 64).is_err());
+
+        // Small enough on their own, but too many together.
+        assert!(pack(&vec![(32, 32); 5], 64).is_err());
+
+        // Exactly fills it.
+        let sizes = vec![(32, 32); 4];
+        let packing = pack(&sizes, 64).unwrap();
+
+        assert_valid(&sizes, &packing, 64);
+        assert_eq!(packing.size, (64, 64));
+    }
+}
@@ -0,0 +1,420 @@
+#![allow(dead_code)]
+
+use std::hint::black_box;
+use std::time::{Duration, Instant};
+use hashbrown::HashMap;
+use crate::block::{BlockIdTable, BlockType};
+use crate::block_atlas::BlockAtlas;
+use crate::chunk::{BlockPos, Chunk, ChunkPos, CHUNK_SIZE};
+use crate::chunk_storage::CHUNK_VOLUME;
+use crate::filesystem::*;
+use crate::log;
+use crate::log::macro_deps::*;
+use crate::mesher::{self, MeshBuilder, MeshData};
+use crate::region::{ChunkCompression, RegionFile, RegionPos, REGION_SIZE};
+use crate::voxel::Voxel;
+use crate::world;
+use crate::world_generation;
+
+const ITERATIONS: u32 = 200;
+
+// The old way of putting meshes together is way too slow to run as many times.
+const SLOW_ITERATIONS: u32 = 5;
+
+/// Run all the benchmarks, and print the results.
+pub fn run() {
+    log::info!("Running benchmarks... ({} iterations each)", ITERATIONS);
+
+    chunk_storage();
+    region_files();
+    meshing();
+    welding();
+}
+
+// Run a function a bunch of times, and return the average time it took.
+fn time<F: FnMut()>(f: F) -> Duration {
+    return time_n(ITERATIONS, f);
+}
+
+fn time_n<F: FnMut()>(iterations: u32, mut f: F) -> Duration {
+    let start = Instant::now();
+
+    for _ in 0..iterations {
+        f();
+    }
+
+    return start.elapsed() / iterations;
+}
+
+// Old vs. new, and how many times faster the new one is.
+fn report(name: &str, labels: (&str, &str), old: Duration, new: Duration) {
+    log::generic!(
+        "{:<28} {}: {:>10.2?}   {}: {:>10.2?}   ({:.2}x)",
+        name,
+        labels.0,
+        old,
+        labels.1,
+        new,
+        old.as_secs_f64() / new.as_secs_f64().max(f64::EPSILON),
+    );
+}
+
+fn all_positions() -> Vec<BlockPos> {
+    let mut positions: Vec<BlockPos> = Vec::with_capacity(CHUNK_VOLUME);
+
+    for x in 0..CHUNK_SIZE.x {
+        for y in 0..CHUNK_SIZE.y {
+            for z in 0..CHUNK_SIZE.z {
+                positions.push(BlockPos::new_unchecked(x, y, z));
+            }
+        }
+    }
+
+    return positions;
+}
+
+// The old chunk storage, kept around to compare against.
+fn map_set(map: &mut HashMap<BlockPos, BlockType>, pos: BlockPos, block: BlockType) {
+    match block {
+        BlockType::AIR => map.remove(&pos),
+        _ => map.insert(pos, block),
+    };
+}
+
+fn map_heap_size(map: &HashMap<BlockPos, BlockType>) -> usize {
+    // One control byte per bucket, plus the key and value.
+    return map.capacity() * (std::mem::size_of::<(BlockPos, BlockType)>() + 1);
+}
+
+/// Palette storage vs. HashMap<BlockPos, BlockType>.
+fn chunk_storage() {
+    log::info!("Chunk storage:");
+
+    let positions = all_positions();
+
+    // A generated chunk, so the block distribution is realistic.
+    let mut generated = Chunk::new(ChunkPos::new(0, -1, 0));
+    world_generation::regular(&mut generated);
+
+    let blocks: Vec<BlockType> = positions.iter().map(|p| generated.get_block(*p)).collect();
+
+    let fill_map = time(|| {
+        let mut map: HashMap<BlockPos, BlockType> = HashMap::new();
+
+        for (p, b) in positions.iter().zip(blocks.iter()) {
+            map_set(&mut map, *p, *b);
+        }
+
+        black_box(map);
+    });
+
+    let fill_palette = time(|| {
+        let mut chunk = Chunk::new(ChunkPos::default());
+
+        for (p, b) in positions.iter().zip(blocks.iter()) {
+            chunk.set_block(*p, *b);
+        }
+
+        black_box(chunk);
+    });
+
+    report("Fill generated chunk", ("map", "palette"), fill_map, fill_palette);
+
+    let mut map: HashMap<BlockPos, BlockType> = HashMap::new();
+
+    for (p, b) in positions.iter().zip(blocks.iter()) {
+        map_set(&mut map, *p, *b);
+    }
+
+    // Make sure both storages actually agree before comparing them.
+    for p in positions.iter() {
+        assert_eq!(map.get(p).copied().unwrap_or(BlockType::AIR), generated.get_block(*p));
+    }
+
+    let read_map = time(|| {
+        for p in positions.iter() {
+            black_box(map.get(p).copied().unwrap_or(BlockType::AIR));
+        }
+    });
+
+    let read_palette = time(|| {
+        for p in positions.iter() {
+            black_box(generated.get_block(*p));
+        }
+    });
+
+    report("Read every block", ("map", "palette"), read_map, read_palette);
+
+    let solid_map = time(|| {
+        let mut map: HashMap<BlockPos, BlockType> = HashMap::new();
+
+        for p in positions.iter() {
+            map_set(&mut map, *p, BlockType::STONE);
+        }
+
+        black_box(map);
+    });
+
+    let solid_palette = time(|| {
+        let mut chunk = Chunk::new(ChunkPos::default());
+
+        for p in positions.iter() {
+            chunk.set_block(*p, BlockType::STONE);
+        }
+
+        chunk.optimize_storage();
+
+        black_box(chunk);
+    });
+
+    report("Fill solid chunk", ("map", "palette"), solid_map, solid_palette);
+
+    let mut solid = Chunk::new(ChunkPos::default());
+
+    for p in positions.iter() {
+        solid.set_block(*p, BlockType::STONE);
+    }
+
+    solid.optimize_storage();
+
+    let mut solid_map: HashMap<BlockPos, BlockType> = HashMap::new();
+
+    for p in positions.iter() {
+        map_set(&mut solid_map, *p, BlockType::STONE);
+    }
+
+    log::generic!(
+        "Memory (generated chunk)     map: {:>8} bytes   palette: {:>8} bytes",
+        map_heap_size(&map),
+        generated.storage().heap_size(),
+    );
+
+    log::generic!(
+        "Memory (solid chunk)         map: {:>8} bytes   palette: {:>8} bytes",
+        map_heap_size(&solid_map),
+        solid.storage().heap_size(),
+    );
+}
+
+/// Save a region full of generated chunks, then load it back.
+fn region_files() {
+    log::info!("Region files:");
+
+    let directory = Path::new(&std::env::temp_dir().join("simplay_region_bench").display().to_string());
+
+    if directory.exists() {
+        fs_action::delete(&directory).unwrap();
+    }
+
+    directory::create(&directory).unwrap();
+
+    let mut chunks: Vec<Chunk> = Vec::new();
+
+    for x in 0..REGION_SIZE {
+        for y in 0..REGION_SIZE {
+            for z in 0..REGION_SIZE {
+                let mut chunk = Chunk::new(ChunkPos::new(x, y - REGION_SIZE, z));
+                world_generation::regular(&mut chunk);
+
+                chunks.push(chunk);
+            }
+        }
+    }
+
+    let ids = BlockIdTable::from_registry();
+
+    for compression in [ChunkCompression::None, ChunkCompression::Zlib] {
+        let start = Instant::now();
+
+        let mut region = RegionFile::open(&directory, RegionPos::new(0, -1, 0)).unwrap();
+
+        for chunk in chunks.iter() {
+            region.write_chunk(chunk, compression, &ids).unwrap();
+        }
+
+        region.save().unwrap();
+
+        let save_time = start.elapsed();
+        let start = Instant::now();
+
+        let region = RegionFile::open(&directory, RegionPos::new(0, -1, 0)).unwrap();
+
+        for chunk in chunks.iter() {
+            let loaded = region.read_chunk(chunk.pos, &ids).unwrap().unwrap();
+
+            assert_eq!(loaded.storage(), chunk.storage());
+        }
+
+        let load_time = start.elapsed();
+
+        let size = std::fs::metadata(directory.add_str(&RegionPos::new(0, -1, 0).file_name()).to_string()).unwrap().len();
+
+        log::generic!(
+            "{:<28} save: {:>10.2?}   load: {:>10.2?}   size: {:>8} bytes",
+            format!("{} chunks ({:?})", chunks.len(), compression),
+            save_time,
+            load_time,
+            size,
+        );
+    }
+
+    fs_action::delete(&directory).unwrap();
+}
+
+// The old way of putting meshes together, kept around to compare against.
+fn combine_indices(indices_vec: &Vec<Vec<u32>>) -> Vec<u32> {
+    let mut new_vec: Vec<u32> = Vec::new();
+
+    for v in indices_vec.iter() {
+        let offset: u32 = match new_vec.iter().max() {
+            Some(s) => *s + 1,
+            None => 0,
+        };
+
+        for i in v.iter() {
+            new_vec.push(i + offset);
+        }
+    }
+
+    return new_vec;
+}
+
+// Every face of a chunk as its own piece of mesh, like the mesher makes them. (Outside of the chunk is air.)
+fn chunk_faces(chunk: &Chunk, atlas: &BlockAtlas) -> Vec<(Vec<MeshData>, Vec<u32>)> {
+    let mut faces: Vec<(Vec<MeshData>, Vec<u32>)> = Vec::new();
+
+    for p in all_positions() {
+        let block = chunk.get_block(p);
+
+        if block == BlockType::AIR {
+            continue;
+        }
+
+        let mut voxel = Voxel::new((p.x, p.y, p.z), block);
+
+        for d in world::Direction::all() {
+            let (dx, dy, dz) = d.offset_with_position((p.x as isize, p.y as isize, p.z as isize));
+
+            if chunk.get_block(BlockPos::new_unchecked(dx as u8, dy as u8, dz as u8)) == BlockType::AIR {
+                voxel.enable_side(d);
+            }
+        }
+
+        for d in voxel.get_sides() {
+            faces.push(voxel.get_side_as_mdi(d, (1, 1, 1), atlas).unwrap());
+        }
+    }
+
+    return faces;
+}
+
+/// MeshBuilder vs. combine_indices(), on full chunks.
+fn meshing() {
+    log::info!("Meshing:");
+
+    let atlas = BlockAtlas::placeholder();
+
+    let mut solid = Chunk::new(ChunkPos::default());
+    let mut checkerboard = Chunk::new(ChunkPos::default());
+
+    for p in all_positions() {
+        solid.set_block(p, BlockType::STONE);
+
+        // Every block shows all of its faces, which is as many faces as a chunk can have.
+        if (p.x + p.y + p.z) % 2 == 0 {
+            checkerboard.set_block(p, BlockType::STONE);
+        }
+    }
+
+    for (name, chunk) in [("solid", &solid), ("checkerboard", &checkerboard)] {
+        let faces = chunk_faces(chunk, &atlas);
+
+        let old = time_n(SLOW_ITERATIONS, || {
+            let mut mesh_data: Vec<MeshData> = Vec::new();
+            let mut indices: Vec<u32> = Vec::new();
+
+            for (m, i) in faces.iter() {
+                mesh_data.extend_from_slice(m);
+                indices = combine_indices(&vec![indices, i.clone()]);
+            }
+
+            black_box((mesh_data, indices));
+        });
+
+        let new = time(|| {
+            let mut builder = MeshBuilder::with_capacity(faces.len() * 4, faces.len() * 6);
+
+            for (m, i) in faces.iter() {
+                builder.push(m, i);
+            }
+
+            black_box(builder);
+        });
+
+        report(&format!("Assemble {} chunk", name), ("combine_indices", "builder"), old, new);
+
+        // Make sure both ways actually make the same mesh before comparing them.
+        let mut builder = MeshBuilder::new();
+        let mut indices: Vec<u32> = Vec::new();
+
+        for (m, i) in faces.iter() {
+            builder.push(m, i);
+            indices = combine_indices(&vec![indices, i.clone()]);
+        }
+
+        assert_eq!(builder.indices(), &indices);
+
+        let mesh_time = time(|| {
+            black_box(chunk.mesh(&atlas));
+        });
+
+        log::generic!("{:<28} mesh: {:>10.2?}   faces: {:>8}", format!("Chunk::mesh() ({})", name), mesh_time, faces.len());
+    }
+}
+
+/// Welding the vertices of whole chunk meshes.
+fn welding() {
+    log::info!("Vertex welding:");
+
+    let atlas = BlockAtlas::placeholder();
+
+    let mut generated = Chunk::new(ChunkPos::new(0, -1, 0));
+    world_generation::regular(&mut generated);
+
+    let mut solid = Chunk::new(ChunkPos::default());
+
+    for p in all_positions() {
+        solid.set_block(p, BlockType::STONE);
+    }
+
+    for (name, chunk) in [("generated", &generated), ("solid", &solid)] {
+        let mut builder = MeshBuilder::new();
+
+        for (m, i) in chunk_faces(chunk, &atlas) {
+            builder.push(&m, &i);
+        }
+
+        let weld_time = time(|| {
+            black_box(mesher::optimize::share_vertices(&builder));
+        });
+
+        let welded = mesher::optimize::share_vertices(&builder);
+
+        // Every triangle has to stay exactly the same, only the vertices get shared.
+        assert_eq!(builder.index_count(), welded.index_count());
+
+        for (a, b) in builder.indices().iter().zip(welded.indices().iter()) {
+            assert_eq!(builder.mesh_data()[*a as usize].to_hashable(), welded.mesh_data()[*b as usize].to_hashable());
+        }
+
+        assert!(welded.vertex_count() < builder.vertex_count());
+
+        log::generic!(
+            "{:<28} weld: {:>10.2?}   vertices: {:>8} -> {:>8}",
+            format!("Weld {} chunk", name),
+            weld_time,
+            builder.vertex_count(),
+            welded.vertex_count(),
+        );
+    }
+}
@@ -1,78 +1,347 @@
 #![allow(dead_code)]
 
+use std::io;
+use std::sync::OnceLock;
 use hashbrown::HashMap;
+use serde::{Serialize, Deserialize};
+use crate::filesystem::*;
+use crate::light;
+use crate::log;
+use crate::log::macro_deps::*;
+use crate::places;
 use crate::world;
 
-// Blocks for the game.
-#[derive(Debug, PartialEq, Eq, Clone, Copy)]
-pub enum BlockType {
-    Air, // Fun fact: the air block never exists in the chunk block array. It is basically an empty block.
-    Debug,
-    Grass,
-    Dirt,
-    Stone,
-    Diamond,
-}
+/// A kind of block, by its ID in the block registry. (IDs are what gets saved to disk.)
+#[derive(Debug, PartialEq, Eq, Clone, Copy, Hash)]
+pub struct BlockType(u16);
 
 impl BlockType {
-    pub fn properties(&self) -> BlockProperties {
-        return match self {
-            BlockType::Debug => BlockProperties { ..BlockProperties::default() },
-
-            BlockType::Air => BlockProperties {
-                name: "Air".into(),
-                collision: BlockCollisionType::Gas,
-                textures: BlockTextures::blank(),
-                transparent: true,
-                ..BlockProperties::default()
-            },
-
-            BlockType::Grass => BlockProperties {
-                name: "Grass Block".into(),
-                collision: BlockCollisionType::Solid,
-                textures: BlockTextures::new([
-                    Some("grass_side"),
-                    Some("grass_side"),
-                    Some("grass_side"),
-                    Some("grass_side"),
-                    Some("grass_top"),
-                    Some("dirt"),
-                ]),
-                transparent: false,
-                ..BlockProperties::default()
-            },
-
-            BlockType::Dirt => BlockProperties {
-                name: "Dirt Block".into(),
-                collision: BlockCollisionType::Solid,
-                textures: BlockTextures::new([
-                    Some("dirt"); 6
-                ]),
-                transparent: false,
-                ..BlockProperties::default()
-            },
-
-            BlockType::Stone => BlockProperties {
-                name: "Stone Block".into(),
-                collision: BlockCollisionType::Solid,
-                textures: BlockTextures::new([
-                    Some("stone"); 6
-                ]),
-                transparent: false,
-                ..BlockProperties::default()
-            },
-
-            BlockType::Diamond => BlockProperties {
-                name: "Diamond Block".into(),
-                collision: BlockCollisionType::Solid,
-                textures: BlockTextures::new([
-                    Some("diamond"); 6
-                ]),
-                transparent: false,
-                ..BlockProperties::default()
-            },
+    // Blocks from the default pack always get the same IDs, so saved worlds and the world generator can count on them. (Never reorder or remove these!)
+    pub const AIR: Self = Self(0); // Fun fact: the air block never exists in the chunk block array. It is basically an empty block.
+    pub const DEBUG: Self = Self(1);
+    pub const GRASS: Self = Self(2);
+    pub const DIRT: Self = Self(3);
+    pub const STONE: Self = Self(4);
+    pub const DIAMOND: Self = Self(5);
+
+    /// Every registered block type, in ID order.
+    pub fn all() -> impl Iterator<Item = BlockType> {
+        return (0..registry().properties.len() as u16).map(BlockType);
+    }
+
+    /// Amount of registered block types. (Every ID is less than this.)
+    pub fn count() -> usize {
+        return registry().properties.len();
+    }
+
+    /// Numeric ID used when saving blocks to disk.
+    pub fn id(&self) -> u16 {
+        return self.0;
+    }
+
+    pub fn from_id(id: u16) -> Option<Self> {
+        return match (id as usize) < Self::count() {
+            true => Some(Self(id)),
+            false => None,
         };
     }
+
+    /// Any ID, even if no block has it. (Block storage doesn't look at properties, so tests can use more blocks than are registered.)
+    #[cfg(test)]
+    pub fn from_id_unchecked(id: u16) -> Self {
+        return Self(id);
+    }
+
+    /// Look up a block by the name of its definition file. (Like "grass" for grass.toml)
+    pub fn from_name(name: &str) -> Option<Self> {
+        return registry().names.get(name).copied();
+    }
+
+    /// Name of the block's definition file. (Inverse of from_name())
+    pub fn definition_name(&self) -> &'static str {
+        return &registry().definition_names[self.0 as usize];
+    }
+
+    pub fn properties(&self) -> &'static BlockProperties {
+        return &registry().properties[self.0 as usize];
+    }
+}
+
+// Definitions of the blocks that are built into the game, in ID order. (See BlockType::AIR and friends.)
+const DEFAULT_BLOCKS: [(&str, &str); 6] = [
+    ("air", include_str!("default_blocks/air.toml")),
+    ("debug", include_str!("default_blocks/debug.toml")),
+    ("grass", include_str!("default_blocks/grass.toml")),
+    ("dirt", include_str!("default_blocks/dirt.toml")),
+    ("stone", include_str!("default_blocks/stone.toml")),
+    ("diamond", include_str!("default_blocks/diamond.toml")),
+];
+
+static REGISTRY: OnceLock<BlockRegistry> = OnceLock::new();
+
+// Blocks that were looked up before load_registry() only get the default pack.
+fn registry() -> &'static BlockRegistry {
+    return REGISTRY.get_or_init(|| {
+        match BlockRegistry::from_definitions(Vec::new()) {
+            Ok(o) => o,
+            Err(e) => panic!("The default block definitions are broken: {}", e),
+        }
+    });
+}
+
+/// Load the block definitions of the asset packs into the block registry. (Call this after building the assets, and before anything uses blocks.)
+pub fn load_registry() -> Result<(), io::Error> {
+    let registry = match BlockRegistry::load() {
+        Ok(o) => o,
+        Err(e) => {
+            log::error!("Failed to load the block definitions: {}", e);
+
+            return Err(e);
+        },
+    };
+
+    log::info!("Loaded {} block types.", registry.properties.len());
+
+    if REGISTRY.set(registry).is_err() {
+        log::warning!("Blocks were used before the block registry was loaded, so only the default blocks exist!");
+    }
+
+    return Ok(());
+}
+
+/// Every block type, and its properties.
+/// Blocks from asset packs get the IDs after the default ones, in name order, so they change when packs are installed or removed. (Saved worlds have their own IDs, see BlockIdTable.)
+pub struct BlockRegistry {
+    properties: Vec<BlockProperties>, // Indexed by block ID.
+    names: HashMap<String, BlockType>, // By the name of the definition file.
+    definition_names: Vec<String>, // Indexed by block ID.
+}
+
+impl BlockRegistry {
+    // Every block definition in the built assets. (assets/blocks/*.toml)
+    fn load() -> Result<Self, io::Error> {
+        let directory = places::assets().add_str("blocks");
+
+        if directory.exists() == false {
+            return Self::from_definitions(Vec::new());
+        }
+
+        let mut files: Vec<Path> = directory::list_items(&directory)?
+            .into_iter()
+            .filter(|x| x.path_type() == PathType::File && x.to_string().ends_with(".toml"))
+            .collect();
+
+        files.sort_by_key(|x| x.basename());
+
+        let mut definitions: Vec<(String, String)> = Vec::new();
+
+        for f in files.iter() {
+            definitions.push((f.basename().replace(".toml", ""), file::read(f)?));
+        }
+
+        return Self::from_definitions(definitions);
+    }
+
+    // The default blocks, with definitions from asset packs on top of them. (Ones with the same name replace the default block.)
+    fn from_definitions(definitions: Vec<(String, String)>) -> Result<Self, io::Error> {
+        let mut registry = Self {
+            properties: Vec::new(),
+            names: HashMap::new(),
+            definition_names: Vec::new(),
+        };
+
+        let default_blocks = DEFAULT_BLOCKS.iter().map(|(n, d)| (n.to_string(), d.to_string()));
+
+        for (name, definition) in default_blocks.chain(definitions) {
+            let properties = match toml::from_str::<BlockFile>(&definition) {
+                Ok(o) => o.into_properties(),
+                Err(e) => Err(e.to_string()),
+            };
+
+            let properties = match properties {
+                Ok(o) => o,
+                Err(e) => return Err(io::Error::new(io::ErrorKind::InvalidData, format!("Invalid block definition '{}': {}", name, e))),
+            };
+
+            match registry.names.get(&name) {
+                Some(s) => registry.properties[s.0 as usize] = properties,
+                None => {
+                    if registry.properties.len() > u16::MAX as usize {
+                        return Err(io::Error::new(io::ErrorKind::Other, "Too many block types!"));
+                    }
+
+                    registry.names.insert(name.clone(), BlockType(registry.properties.len() as u16));
+                    registry.definition_names.push(name);
+                    registry.properties.push(properties);
+                },
+            };
+        }
+
+        return Ok(registry);
+    }
+}
+
+/// Which block every ID in a saved world stands for, by the name of its definition file.
+/// Registry IDs change when asset packs are installed or removed, so chunks get saved with the world's own IDs, which never do.
+/// Blocks that get registered later are added to the end, and blocks that aren't registered anymore load as air.
+#[derive(Debug, Clone)]
+pub struct BlockIdTable {
+    names: Vec<String>, // Indexed by saved ID.
+    blocks: Vec<BlockType>, // Registered block of every saved ID.
+    saved_ids: Vec<u16>, // Saved ID of every registered block, indexed by block ID.
+    changed: bool, // Blocks were added since it was loaded.
+}
+
+/// A BlockIdTable on disk. (A world's blocks.toml)
+/// ```toml
+/// blocks = ["air", "debug", "grass", "dirt", "stone", "diamond"] # Saved ID 0, 1, 2...
+/// ```
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct BlockIdFile {
+    blocks: Vec<String>,
+}
+
+impl BlockIdTable {
+    /// Load a world's table, and add the blocks that are new since it was saved. (Worlds without one start with the registry's IDs.)
+    pub fn load(path: &Path) -> Result<Self, io::Error> {
+        if path.exists() == false {
+            return Self::from_names(Vec::new());
+        }
+
+        return match toml::from_str::<BlockIdFile>(&file::read(path)?) {
+            Ok(o) => Self::from_names(o.blocks),
+            Err(e) => Err(io::Error::new(io::ErrorKind::InvalidData, format!("Invalid block ID table {}: {}", path.to_string(), e))),
+        };
+    }
+
+    /// A table with the same IDs as the block registry. (For chunks that aren't part of a world.)
+    pub fn from_registry() -> Self {
+        return Self::from_names(Vec::new()).unwrap(); // Guarenteed value, the registry doesn't have duplicate names.
+    }
+
+    fn from_names(mut names: Vec<String>) -> Result<Self, io::Error> {
+        let loaded = names.len();
+
+        let mut saved_by_name: HashMap<String, usize> = HashMap::with_capacity(names.len());
+
+        for (i, name) in names.iter().enumerate() {
+            if saved_by_name.insert(name.clone(), i).is_some() {
+                return Err(io::Error::new(io::ErrorKind::InvalidData, format!("Block '{}' is in the block ID table twice", name)));
+            }
+        }
+
+        for block in BlockType::all() {
+            let name = block.definition_name();
+
+            if saved_by_name.contains_key(name) == false {
+                saved_by_name.insert(name.to_string(), names.len());
+                names.push(name.to_string());
+            }
+        }
+
+        if names.len() > u16::MAX as usize + 1 {
+            return Err(io::Error::new(io::ErrorKind::Other, "Too many block types in the block ID table!"));
+        }
+
+        let blocks: Vec<BlockType> = names.iter().map(|x| BlockType::from_name(x).unwrap_or(BlockType::AIR)).collect();
+        let saved_ids: Vec<u16> = BlockType::all().map(|x| saved_by_name[x.definition_name()] as u16).collect();
+
+        let missing: Vec<&str> = names.iter().filter(|x| BlockType::from_name(x).is_none()).map(|x| x.as_str()).collect();
+
+        if missing.is_empty() == false {
+            log::warning!("These blocks aren't registered anymore, so they load as air: {}", missing.join(", "));
+        }
+
+        return Ok(Self {
+            changed: names.len() != loaded,
+            names,
+            blocks,
+            saved_ids,
+        });
+    }
+
+    pub fn save(&self, path: &Path) -> Result<(), io::Error> {
+        let contents = match toml::to_string(&BlockIdFile { blocks: self.names.clone() }) {
+            Ok(o) => o,
+            Err(e) => return Err(io::Error::new(io::ErrorKind::Other, e.to_string())),
+        };
+
+        return file::write(&contents, path);
+    }
+
+    /// Whether blocks were added since the table was loaded, so it has to be saved before any chunks are.
+    pub fn changed(&self) -> bool {
+        return self.changed;
+    }
+
+    /// Block that a saved ID stands for. (Returns None if the ID isn't in the table, which means the data is corrupt.)
+    pub fn block(&self, saved_id: u16) -> Option<BlockType> {
+        return self.blocks.get(saved_id as usize).copied();
+    }
+
+    pub fn saved_id(&self, block: BlockType) -> u16 {
+        return self.saved_ids[block.id() as usize];
+    }
+}
+
+/// Block definition file, from the blocks directory of an asset pack. The name of the file is the name of the block.
+/// ```toml
+/// name = "Grass Block"
+/// collision = "Solid" # Solid, Liquid, or Gas
+/// transparent = false # Optional
+/// emission = 0 # Optional, 0 to 15
+/// render_layer = "Opaque" # Optional, Opaque, Cutout, or Translucent
+/// textures = ["grass_side", "grass_side", "grass_side", "grass_side", "grass_top", "dirt"] # North, South, East, West, Up, Down
+/// ```
+/// Textures can also be one texture for every face, or empty for no faces at all. (An empty string leaves out a single face.)
+/// Blocks that leave out any faces, or aren't in the Opaque render layer, are always transparent, since you can see through them.
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct BlockFile {
+    name: String,
+    collision: BlockCollisionType,
+    #[serde(default)]
+    transparent: bool,
+    #[serde(default)]
+    emission: u8,
+    #[serde(default)]
+    render_layer: Option<RenderLayer>,
+    #[serde(default)]
+    textures: Vec<String>,
+}
+
+impl BlockFile {
+    fn into_properties(self) -> Result<BlockProperties, String> {
+        let textures: Vec<Option<&str>> = self.textures.iter()
+            .map(|x| match x.is_empty() { true => None, false => Some(x.as_str()) })
+            .collect();
+
+        let textures: [Option<&str>; 6] = match textures.len() {
+            0 => [None; 6],
+            1 => [textures[0]; 6],
+            6 => [textures[0], textures[1], textures[2], textures[3], textures[4], textures[5]],
+            _ => return Err(String::from("textures needs 0, 1, or 6 entries")),
+        };
+
+        if self.emission > light::MAX_LIGHT {
+            return Err(format!("emission can't be more than {}", light::MAX_LIGHT));
+        }
+
+        let render_layer = self.render_layer.unwrap_or(RenderLayer::Opaque);
+
+        // Otherwise neighbors would cull their faces behind holes in the block.
+        let transparent = self.transparent || textures.contains(&None) || render_layer != RenderLayer::Opaque;
+
+        return Ok(BlockProperties {
+            name: self.name,
+            collision: self.collision,
+            transparent,
+            emission: self.emission,
+            render_layer,
+            textures: BlockTextures::new(textures),
+        });
+    }
 }
 
 // Block textures struct.
@@ -103,11 +372,13 @@ impl BlockTextures {
     }
 }
 
-// Properties for a block. This is returned in the BlockType.properties() function.
+// Properties for a block. This is returned in the BlockType.properties() function, and comes from a BlockFile.
 pub struct BlockProperties {
     pub name: String, // This is the name of the block.
     pub collision: BlockCollisionType, // Solid? Liquid? Gas?
-    pub transparent: bool, // Are any of the textures transparent? This is used in mesh generation.
+    pub transparent: bool, // Are any of the textures transparent? This is used in mesh generation, and lets light through.
+    pub emission: u8, // How much light the block gives off. (0 to 15)
+    pub render_layer: RenderLayer, // How the faces get drawn. (Anything but Opaque is also transparent.)
     pub textures: BlockTextures, // A list of textures for the block. (Index of block texture array).
 }
 
@@ -118,14 +389,41 @@ impl Default for BlockProperties {
             name: "Debug Block".into(),
             collision: BlockCollisionType::Solid,
             transparent: false,
+            emission: 0,
+            render_layer: RenderLayer::Opaque,
             textures: BlockTextures::new([Some("debug"); 6]),
         };
     }
 }
 
 // Collision type for a block.
+#[derive(Debug, PartialEq, Eq, Clone, Copy, Serialize, Deserialize)]
 pub enum BlockCollisionType {
     Solid,
     Liquid,
     Gas,
 }
+
+// How the faces of a block are drawn. Every layer of a chunk is a separate mesh, with its own material.
+#[derive(Debug, PartialEq, Eq, Clone, Copy, Hash, Serialize, Deserialize)]
+pub enum RenderLayer {
+    Opaque, // Nothing shows through.
+    Cutout, // Pixels are either fully see-through or not at all. (Leaves, glass)
+    Translucent, // Blended with whatever is behind it. (Water, stained glass)
+}
+
+impl RenderLayer {
+    pub const ALL: [RenderLayer; 3] = [
+        RenderLayer::Opaque,
+        RenderLayer::Cutout,
+        RenderLayer::Translucent,
+    ];
+
+    pub fn index(&self) -> usize {
+        return match self {
+            RenderLayer::Opaque => 0,
+            RenderLayer::Cutout => 1,
+            RenderLayer::Translucent => 2,
+        };
+    }
+}
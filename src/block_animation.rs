@@ -0,0 +1,181 @@
+#![allow(dead_code)]
+
+use bevy::prelude::*;
+use bevy::render::render_asset::RenderAssets;
+use bevy::render::render_resource::{Extent3d, ImageCopyTexture, ImageDataLayout, Origin3d, TextureAspect};
+use bevy::render::renderer::RenderQueue;
+use bevy::render::{Extract, ExtractSchedule, Render, RenderApp, RenderSet};
+
+use crate::block_atlas::{BlockAtlas, TextureRegion};
+use crate::chunk_meshing::{ChunkMaterials, ChunkMeshingSet};
+
+pub struct BlockAnimationPlugin;
+
+impl Plugin for BlockAnimationPlugin {
+    fn build(&self, app: &mut App) {
+        app.init_resource::<AnimationWrites>();
+
+        // After the chunk textures get reloaded, so a rebuild doesn't throw away the frame that was just copied in.
+        app.add_systems(Update, animate_block_textures.after(ChunkMeshingSet));
+
+        let render_app = match app.get_sub_app_mut(RenderApp) {
+            Ok(o) => o,
+            Err(_) => return,
+        };
+
+        render_app.init_resource::<AnimationWrites>();
+        render_app.add_systems(ExtractSchedule, extract_animation_writes);
+        render_app.add_systems(Render, write_animation_frames.in_set(RenderSet::Queue));
+    }
+}
+
+// One mip level of a part of a texture, to write straight into the texture on the GPU.
+#[derive(Debug, Clone)]
+struct TextureWrite {
+    level: u32,
+    layer: u32,
+    corner: (u32, u32), // In pixels of the mip level.
+    size: (u32, u32),
+    data: Vec<u8>,
+}
+
+// Frames that changed this frame, and the texture they go into.
+// Changing the image itself would make the whole texture get uploaded again, so only the changed tiles get written.
+#[derive(Resource, Clone, Default)]
+struct AnimationWrites {
+    texture: Handle<Image>,
+    writes: Vec<TextureWrite>,
+}
+
+// Find the animated textures whose frame changed, and queue their new frame to be written over the spot that meshes sample from.
+fn animate_block_textures(
+    time: Res<Time>,
+    atlas: Res<BlockAtlas>,
+    materials: Res<ChunkMaterials>,
+    images: Res<Assets<Image>>,
+    mut writes: ResMut<AnimationWrites>,
+    mut shown: Local<Vec<usize>>,
+) {
+    writes.writes.clear();
+
+    let (textures, animations) = match materials.as_ref() {
+        ChunkMaterials::Atlas(textures, _) => (textures, atlas.atlas_animations()),
+        ChunkMaterials::Array(textures, _) => (textures, atlas.texture_array_animations()),
+    };
+
+    // Freshly loaded textures show the first frame of every animation.
+    if atlas.is_changed() || materials.is_changed() || shown.len() != animations.len() {
+        *shown = vec![0; animations.len()];
+    }
+
+    let seconds = time.elapsed_seconds_f64();
+
+    let changed: Vec<usize> = (0..animations.len())
+        .filter(|i| animations[*i].frame_at(seconds) != shown[*i])
+        .collect();
+
+    if changed.is_empty() {
+        return;
+    }
+
+    // The image on the CPU never changes, so it still has every frame where they were packed.
+    let image = match images.get(textures) {
+        Some(s) => s,
+        None => return,
+    };
+
+    writes.texture = textures.clone();
+
+    for i in changed {
+        let animation = &animations[i];
+        let frame = animation.frame_at(seconds);
+
+        writes.writes.extend(region_writes(image, animation.frames[frame], animation.target));
+
+        shown[i] = frame;
+    }
+}
+
+fn extract_animation_writes(mut writes: ResMut<AnimationWrites>, main_writes: Extract<Res<AnimationWrites>>) {
+    *writes = main_writes.clone();
+}
+
+// Write the changed frames into the texture on the GPU. (Skipped if the texture isn't on the GPU yet, since it starts at the first frame anyway.)
+fn write_animation_frames(writes: Res<AnimationWrites>, gpu_images: Res<RenderAssets<Image>>, queue: Res<RenderQueue>) {
+    if writes.writes.is_empty() {
+        return;
+    }
+
+    let gpu_image = match gpu_images.get(&writes.texture) {
+        Some(s) => s,
+        None => return,
+    };
+
+    for write in writes.writes.iter() {
+        queue.write_texture(
+            ImageCopyTexture {
+                texture: &gpu_image.texture,
+                mip_level: write.level,
+                origin: Origin3d { x: write.corner.0, y: write.corner.1, z: write.layer },
+                aspect: TextureAspect::All,
+            },
+            &write.data,
+            ImageDataLayout {
+                offset: 0,
+                bytes_per_row: Some(write.size.0 * 4),
+                rows_per_image: Some(write.size.1),
+            },
+            Extent3d { width: write.size.0, height: write.size.1, depth_or_array_layers: 1 },
+        );
+    }
+}
+
+// Writes that copy one part of a texture over another one of the same size, on every mip level.
+// (Parts that don't fit in the texture get skipped, like when it failed to load.)
+fn region_writes(image: &Image, from: TextureRegion, to: TextureRegion) -> Vec<TextureWrite> {
+    let size = image.texture_descriptor.size;
+    let levels = image.texture_descriptor.mip_level_count;
+
+    let level_size = |level: u32| ((size.width >> level).max(1), (size.height >> level).max(1));
+
+    // Every mip level of a layer comes before the next layer. (See block_atlas::mipmapped_image())
+    let layer_bytes: usize = (0..levels).map(|l| (level_size(l).0 * level_size(l).1 * 4) as usize).sum();
+
+    let fits = |r: &TextureRegion| r.layer < size.depth_or_array_layers && r.corner.0 + r.size.0 <= size.width && r.corner.1 + r.size.1 <= size.height;
+
+    if from.size != to.size || fits(&from) == false || fits(&to) == false || image.data.len() != layer_bytes * size.depth_or_array_layers as usize {
+        return Vec::new();
+    }
+
+    let mut writes: Vec<TextureWrite> = Vec::new();
+    let mut level_offset: usize = 0;
+
+    for level in 0..levels {
+        let (width, height) = level_size(level);
+        let (region_width, region_height) = (from.size.0 >> level, from.size.1 >> level);
+
+        if region_width == 0 || region_height == 0 {
+            break;
+        }
+
+        let mut data: Vec<u8> = Vec::with_capacity((region_width * region_height * 4) as usize);
+
+        for row in 0..region_height {
+            let source = from.layer as usize * layer_bytes + level_offset + (((from.corner.1 >> level) + row) * width + (from.corner.0 >> level)) as usize * 4;
+
+            data.extend_from_slice(&image.data[source..source + region_width as usize * 4]);
+        }
+
+        writes.push(TextureWrite {
+            level,
+            layer: to.layer,
+            corner: (to.corner.0 >> level, to.corner.1 >> level),
+            size: (region_width, region_height),
+            data,
+        });
+
+        level_offset += (width * height * 4) as usize;
+    }
+
+    return writes;
+}
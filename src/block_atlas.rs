@@ -0,0 +1,435 @@
+#![allow(dead_code)]
+
+use std::io;
+use std::sync::Arc;
+use std::time::SystemTime;
+use bevy::prelude::*;
+use bevy::render::render_resource::{AddressMode, Extent3d, FilterMode, SamplerDescriptor, TextureDimension, TextureFormat, TextureViewDescriptor, TextureViewDimension};
+use bevy::render::texture::ImageSampler;
+use hashbrown::HashMap;
+
+use crate::asset_manager;
+use crate::asset_manager::{AtlasUVMapElement, BlockAtlasInfo, BlockTextureArrayInfo};
+use crate::block::BlockType;
+use crate::filesystem::*;
+use crate::log;
+use crate::log::macro_deps::*;
+use crate::places;
+use crate::world;
+
+pub struct BlockAtlasPlugin;
+
+impl Plugin for BlockAtlasPlugin {
+    fn build(&self, app: &mut App) {
+        // Assets are always built before the app starts, so the atlas can be loaded right away.
+        let atlas = match BlockAtlas::load() {
+            Ok(o) => o,
+            Err(e) => {
+                log::error!("Failed to load the block atlas info, blocks will be missing their textures: {}", e);
+
+                BlockAtlas::from_info(&BlockAtlasInfo { size: (1, 1), uv_map: Default::default(), mip_levels: 1, animations: Default::default() }, None)
+            },
+        };
+
+        app.insert_resource(atlas);
+        app.insert_resource(AtlasWatchTimer(Timer::from_seconds(ATLAS_WATCH_SECONDS, TimerMode::Repeating)));
+        app.add_systems(Update, reload_rebuilt_atlas);
+    }
+}
+
+/// How often the atlas files are checked for a rebuild.
+const ATLAS_WATCH_SECONDS: f32 = 2.0;
+
+const ATLAS_PIXEL_CROP: f32 = 0.0;
+
+/// Where a texture is in the block atlas, in atlas UVs.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct AtlasTile {
+    /// Top-left corner.
+    pub min: (f32, f32),
+    pub size: (f32, f32),
+    /// Layer of the texture in the block texture array. (0 if there is no texture array.)
+    pub layer: u32,
+}
+
+impl AtlasTile {
+    /// Left, top, width, height. (Same layout as mesher::ATTRIBUTE_ATLAS_TILE.)
+    pub fn to_array(&self) -> [f32; 4] {
+        [self.min.0, self.min.1, self.size.0, self.size.1]
+    }
+}
+
+/// Part of a chunk texture, on every one of its mip levels. (Somewhere on the atlas, or a whole layer of the texture array.)
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TextureRegion {
+    pub layer: u32,
+    /// Top-left corner, in pixels.
+    pub corner: (u32, u32),
+    pub size: (u32, u32),
+}
+
+impl TextureRegion {
+    // An atlas tile, along with its padding. (The padding has to change along with the frame.)
+    fn padded(element: &AtlasUVMapElement) -> Self {
+        return Self {
+            layer: 0,
+            corner: (element.corner.0 - element.padding, element.corner.1 - element.padding),
+            size: (element.size.0 + element.padding * 2, element.size.1 + element.padding * 2),
+        };
+    }
+}
+
+/// An animated block texture. Meshes always point to the same spot, and every frame gets copied over it when it's time.
+#[derive(Debug, Clone, PartialEq)]
+pub struct TextureAnimation {
+    /// Where meshes sample the texture from.
+    pub target: TextureRegion,
+    /// Where every frame is, in the order they play.
+    pub frames: Vec<TextureRegion>,
+    /// How long every frame shows, in seconds.
+    pub durations: Vec<f32>,
+}
+
+impl TextureAnimation {
+    /// Which entry of frames should be showing, some amount of seconds after the animation started.
+    pub fn frame_at(&self, seconds: f64) -> usize {
+        let total: f64 = self.durations.iter().map(|x| *x as f64).sum();
+
+        if total <= 0.0 {
+            return 0;
+        }
+
+        let mut time = seconds % total;
+
+        for (i, d) in self.durations.iter().enumerate() {
+            if time < *d as f64 {
+                return i;
+            }
+
+            time -= *d as f64;
+        }
+
+        return self.durations.len() - 1;
+    }
+}
+
+/// The block atlas info, with the tile of every block face looked up ahead of time.
+/// (Cheap to clone, so it can be sent off to meshing tasks.)
+#[derive(Resource, Clone)]
+pub struct BlockAtlas {
+    /// Indexed by block ID, then by world::Direction::index(). (None if the face has no texture.)
+    tiles: Arc<Vec<[Option<AtlasTile>; 6]>>,
+    /// When the atlas info file was last changed, to tell when the assets get rebuilt.
+    modified: Option<SystemTime>,
+    /// Size of the atlas image, and how many mip levels were built for it.
+    size: (u32, u32),
+    mip_levels: u32,
+    /// The block texture array, if the asset build could make one. (See asset_manager::build_block_texture_array())
+    texture_array: Option<Arc<BlockTextureArrayInfo>>,
+    /// Animated textures, in the atlas and in the texture array.
+    atlas_animations: Arc<Vec<TextureAnimation>>,
+    texture_array_animations: Arc<Vec<TextureAnimation>>,
+}
+
+impl BlockAtlas {
+    /// Load the atlas info that was saved by the asset build.
+    pub fn load() -> Result<Self, io::Error> {
+        let path = info_path();
+
+        let info: BlockAtlasInfo = match toml::from_str(&file::read(&path)?) {
+            Ok(o) => o,
+            Err(e) => return Err(io::Error::new(io::ErrorKind::InvalidData, format!("Failed to parse block atlas info: {}", e))),
+        };
+
+        // Only there when every block texture is the same size.
+        let array_info: Option<BlockTextureArrayInfo> = match texture_array_info_path().exists() {
+            true => match toml::from_str(&file::read(&texture_array_info_path())?) {
+                Ok(o) => Some(o),
+                Err(e) => return Err(io::Error::new(io::ErrorKind::InvalidData, format!("Failed to parse block texture array info: {}", e))),
+            },
+            false => None,
+        };
+
+        let mut atlas = Self::from_info(&info, array_info.as_ref());
+
+        atlas.modified = modified_time(&path);
+
+        return Ok(atlas);
+    }
+
+    /// Every block texture gets its own 16x16 tile, without needing the built atlas. (For meshing in tests and benchmarks.)
+    pub fn placeholder() -> Self {
+        let mut info = BlockAtlasInfo { size: (256, 256), uv_map: HashMap::new(), mip_levels: 1, animations: HashMap::new() };
+
+        for block in BlockType::all() {
+            for d in world::Direction::all() {
+                if let Some(s) = block.properties().textures.get(d) {
+                    let corner = ((info.uv_map.len() as u32 % 16) * 16, (info.uv_map.len() as u32 / 16) * 16);
+
+                    info.uv_map.entry(s).or_insert(AtlasUVMapElement { corner, size: (16, 16), padding: 0 });
+                }
+            }
+        }
+
+        return Self::from_info(&info, None);
+    }
+
+    pub fn from_info(info: &BlockAtlasInfo, array_info: Option<&BlockTextureArrayInfo>) -> Self {
+        let mut tiles: Vec<[Option<AtlasTile>; 6]> = Vec::new();
+
+        for block in BlockType::all() {
+            let id = block.id() as usize;
+            let textures = &block.properties().textures;
+
+            if tiles.len() <= id {
+                tiles.resize(id + 1, [None; 6]);
+            }
+
+            for d in world::Direction::all() {
+                let texture_name = match textures.get(d) {
+                    Some(s) => s,
+                    None => continue,
+                };
+
+                let mut tile = match info.uv_map.get(&texture_name) {
+                    Some(s) => AtlasTile {
+                        min: (
+                            (s.corner.0 as f32 + ATLAS_PIXEL_CROP) / info.size.0 as f32,
+                            (s.corner.1 as f32 + ATLAS_PIXEL_CROP) / info.size.1 as f32,
+                        ),
+                        size: (
+                            (s.size.0 as f32 - ATLAS_PIXEL_CROP * 2.0) / info.size.0 as f32,
+                            (s.size.1 as f32 - ATLAS_PIXEL_CROP * 2.0) / info.size.1 as f32,
+                        ),
+                        layer: 0,
+                    },
+                    None => {
+                        log::error!("Texture '{}' is not in the block atlas!", texture_name);
+
+                        AtlasTile { min: (0.0, 0.0), size: (0.0, 0.0), layer: 0 }
+                    },
+                };
+
+                if let Some(array_info) = array_info {
+                    tile.layer = match array_info.layers.iter().position(|x| *x == texture_name) {
+                        Some(s) => s as u32,
+                        None => {
+                            log::error!("Texture '{}' is not in the block texture array!", texture_name);
+
+                            0
+                        },
+                    };
+                }
+
+                tiles[id][d.index()] = Some(tile);
+            }
+        }
+
+        let mut atlas_animations: Vec<TextureAnimation> = Vec::new();
+
+        for (name, animation) in info.animations.iter() {
+            let target = match info.uv_map.get(name) {
+                Some(s) => TextureRegion::padded(s),
+                None => continue,
+            };
+
+            atlas_animations.push(TextureAnimation {
+                target,
+                frames: animation.frames.iter().map(TextureRegion::padded).collect(),
+                durations: animation.durations.clone(),
+            });
+        }
+
+        let mut texture_array_animations: Vec<TextureAnimation> = Vec::new();
+
+        if let Some(array_info) = array_info {
+            let layer = |layer: u32| TextureRegion { layer, corner: (0, 0), size: array_info.size };
+
+            for (name, animation) in array_info.animations.iter() {
+                let target = match array_info.layers.iter().position(|x| x == name) {
+                    Some(s) => layer(s as u32),
+                    None => continue,
+                };
+
+                texture_array_animations.push(TextureAnimation {
+                    target,
+                    frames: animation.frames.iter().map(|x| layer(*x)).collect(),
+                    durations: animation.durations.clone(),
+                });
+            }
+        }
+
+        return Self {
+            tiles: Arc::new(tiles),
+            modified: None,
+            size: info.size,
+            mip_levels: info.mip_levels.max(1),
+            texture_array: array_info.map(|x| Arc::new(x.clone())),
+            atlas_animations: Arc::new(atlas_animations),
+            texture_array_animations: Arc::new(texture_array_animations),
+        };
+    }
+
+    /// Load the atlas image that goes with this atlas, along with all of its mip levels.
+    pub fn atlas_image(&self) -> Result<Image, io::Error> {
+        let mut data: Vec<u8> = Vec::new();
+
+        for level in 0..self.mip_levels {
+            let path = places::custom_built_assets().add_str(&asset_manager::atlas_mip_file_name(level));
+
+            let mip = match image::open(path.to_string()) {
+                Ok(o) => o.into_rgba8(),
+                Err(e) => return Err(io::Error::new(io::ErrorKind::InvalidData, format!("Failed to open block atlas mip level {}: {}", level, e))),
+            };
+
+            if mip.dimensions() != (self.size.0 >> level, self.size.1 >> level) {
+                return Err(io::Error::new(io::ErrorKind::InvalidData, "Block atlas doesn't match its info!"));
+            }
+
+            data.extend_from_slice(mip.as_raw());
+        }
+
+        // The default sampler is fine, since the chunk shader keeps UVs inside of their tiles by itself.
+        return Ok(mipmapped_image(self.size, 1, self.mip_levels, data));
+    }
+
+    pub fn atlas_animations(&self) -> &[TextureAnimation] {
+        &self.atlas_animations
+    }
+
+    pub fn texture_array_animations(&self) -> &[TextureAnimation] {
+        &self.texture_array_animations
+    }
+
+    pub fn texture_array(&self) -> Option<&BlockTextureArrayInfo> {
+        self.texture_array.as_deref()
+    }
+
+    /// Load the block texture array that goes with this atlas. (See load_texture_array_image())
+    pub fn texture_array_image(&self) -> Result<Image, io::Error> {
+        return match self.texture_array() {
+            Some(s) => load_texture_array_image(s),
+            None => Err(io::Error::new(io::ErrorKind::NotFound, "There is no block texture array, are the block textures different sizes?")),
+        };
+    }
+
+    /// Atlas tile of a block face. (None if the face has no texture.)
+    pub fn tile(&self, block: BlockType, direction: world::Direction) -> Option<AtlasTile> {
+        self.tiles.get(block.id() as usize).and_then(|x| x[direction.index()])
+    }
+}
+
+fn info_path() -> Path {
+    places::custom_built_assets().add_str("block_atlas.toml")
+}
+
+fn texture_array_info_path() -> Path {
+    places::custom_built_assets().add_str("block_textures.toml")
+}
+
+fn texture_array_image_path() -> Path {
+    places::custom_built_assets().add_str("block_textures.png")
+}
+
+// Load the block texture array that was saved by the asset build, as a 2D array texture with one layer per block texture.
+// Every layer gets its own mip chain, so textures never bleed into each other no matter how far away they are.
+fn load_texture_array_image(info: &BlockTextureArrayInfo) -> Result<Image, io::Error> {
+    let stacked = match image::open(texture_array_image_path().to_string()) {
+        Ok(o) => o.into_rgba8(),
+        Err(e) => return Err(io::Error::new(io::ErrorKind::InvalidData, format!("Failed to open block texture array: {}", e))),
+    };
+
+    let (width, height) = info.size;
+    let layers = info.layers.len() as u32;
+
+    if layers == 0 || stacked.dimensions() != (width, height * layers) {
+        return Err(io::Error::new(io::ErrorKind::InvalidData, "Block texture array doesn't match its info!"));
+    }
+
+    // Every mip level of the first layer, then every mip level of the next layer, and so on. (The order wgpu uploads them in.)
+    let mut data: Vec<u8> = Vec::new();
+    let mut mip_levels: u32 = 1;
+
+    for i in 0..layers {
+        let layer = image::imageops::crop_imm(&stacked, 0, height * i, width, height).to_image();
+        let mips = asset_manager::generate_mips(&layer);
+
+        mip_levels = mips.len() as u32;
+
+        for mip in mips {
+            data.extend_from_slice(mip.as_raw());
+        }
+    }
+
+    let mut texture = mipmapped_image((width, height), layers, mip_levels, data);
+
+    // A single layer would get a plain 2D view otherwise, which the shader can't use.
+    texture.texture_view_descriptor = Some(TextureViewDescriptor {
+        dimension: Some(TextureViewDimension::D2Array),
+        ..default()
+    });
+
+    // UVs count in textures, so repeating them tiles merged faces.
+    texture.sampler_descriptor = ImageSampler::Descriptor(SamplerDescriptor {
+        address_mode_u: AddressMode::Repeat,
+        address_mode_v: AddressMode::Repeat,
+        mag_filter: FilterMode::Nearest,
+        min_filter: FilterMode::Nearest,
+        mipmap_filter: FilterMode::Linear,
+        ..default()
+    });
+
+    return Ok(texture);
+}
+
+// A 2D texture (with layers) out of pixel data that already has every mip level in it.
+// Image::new() only takes the full size level, so the real data gets put in after.
+fn mipmapped_image(size: (u32, u32), layers: u32, mip_levels: u32, data: Vec<u8>) -> Image {
+    let full_size = (size.0 * size.1 * layers * 4) as usize;
+
+    let mut texture = Image::new(
+        Extent3d { width: size.0, height: size.1, depth_or_array_layers: layers },
+        TextureDimension::D2,
+        vec![0; full_size],
+        TextureFormat::Rgba8UnormSrgb,
+    );
+
+    texture.data = data;
+    texture.texture_descriptor.mip_level_count = mip_levels;
+
+    return texture;
+}
+
+fn modified_time(path: &Path) -> Option<SystemTime> {
+    std::fs::metadata(path.to_string()).and_then(|x| x.modified()).ok()
+}
+
+#[derive(Resource)]
+struct AtlasWatchTimer(Timer);
+
+// Reload the atlas info once the assets have been rebuilt. (Chunk textures get reloaded, and chunks remeshed, when BlockAtlas changes.)
+fn reload_rebuilt_atlas(
+    time: Res<Time>,
+    mut timer: ResMut<AtlasWatchTimer>,
+    mut atlas: ResMut<BlockAtlas>,
+) {
+    if timer.0.tick(time.delta()).just_finished() == false {
+        return;
+    }
+
+    let modified = modified_time(&info_path());
+
+    if modified.is_none() || modified == atlas.modified {
+        return;
+    }
+
+    match BlockAtlas::load() {
+        Ok(o) => {
+            log::info!("Block atlas was rebuilt, reloading it...");
+
+            *atlas = o;
+        },
+        // Probably caught halfway through a rebuild, so try again next time.
+        Err(e) => log::error!("Failed to reload the block atlas: {}", e),
+    };
+}
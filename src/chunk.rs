@@ -1,16 +1,21 @@
 #![allow(dead_code)]
 
+use bevy::app::AppExit;
 use bevy::prelude::*;
-use hashbrown::HashMap;
-use std::ops::Not;
-use std::ops::Range;
-use std::rc::Rc;
+use clap::ValueEnum;
+use hashbrown::{HashMap, HashSet};
+use std::collections::VecDeque;
+use std::io;
 
 use crate::block::BlockType;
-use crate::mesher;
-use crate::places;
-use crate::voxel::mdi_from;
-use crate::voxel::Voxel;
+use crate::block_atlas::BlockAtlas;
+use crate::chunk_storage::{self, BlockStorage};
+use crate::light::{self, ChunkLight, LightKind};
+use crate::log;
+use crate::log::macro_deps::*;
+use crate::mesher::{self, LayeredMeshBuilder};
+use crate::region::{ChunkSource, RegionPos, WorldStorage, REGION_SIZE};
+use crate::voxel::{self, mdi_from, Voxel};
 use crate::world;
 use crate::world_generation;
 
@@ -18,13 +23,20 @@ pub struct ChunkManagerPlugin;
 
 impl Plugin for ChunkManagerPlugin {
     fn build(&self, app: &mut App) {
-        app.insert_resource(ChunkManager::new());
-        app.add_systems(Startup, test_chunks);
+        // The ChunkManager resource is opened before the app starts. (See ChunkManager::open())
+        app.insert_resource(ChunkAutosaveTimer(Timer::from_seconds(AUTOSAVE_SECONDS, TimerMode::Repeating)));
+        app.add_systems(Last, save_chunks);
     }
 }
 
 pub const CHUNK_SIZE: BlockPos = BlockPos::new_unchecked(16, 16, 16);
 
+/// How often modified chunks are written to disk.
+const AUTOSAVE_SECONDS: f32 = 30.0;
+
+/// Most blocks that ChunkManager::fill_region() changes at once. (Every chunk it touches gets loaded right away, on the main thread.)
+pub const MAX_FILL_VOLUME: usize = 64 * 64 * 64;
+
 #[derive(Default, Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
 pub struct ChunkPos {
     pub x: isize,
@@ -36,6 +48,49 @@ impl ChunkPos {
     pub const fn new(x: isize, y: isize, z: isize) -> Self {
         Self { x, y, z }
     }
+
+    /// The chunk that contains a position in the world.
+    pub fn from_world(position: Vec3) -> Self {
+        GlobalBlockPos::from_world(position).to_chunk().0
+    }
+
+    /// The neighboring chunk in a direction.
+    pub fn offset(&self, direction: world::Direction) -> Self {
+        let (dx, dy, dz) = direction.offset_with_position((self.x, self.y, self.z));
+
+        Self::new(dx, dy, dz)
+    }
+
+    /// The chunk at an offset from this one, in chunks.
+    pub fn add(&self, offset: (isize, isize, isize)) -> Self {
+        Self::new(self.x + offset.0, self.y + offset.1, self.z + offset.2)
+    }
+
+    /// Offsets of the 26 chunks around a chunk. (Face, edge, and corner neighbors.)
+    pub fn neighbor_offsets() -> Vec<(isize, isize, isize)> {
+        let mut offsets: Vec<(isize, isize, isize)> = Vec::with_capacity(26);
+
+        for x in -1..=1 {
+            for y in -1..=1 {
+                for z in -1..=1 {
+                    if (x, y, z) != (0, 0, 0) {
+                        offsets.push((x, y, z));
+                    }
+                }
+            }
+        }
+
+        offsets
+    }
+
+    /// Position of the chunk's origin in the world.
+    pub fn to_world(&self) -> Vec3 {
+        Vec3::new(
+            (self.x * CHUNK_SIZE.x as isize) as f32,
+            (self.y * CHUNK_SIZE.y as isize) as f32,
+            (self.z * CHUNK_SIZE.z as isize) as f32,
+        )
+    }
 }
 
 #[derive(Default, Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
@@ -66,99 +121,93 @@ impl BlockPos {
     }
 }
 
+/// Position of a block in the world.
 #[derive(Default, Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
-struct BlockOffset {
-    pub x: i8,
-    pub y: i8,
-    pub z: i8,
+pub struct GlobalBlockPos {
+    pub x: isize,
+    pub y: isize,
+    pub z: isize,
 }
 
-impl BlockOffset {
-    pub const fn new(x: i8, y: i8, z: i8) -> Self {
+impl GlobalBlockPos {
+    pub const fn new(x: isize, y: isize, z: isize) -> Self {
         Self { x, y, z }
     }
 
-    pub fn full_pos(&self, chunk_pos: ChunkPos) -> (ChunkPos, BlockPos) {
-        let overflow_directions = self.overflow_direction();
-
-        let mut d_chunk_pos = chunk_pos;
-        for overflow_direction in overflow_directions {
-            match overflow_direction {
-                world::Direction::North => d_chunk_pos.z -= 1,
-                world::Direction::South => d_chunk_pos.z += 1,
-                world::Direction::East => d_chunk_pos.x += 1,
-                world::Direction::West => d_chunk_pos.x -= 1,
-                world::Direction::Up => d_chunk_pos.y += 1,
-                world::Direction::Down => d_chunk_pos.y -= 1,
-            }
-        }
-
-        (d_chunk_pos, self.wrap())
+    pub fn from_chunk(chunk_pos: ChunkPos, block_pos: BlockPos) -> Self {
+        Self::new(
+            chunk_pos.x * CHUNK_SIZE.x as isize + block_pos.x as isize,
+            chunk_pos.y * CHUNK_SIZE.y as isize + block_pos.y as isize,
+            chunk_pos.z * CHUNK_SIZE.z as isize + block_pos.z as isize,
+        )
     }
 
-    // Wrap an overflowing position to a regular position.
-    pub fn wrap(&self) -> BlockPos {
-        let x: u8 = self.x.rem_euclid(CHUNK_SIZE.x as i8) as u8;
-        let y: u8 = self.y.rem_euclid(CHUNK_SIZE.y as i8) as u8;
-        let z: u8 = self.z.rem_euclid(CHUNK_SIZE.z as i8) as u8;
-
-        BlockPos::new_unchecked(x, y, z)
+    /// The block that contains a position in the world. (Blocks are centered on whole numbers.)
+    pub fn from_world(position: Vec3) -> Self {
+        Self::new(
+            (position.x + 0.5).floor() as isize,
+            (position.y + 0.5).floor() as isize,
+            (position.z + 0.5).floor() as isize,
+        )
     }
 
-    // What direction does a position overflow in?
-    pub fn overflow_direction(&self) -> Vec<world::Direction> {
-        let mut directions: Vec<world::Direction> = Vec::new();
-
-        if self.position_overflow().not() {
-            return directions;
-        }
-
-        if self.x < 0 {
-            directions.push(world::Direction::West);
-        } else if self.x > (CHUNK_SIZE.x - 1) as i8 {
-            directions.push(world::Direction::East);
-        }
-
-        if self.y < 0 {
-            directions.push(world::Direction::Down);
-        } else if self.y > (CHUNK_SIZE.y - 1) as i8 {
-            directions.push(world::Direction::Up);
-        }
+    /// Center of the block in the world.
+    pub fn to_world(&self) -> Vec3 {
+        Vec3::new(self.x as f32, self.y as f32, self.z as f32)
+    }
 
-        if self.z < 0 {
-            directions.push(world::Direction::North);
-        } else if self.z > (CHUNK_SIZE.z - 1) as i8 {
-            directions.push(world::Direction::South);
-        }
+    /// The chunk that owns the block, and the block's position inside of it. (Works with negative positions too.)
+    pub fn to_chunk(&self) -> (ChunkPos, BlockPos) {
+        let size = (CHUNK_SIZE.x as isize, CHUNK_SIZE.y as isize, CHUNK_SIZE.z as isize);
 
-        directions
+        (
+            ChunkPos::new(self.x.div_euclid(size.0), self.y.div_euclid(size.1), self.z.div_euclid(size.2)),
+            BlockPos::new_unchecked(
+                self.x.rem_euclid(size.0) as u8,
+                self.y.rem_euclid(size.1) as u8,
+                self.z.rem_euclid(size.2) as u8,
+            ),
+        )
     }
 
-    // Is a position outside of a chunk?
-    pub fn position_overflow(&self) -> bool {
-        (self.x < 0 || self.x >= CHUNK_SIZE.x as i8)
-            || (self.y < 0 || self.y >= CHUNK_SIZE.y as i8)
-            || (self.z < 0 || self.z >= CHUNK_SIZE.z as i8)
+    /// The neighboring block in a direction.
+    pub fn offset(&self, direction: world::Direction) -> Self {
+        let (x, y, z) = direction.offset_with_position((self.x, self.y, self.z));
+
+        Self::new(x, y, z)
     }
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct Chunk {
     /// Chunk position.
     pub pos: ChunkPos,
-    /// The reason that I am using i8 instead of u8, is so I can read the blocks of neighboring chunks.
-    blocks: HashMap<BlockPos, BlockType>,
+    /// Palette-compressed blocks. (Starts out as a single air block.)
+    blocks: BlockStorage,
+    /// Sky and block light levels. (Filled in by light::light_chunk(), and fixed up by the chunk manager once the chunk is loaded.)
+    light: ChunkLight,
 }
 
 impl Chunk {
     pub fn new(pos: ChunkPos) -> Self {
         Self {
             pos,
-            blocks: HashMap::new(),
+            blocks: BlockStorage::default(),
+            light: ChunkLight::default(),
+        }
+    }
+
+    pub fn from_storage(pos: ChunkPos, blocks: BlockStorage) -> Self {
+        Self {
+            pos,
+            blocks,
+            light: ChunkLight::default(),
         }
     }
 
-    pub fn mesh(&self) -> Mesh {
+    /// Mesh the chunk without looking at its neighbors. (Everything outside of the chunk counts as air.)
+    /// Returns a mesh for every render layer, in RenderLayer::ALL order.
+    pub fn mesh(&self, atlas: &BlockAtlas) -> [Mesh; 3] {
         // Voxels store data like what sides need to be drawn.
         let mut voxels: Vec<Voxel> = Vec::new();
 
@@ -182,17 +231,35 @@ impl Chunk {
 
                         if Self::is_face(block, d_block) {
                             voxel_data.enable_side(d);
+
+                            // Outside of the chunk counts as open sky.
+                            let color = match chunk_storage::index_of(dbp) {
+                                Some(_) => light::face_color(
+                                    self.get_light(dbp, LightKind::Sky),
+                                    self.get_light(dbp, LightKind::Block),
+                                    d,
+                                ),
+                                _ => light::face_color(light::MAX_LIGHT, 0, d),
+                            };
+
+                            voxel_data.set_side_color(d, color);
                         }
                     }
 
+                    if voxel_data.get_sides().is_empty() == false {
+                        voxel_data.set_occluders(voxel::occluders_from(|(ox, oy, oz)| {
+                            let (ox, oy, oz) = (x as isize + ox, y as isize + oy, z as isize + oz);
+
+                            self.get_block(BlockPos::new_unchecked(ox as u8, oy as u8, oz as u8)).properties().transparent == false
+                        }));
+                    }
+
                     voxels.push(voxel_data);
                 }
             }
         }
 
-        let (mesh_data, indices) = mdi_from::voxel_array(&voxels);
-
-        mesher::create_mesh(&mesh_data, &indices)
+        mdi_from::voxel_array(&voxels, atlas).build()
     }
 
     // Used in self.mesh() to check whether a block needs a face or not.
@@ -204,24 +271,51 @@ impl Chunk {
         d_block.properties().transparent
     }
 
+    /// Positions outside of the chunk are always air.
     pub fn get_block(&self, block_pos: BlockPos) -> BlockType {
-        self.blocks
-            .get(&block_pos)
-            .copied()
-            .unwrap_or(BlockType::Air)
+        match chunk_storage::index_of(block_pos) {
+            Some(i) => self.blocks.get(i),
+            None => BlockType::AIR,
+        }
     }
 
+    /// Positions outside of the chunk are ignored.
     pub fn set_block(&mut self, position: BlockPos, blocktype: BlockType) {
-        match blocktype {
-            BlockType::Air => self.blocks.remove(&position),
-            _ => self.blocks.insert(position, blocktype),
-        };
+        if let Some(i) = chunk_storage::index_of(position) {
+            self.blocks.set(i, blocktype);
+        }
+    }
+
+    /// Positions outside of the chunk are always dark.
+    pub fn get_light(&self, block_pos: BlockPos, kind: LightKind) -> u8 {
+        match chunk_storage::index_of(block_pos) {
+            Some(i) => self.light.get(i, kind),
+            None => 0,
+        }
+    }
+
+    /// Positions outside of the chunk are ignored.
+    pub fn set_light(&mut self, block_pos: BlockPos, kind: LightKind, level: u8) {
+        if let Some(i) = chunk_storage::index_of(block_pos) {
+            self.light.set(i, kind, level);
+        }
     }
 
     pub fn set_all_blocks_from_hashmap(&mut self, blocks: HashMap<BlockPos, BlockType>) {
         for (k, v) in blocks.iter() {
             self.set_block(*k, *v);
         }
+
+        self.optimize_storage();
+    }
+
+    /// Shrink the block palette down to the blocks that are actually used.
+    pub fn optimize_storage(&mut self) {
+        self.blocks.optimize();
+    }
+
+    pub fn storage(&self) -> &BlockStorage {
+        &self.blocks
     }
 
     pub fn pos_local_to_global(&self, block_pos: BlockPos) -> (isize, isize, isize) {
@@ -252,113 +346,754 @@ impl Chunk {
 }
 
 #[derive(Resource)]
-struct ChunkManager {
+pub struct ChunkManager {
     chunks: HashMap<ChunkPos, Chunk>,
+    storage: WorldStorage,
+    /// Chunks that changed since they were last saved.
+    modified: HashSet<ChunkPos>,
+    /// Chunks whose mesh is outdated because of an edit.
+    dirty: HashSet<ChunkPos>,
+    /// Chunks whose mesh is outdated because light came in from a newly loaded neighbor. (Less urgent than edits.)
+    relit: HashSet<ChunkPos>,
+    /// Loaded chunks whose light still has to be spread to their neighbors, oldest first. (See light_queued_borders())
+    unlit_borders: VecDeque<ChunkPos>,
 }
 
 impl ChunkManager {
-    fn new() -> Self {
-        Self {
+    /// Open a saved world, or start a new one. (Fails if the world can't be read, so it never gets saved over.)
+    pub fn open(world_name: &str) -> Result<Self, io::Error> {
+        let storage = match WorldStorage::open(world_name) {
+            Ok(o) => o,
+            Err(e) => {
+                log::error!("Failed to open world '{}': {}", world_name, e);
+
+                return Err(e);
+            },
+        };
+
+        Ok(Self {
             chunks: HashMap::new(),
+            storage,
+            modified: HashSet::new(),
+            dirty: HashSet::new(),
+            relit: HashSet::new(),
+            unlit_borders: VecDeque::new(),
+        })
+    }
+
+    /// Load a chunk from disk, or generate it if it was never saved.
+    pub fn load_or_generate(&mut self, chunk_pos: ChunkPos) {
+        if self.chunks.contains_key(&chunk_pos) {
+            return;
+        }
+
+        let (mut chunk, modified) = match self.load_from_disk(chunk_pos) {
+            Ok(Some(chunk)) => (chunk, false),
+            // Generation is random, so new chunks have to be saved to stay the same.
+            Ok(None) => (Self::generate(chunk_pos), true),
+            Err(e) => {
+                // Not marked as modified, so the broken chunk on disk doesn't get overwritten.
+                log::error!("Failed to load chunk ({}, {}, {}), generating it instead: {}", chunk_pos.x, chunk_pos.y, chunk_pos.z, e);
+
+                (Self::generate(chunk_pos), false)
+            },
+        };
+
+        light::light_chunk(&mut chunk);
+
+        self.insert(chunk, modified);
+
+        // Whatever needed the chunk right now also needs its light to be right.
+        self.unlit_borders.retain(|p| *p != chunk_pos);
+        self.light_borders(chunk_pos);
+    }
+
+    /// Read a chunk from disk without loading it. (Returns None if it was never saved.)
+    pub fn load_from_disk(&self, chunk_pos: ChunkPos) -> Result<Option<Chunk>, io::Error> {
+        self.storage.load_chunk(chunk_pos)
+    }
+
+    /// Same as load_from_disk(), but the reading can be done later, on another thread.
+    pub fn chunk_source(&self, chunk_pos: ChunkPos) -> ChunkSource {
+        self.storage.chunk_source(chunk_pos)
+    }
+
+    /// Generate a new chunk. (Doesn't touch the chunk manager, so it can run on any thread.)
+    pub fn generate(chunk_pos: ChunkPos) -> Chunk {
+        let mut chunk = Chunk::new(chunk_pos);
+
+        world_generation::regular(&mut chunk);
+
+        chunk
+    }
+
+    /// Add a chunk to the loaded chunks, replacing any chunk that was already there.
+    /// The chunk has to be lit by light::light_chunk() already. Light gets spread between it and its neighbors later on. (See light_queued_borders())
+    pub fn insert(&mut self, chunk: Chunk, modified: bool) {
+        let chunk_pos = chunk.pos;
+
+        if modified {
+            self.modified.insert(chunk_pos);
+        }
+
+        self.chunks.insert(chunk_pos, chunk);
+
+        // Whoever loaded the chunk meshes it.
+        self.dirty.remove(&chunk_pos);
+
+        if self.unlit_borders.contains(&chunk_pos) == false {
+            self.unlit_borders.push_back(chunk_pos);
+        }
+    }
+
+    /// Spread light between newly loaded chunks and their neighbors, for at most a certain amount of chunks.
+    pub fn light_queued_borders(&mut self, limit: usize) {
+        let mut lit: usize = 0;
+
+        while lit < limit {
+            let chunk_pos = match self.unlit_borders.pop_front() {
+                Some(s) => s,
+                None => return,
+            };
+
+            if self.is_loaded(chunk_pos) {
+                self.light_borders(chunk_pos);
+                lit += 1;
+            }
+        }
+    }
+
+    // Light that changes here only catches up with a newly loaded chunk, so it goes to the relit chunks instead of the dirty ones.
+    fn light_borders(&mut self, chunk_pos: ChunkPos) {
+        let edited = std::mem::take(&mut self.dirty);
+
+        light::light_borders(self, chunk_pos);
+
+        let relit = std::mem::replace(&mut self.dirty, edited);
+
+        self.relit.extend(relit);
+    }
+
+    pub fn get_chunk(&self, chunk_pos: ChunkPos) -> Option<&Chunk> {
+        self.chunks.get(&chunk_pos)
+    }
+
+    pub fn is_loaded(&self, chunk_pos: ChunkPos) -> bool {
+        self.chunks.contains_key(&chunk_pos)
+    }
+
+    pub fn loaded_chunks(&self) -> impl Iterator<Item = &ChunkPos> {
+        self.chunks.keys()
+    }
+
+    /// Remove a chunk from memory, queueing it to be saved first if it was modified.
+    /// Once the last chunk of a region is unloaded, the region gets written to disk and dropped from memory too.
+    /// The chunk stays loaded if it can't be saved, so its changes aren't lost. (See can_unload())
+    pub fn unload(&mut self, chunk_pos: ChunkPos) -> Result<(), io::Error> {
+        if self.modified.contains(&chunk_pos) {
+            if let Some(chunk) = self.chunks.get(&chunk_pos) {
+                self.storage.save_chunk(chunk)?;
+            }
+
+            self.modified.remove(&chunk_pos);
+        }
+
+        self.chunks.remove(&chunk_pos);
+        self.dirty.remove(&chunk_pos);
+        self.relit.remove(&chunk_pos);
+        self.unlit_borders.retain(|p| *p != chunk_pos);
+
+        let region_pos = RegionPos::from_chunk(chunk_pos);
+
+        // The chunk is already gone, so this only gets reported. The region stays open, and the next save tries again.
+        if self.region_in_use(region_pos) == false {
+            if let Err(e) = self.storage.close_region(region_pos) {
+                log::error!("Failed to save region {}: {}", region_pos.file_name(), e);
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Can a chunk be unloaded without losing changes? (Not if it was modified, and its region failed to open.)
+    pub fn can_unload(&self, chunk_pos: ChunkPos) -> bool {
+        self.modified.contains(&chunk_pos) == false || self.storage.region_failed(RegionPos::from_chunk(chunk_pos)) == false
+    }
+
+    // Is any chunk of a region loaded?
+    fn region_in_use(&self, region_pos: RegionPos) -> bool {
+        let origin = (region_pos.x * REGION_SIZE, region_pos.y * REGION_SIZE, region_pos.z * REGION_SIZE);
+
+        for x in 0..REGION_SIZE {
+            for y in 0..REGION_SIZE {
+                for z in 0..REGION_SIZE {
+                    if self.chunks.contains_key(&ChunkPos::new(origin.0 + x, origin.1 + y, origin.2 + z)) {
+                        return true;
+                    }
+                }
+            }
+        }
+
+        return false;
+    }
+
+    /// Make sure a chunk gets written back to disk on the next save.
+    pub fn mark_modified(&mut self, chunk_pos: ChunkPos) {
+        self.modified.insert(chunk_pos);
+    }
+
+    /// Write every modified chunk to disk, and drop the regions that no loaded chunk needs anymore.
+    /// Every chunk and region is saved on its own, so one broken region doesn't stop the rest from being saved. (Chunks that failed stay modified.)
+    pub fn save_modified(&mut self) -> Result<(), io::Error> {
+        let mut failed: HashSet<ChunkPos> = HashSet::new();
+        let mut error: Option<io::Error> = None;
+
+        for chunk_pos in self.modified.iter() {
+            if let Some(chunk) = self.chunks.get(chunk_pos) {
+                if let Err(e) = self.storage.save_chunk(chunk) {
+                    failed.insert(*chunk_pos);
+                    error.get_or_insert(e);
+                }
+            }
+        }
+
+        if let Some(e) = error.take() {
+            error = Some(io::Error::new(e.kind(), format!("{} chunks couldn't be saved: {}", failed.len(), e)));
+        }
+
+        self.modified = failed;
+
+        if let Err(e) = self.storage.flush() {
+            error.get_or_insert(e);
+        }
+
+        // Like regions that were only opened to look for a chunk that never got loaded.
+        for region_pos in self.storage.open_regions() {
+            if self.region_in_use(region_pos) == false {
+                if let Err(e) = self.storage.close_region(region_pos) {
+                    error.get_or_insert(e);
+                }
+            }
+        }
+
+        match error {
+            Some(e) => Err(e),
+            None => Ok(()),
+        }
+    }
+
+    /// Get a block anywhere in the world. (Returns None if its chunk isn't loaded.)
+    pub fn get_block(&self, pos: GlobalBlockPos) -> Option<BlockType> {
+        let (chunk_pos, block_pos) = pos.to_chunk();
+
+        self.chunks.get(&chunk_pos).map(|c| c.get_block(block_pos))
+    }
+
+    /// Set a block anywhere in the world, loading (or generating) its chunk if needed.
+    /// Returns the block that was there before.
+    pub fn set_block(&mut self, pos: GlobalBlockPos, block: BlockType) -> BlockType {
+        let (chunk_pos, block_pos) = pos.to_chunk();
+
+        self.load_or_generate(chunk_pos);
+
+        let chunk = self.chunks.get_mut(&chunk_pos).unwrap(); // Guarenteed value.
+        let old = chunk.get_block(block_pos);
+
+        if old != block {
+            chunk.set_block(block_pos, block);
+            self.modified.insert(chunk_pos);
+            self.mark_dirty(chunk_pos, (block_pos, block_pos));
+
+            light::update_blocks(self, &[pos]);
+        }
+
+        old
+    }
+
+    /// Get the light level of a block anywhere in the world. (Returns None if its chunk isn't loaded.)
+    pub fn get_light(&self, pos: GlobalBlockPos, kind: LightKind) -> Option<u8> {
+        let (chunk_pos, block_pos) = pos.to_chunk();
+
+        self.chunks.get(&chunk_pos).map(|c| c.get_light(block_pos, kind))
+    }
+
+    /// Set the light level of a block, without spreading it. (See light.rs for that.)
+    /// Chunks that can see the block get remeshed if the level changed.
+    pub fn set_light(&mut self, pos: GlobalBlockPos, kind: LightKind, level: u8) {
+        let (chunk_pos, block_pos) = pos.to_chunk();
+
+        let chunk = match self.chunks.get_mut(&chunk_pos) {
+            Some(s) => s,
+            None => return,
+        };
+
+        if chunk.get_light(block_pos, kind) != level {
+            chunk.set_light(block_pos, kind, level);
+            self.mark_dirty(chunk_pos, (block_pos, block_pos));
+        }
+    }
+
+    /// Mark a chunk's mesh as outdated, along with every neighbor that touches the changed area.
+    /// (The area is given as the inclusive corners of the changed blocks inside the chunk.)
+    fn mark_dirty(&mut self, chunk_pos: ChunkPos, area: (BlockPos, BlockPos)) {
+        let (min, max) = area;
+
+        self.dirty.insert(chunk_pos);
+
+        // A changed block on the border can hide or reveal a face in the chunk next to it.
+        // Blocks on edges and corners also change the ambient occlusion of the chunks diagonal to them.
+        let touches = |offset: isize, min: u8, max: u8, size: u8| match offset {
+            -1 => min == 0,
+            1 => max == size - 1,
+            _ => true,
+        };
+
+        for o in ChunkPos::neighbor_offsets() {
+            if touches(o.0, min.x, max.x, CHUNK_SIZE.x) && touches(o.1, min.y, max.y, CHUNK_SIZE.y) && touches(o.2, min.z, max.z, CHUNK_SIZE.z) {
+                self.dirty.insert(chunk_pos.add(o));
+            }
+        }
+    }
+
+    /// Take every chunk whose mesh is outdated. (Each chunk is only returned once, no matter how many edits it got.)
+    pub fn take_dirty(&mut self) -> Vec<ChunkPos> {
+        self.dirty.drain().collect()
+    }
+
+    /// Take every chunk whose light changed because a neighbor was loaded. (Unlike dirty chunks, these can wait their turn to be remeshed.)
+    pub fn take_relit(&mut self) -> Vec<ChunkPos> {
+        self.relit.drain().collect()
+    }
+
+    /// Fill every block between two corners (inclusive, in any order), loading chunks as needed.
+    /// Returns the amount of blocks that actually changed. Regions bigger than MAX_FILL_VOLUME are refused.
+    pub fn fill_region(&mut self, a: GlobalBlockPos, b: GlobalBlockPos, block: BlockType) -> Result<usize, io::Error> {
+        let min = GlobalBlockPos::new(a.x.min(b.x), a.y.min(b.y), a.z.min(b.z));
+        let max = GlobalBlockPos::new(a.x.max(b.x), a.y.max(b.y), a.z.max(b.z));
+
+        let side = |lo: isize, hi: isize| (hi as i128 - lo as i128 + 1) as u128;
+        let volume = side(min.x, max.x) * side(min.y, max.y) * side(min.z, max.z);
+
+        if volume > MAX_FILL_VOLUME as u128 {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, format!("Can't fill {} blocks at once, the limit is {}!", volume, MAX_FILL_VOLUME)));
+        }
+
+        let (min_chunk, _) = min.to_chunk();
+        let (max_chunk, _) = max.to_chunk();
+
+        let mut changed: Vec<GlobalBlockPos> = Vec::new();
+
+        // Go chunk by chunk, so every chunk is only looked up once.
+        for cx in min_chunk.x..=max_chunk.x {
+            for cy in min_chunk.y..=max_chunk.y {
+                for cz in min_chunk.z..=max_chunk.z {
+                    let chunk_pos = ChunkPos::new(cx, cy, cz);
+
+                    self.load_or_generate(chunk_pos);
+
+                    let origin = GlobalBlockPos::from_chunk(chunk_pos, BlockPos::default());
+
+                    let local_min = (
+                        (min.x - origin.x).max(0),
+                        (min.y - origin.y).max(0),
+                        (min.z - origin.z).max(0),
+                    );
+                    let local_max = (
+                        (max.x - origin.x).min(CHUNK_SIZE.x as isize - 1),
+                        (max.y - origin.y).min(CHUNK_SIZE.y as isize - 1),
+                        (max.z - origin.z).min(CHUNK_SIZE.z as isize - 1),
+                    );
+
+                    let chunk = self.chunks.get_mut(&chunk_pos).unwrap(); // Guarenteed value.
+
+                    // Only the part of the chunk that actually changed has to be remeshed.
+                    let mut changed_area: Option<(BlockPos, BlockPos)> = None;
+
+                    for x in local_min.0..=local_max.0 {
+                        for y in local_min.1..=local_max.1 {
+                            for z in local_min.2..=local_max.2 {
+                                let block_pos = BlockPos::new_unchecked(x as u8, y as u8, z as u8);
+
+                                if chunk.get_block(block_pos) == block {
+                                    continue;
+                                }
+
+                                chunk.set_block(block_pos, block);
+                                changed.push(GlobalBlockPos::from_chunk(chunk_pos, block_pos));
+
+                                changed_area = Some(match changed_area {
+                                    Some((lo, hi)) => (
+                                        BlockPos::new_unchecked(lo.x.min(block_pos.x), lo.y.min(block_pos.y), lo.z.min(block_pos.z)),
+                                        BlockPos::new_unchecked(hi.x.max(block_pos.x), hi.y.max(block_pos.y), hi.z.max(block_pos.z)),
+                                    ),
+                                    None => (block_pos, block_pos),
+                                });
+                            }
+                        }
+                    }
+
+                    let area = match changed_area {
+                        Some(s) => s,
+                        None => continue,
+                    };
+
+                    chunk.optimize_storage();
+                    self.modified.insert(chunk_pos);
+                    self.mark_dirty(chunk_pos, area);
+                }
+            }
         }
+
+        // Relight everything at once, instead of block by block.
+        light::update_blocks(self, &changed);
+
+        Ok(changed.len())
+    }
+
+    /// Copy a chunk and all of its loaded neighbors, so it can be meshed on another thread.
+    pub fn snapshot(&self, chunk_pos: ChunkPos) -> Option<ChunkNeighborhood> {
+        let center = self.chunks.get(&chunk_pos)?.clone();
+
+        let mut neighbors: Vec<Option<Chunk>> = vec![None; 27];
+
+        for o in ChunkPos::neighbor_offsets() {
+            neighbors[ChunkNeighborhood::neighbor_index(o)] = self.chunks.get(&chunk_pos.add(o)).cloned();
+        }
+
+        Some(ChunkNeighborhood { center, neighbors })
+    }
+
+    /// Mesh a loaded chunk. (Returns None if it isn't loaded, or was deferred by the policy.)
+    pub fn mesh(&self, chunk_pos: ChunkPos, policy: MissingNeighborPolicy, mode: MeshingMode, weld_vertices: bool, lod: u8, neighbor_lods: [u8; 6], atlas: &BlockAtlas) -> Option<[Mesh; 3]> {
+        self.snapshot(chunk_pos)?.mesh(policy, mode, weld_vertices, lod, neighbor_lods, atlas)
+    }
+}
+
+/// What to do with faces on the border of a chunk, when the neighboring chunk isn't loaded.
+#[derive(Debug, Copy, Clone, Eq, PartialEq, ValueEnum)]
+pub enum MissingNeighborPolicy {
+    /// Draw the border faces, as if the neighbor was empty.
+    Air,
+    /// Skip the border faces, as if the neighbor was completely solid.
+    Solid,
+    /// Don't mesh the chunk at all until every neighbor is loaded.
+    Defer,
+}
+
+/// How the visible faces of a chunk are turned into quads.
+#[derive(Debug, Copy, Clone, Eq, PartialEq, ValueEnum)]
+pub enum MeshingMode {
+    /// One quad for every visible face.
+    PerFace,
+    /// Merge neighboring faces that look the same into bigger quads. (Greedy meshing)
+    Greedy,
+}
+
+/// A copy of a chunk and the 26 chunks around it. (Edge and corner neighbors are needed for ambient occlusion.)
+pub struct ChunkNeighborhood {
+    pub center: Chunk,
+    /// Indexed by neighbor_index(). (None if the neighbor wasn't loaded. The spot of the center chunk is always None.)
+    neighbors: Vec<Option<Chunk>>,
+}
+
+impl ChunkNeighborhood {
+    /// A chunk on its own, with none of its neighbors loaded.
+    pub fn alone(center: Chunk) -> Self {
+        Self { center, neighbors: vec![None; 27] }
+    }
+
+    // Where a neighbor goes in self.neighbors, by its offset from the center chunk.
+    fn neighbor_index(offset: (isize, isize, isize)) -> usize {
+        ((offset.0 + 1) * 9 + (offset.1 + 1) * 3 + (offset.2 + 1)) as usize
+    }
+
+    /// Directions of the face neighbors that weren't loaded when the snapshot was taken.
+    pub fn missing_neighbors(&self) -> Vec<world::Direction> {
+        world::Direction::all()
+            .into_iter()
+            .filter(|d| {
+                let o = d.offset();
+
+                self.neighbors[Self::neighbor_index((o.0 as isize, o.1 as isize, o.2 as isize))].is_none()
+            })
+            .collect()
     }
 
-    pub fn mesh(&self, chunk_pos: ChunkPos) -> Mesh {
-        let chunk = self.chunks.get(&chunk_pos).unwrap();
+    /// Offsets of every neighbor that wasn't loaded when the snapshot was taken, including edge and corner neighbors.
+    pub fn missing_offsets(&self) -> Vec<(isize, isize, isize)> {
+        ChunkPos::neighbor_offsets()
+            .into_iter()
+            .filter(|o| self.neighbors[Self::neighbor_index(*o)].is_none())
+            .collect()
+    }
+
+    // Chunk and local position of a position relative to the center chunk.
+    // (Returns None if the position is in a missing neighbor, or further away than the neighbors.)
+    fn locate(&self, x: isize, y: isize, z: isize) -> Option<(&Chunk, BlockPos)> {
+        let size = (CHUNK_SIZE.x as isize, CHUNK_SIZE.y as isize, CHUNK_SIZE.z as isize);
+
+        let offset = (x.div_euclid(size.0), y.div_euclid(size.1), z.div_euclid(size.2));
+
+        let wrapped = BlockPos::new_unchecked(
+            x.rem_euclid(size.0) as u8,
+            y.rem_euclid(size.1) as u8,
+            z.rem_euclid(size.2) as u8,
+        );
+
+        if offset == (0, 0, 0) {
+            return Some((&self.center, wrapped));
+        }
+
+        if offset.0.abs() > 1 || offset.1.abs() > 1 || offset.2.abs() > 1 {
+            return None;
+        }
+
+        self.neighbors[Self::neighbor_index(offset)].as_ref().map(|c| (c, wrapped))
+    }
+
+    // Block at a position relative to the center chunk. (Returns None if the block is in a missing neighbor.)
+    fn block_at(&self, x: isize, y: isize, z: isize) -> Option<BlockType> {
+        self.locate(x, y, z).map(|(c, p)| c.get_block(p))
+    }
+
+    // Sky and block light at a position relative to the center chunk. (Missing neighbors count as open sky.)
+    fn light_at(&self, x: isize, y: isize, z: isize) -> (u8, u8) {
+        match self.locate(x, y, z) {
+            Some((c, p)) => (c.get_light(p, LightKind::Sky), c.get_light(p, LightKind::Block)),
+            None => (light::MAX_LIGHT, 0),
+        }
+    }
+
+    /// Mesh the center chunk, culling faces against its neighbors. (Returns None if deferred by the policy.)
+    /// There is a mesh for every render layer, in RenderLayer::ALL order.
+    /// Welding vertices merges the corners that neighboring faces share, which takes longer but makes smaller meshes.
+    /// The LOD is how many blocks wide every cell of the mesh is. (See LOD_SCALES)
+    /// Neighbor LODs are the LODs the face neighbors are meshed at, by Direction::index(). Borders toward neighbors with more detail get closed off, so there are no gaps between them.
+    pub fn mesh(&self, policy: MissingNeighborPolicy, mode: MeshingMode, weld_vertices: bool, lod: u8, neighbor_lods: [u8; 6], atlas: &BlockAtlas) -> Option<[Mesh; 3]> {
+        self.mesh_builder(policy, mode, weld_vertices, lod, neighbor_lods, atlas).map(|x| x.build())
+    }
+
+    /// Same as mesh(), but without building the meshes yet.
+    pub fn mesh_builder(&self, policy: MissingNeighborPolicy, mode: MeshingMode, weld_vertices: bool, lod: u8, neighbor_lods: [u8; 6], atlas: &BlockAtlas) -> Option<LayeredMeshBuilder> {
+        if policy == MissingNeighborPolicy::Defer && self.missing_neighbors().is_empty() == false {
+            return None;
+        }
+
+        let grid = LodGrid::new(self, lod);
+        let (scale, size) = (grid.scale, grid.size);
 
         // Voxels store data like what sides need to be drawn.
         let mut voxels: Vec<Voxel> = Vec::new();
 
-        // Loop through every block in the chunk.
-        for x in 0..CHUNK_SIZE.x {
-            for y in 0..CHUNK_SIZE.y {
-                for z in 0..CHUNK_SIZE.z {
-                    let ibp = BlockPos::new_unchecked(x, y, z);
+        // Looked up once, since ambient occlusion checks a lot of blocks.
+        let transparent: Vec<bool> = BlockType::all().map(|b| b.properties().transparent).collect();
+        let is_opaque = |block: Option<BlockType>| match block {
+            Some(s) => transparent[s.id() as usize] == false,
+            None => false,
+        };
 
-                    let block = chunk.get_block(ibp);
+        // Block in front of a cell's face, to get the face's light from. (The middle of the face, on the axes it doesn't point along.)
+        let in_front = |c: isize, o: isize| match o {
+            1 => c * scale + scale,
+            -1 => c * scale - 1,
+            _ => c * scale + scale / 2,
+        };
 
-                    let mut voxel_data = Voxel::new((x, y, z), block);
+        // Whether a cell's face on the border needs to be drawn, by the actual blocks in front of it.
+        // Cells are solid if any of their blocks are, so they cover every block the neighbor doesn't draw a face for.
+        let exposed_to_blocks = |(x, y, z): (isize, isize, isize), block: BlockType, d: world::Direction| {
+            let o = d.offset();
+            let range = |c: isize, o: i8| match o {
+                0 => c * scale..(c + 1) * scale,
+                _ => in_front(c, o as isize)..in_front(c, o as isize) + 1,
+            };
+
+            for bx in range(x, o.0) {
+                for by in range(y, o.1) {
+                    for bz in range(z, o.2) {
+                        let face = match self.block_at(bx, by, bz) {
+                            Some(s) => Chunk::is_face(block, s),
+                            None => policy != MissingNeighborPolicy::Solid && Chunk::is_face(block, BlockType::AIR),
+                        };
+
+                        if face {
+                            return true;
+                        }
+                    }
+                }
+            }
 
-                    // Loop through all the neighboring blocks, and check if a face should be drawn.
-                    for d in world::Direction::all() {
-                        let (dx, dy, dz) =
-                            d.offset_with_position((x as isize, y as isize, z as isize)); // Returns isizes.
+            return false;
+        };
 
-                        let d_offset = BlockOffset::new(dx as i8, dy as i8, dz as i8);
-                        let (d_chunk_pos, dbp) = d_offset.full_pos(chunk_pos);
+        // Loop through every cell in the chunk. (At full detail, every cell is just a block.)
+        for x in 0..size.0 {
+            for y in 0..size.1 {
+                for z in 0..size.2 {
+                    let block = grid.get(x, y, z).unwrap_or(BlockType::AIR);
 
-                        let d_chunk = self.chunks.get(&d_chunk_pos).unwrap();
-                        let d_block = d_chunk.get_block(dbp);
+                    let mut voxel_data = Voxel::new((x as u8, y as u8, z as u8), block);
+
+                    // Loop through all the neighboring cells, and check if a face should be drawn.
+                    for d in world::Direction::all() {
+                        let (dx, dy, dz) = d.offset_with_position((x, y, z)); // Returns isizes.
+
+                        let outside = dx < 0 || dy < 0 || dz < 0 || dx >= size.0 || dy >= size.1 || dz >= size.2;
+
+                        let finer = (neighbor_lods[d.index()].max(1) as isize) < scale;
+
+                        let face = match grid.get(dx, dy, dz) {
+                            // Neighbors with more detail don't line up with the cells, so the sides of the border cells hang down over their blocks.
+                            Some(_) if outside && finer => exposed_to_blocks((x, y, z), block, d),
+                            Some(d_block) => Chunk::is_face(block, d_block),
+                            None => match policy {
+                                MissingNeighborPolicy::Solid => false,
+                                _ => Chunk::is_face(block, BlockType::AIR),
+                            },
+                        };
+
+                        if face {
+                            let o = d.offset();
+                            let (sky, block_light) = self.light_at(
+                                in_front(x, o.0 as isize),
+                                in_front(y, o.1 as isize),
+                                in_front(z, o.2 as isize),
+                            );
 
-                        if Chunk::is_face(block, d_block) {
                             voxel_data.enable_side(d);
+                            voxel_data.set_side_color(d, light::face_color(sky, block_light, d));
                         }
                     }
 
+                    if voxel_data.get_sides().is_empty() == false {
+                        voxel_data.set_occluders(voxel::occluders_from(|(ox, oy, oz)| {
+                            is_opaque(grid.get(x + ox, y + oy, z + oz))
+                        }));
+                    }
+
                     voxels.push(voxel_data);
                 }
             }
         }
 
-        let (mesh_data, indices) = mdi_from::voxel_array(&voxels);
+        let mut builder = match mode {
+            MeshingMode::PerFace => mdi_from::voxel_array(&voxels, atlas),
+            MeshingMode::Greedy => mdi_from::greedy(&voxels, atlas),
+        };
+
+        if weld_vertices {
+            builder = builder.map(mesher::optimize::share_vertices);
+        }
 
-        mesher::create_mesh(&mesh_data, &indices)
+        // Cells get meshed like blocks, so they have to be stretched back over the blocks they cover.
+        // (Textures too, so they still repeat once per block.)
+        if scale > 1 {
+            builder.scale_positions(scale as f32, (scale - 1) as f32 / 2.0);
+            builder.scale_uvs(scale as f32);
+        }
+
+        Some(builder)
     }
 }
 
-fn test_chunks(
-    mut cmds: Commands,
-    mut materials: ResMut<Assets<StandardMaterial>>,
-    mut meshes: ResMut<Assets<Mesh>>,
-    mut chunk_manager: ResMut<ChunkManager>,
-    asset_server: ResMut<AssetServer>,
-) {
-    let xyz_ranges: Rc<(Range<isize>, Range<isize>, Range<isize>)> = Rc::new((-1..2, -1..2, -1..2));
+/// How many blocks wide every cell of a chunk mesh can be. (1 is full detail, the rest are levels of detail for far away chunks.)
+pub const LOD_SCALES: [u8; 4] = [1, 2, 4, 8];
+
+// The blocks of a chunk neighborhood, downsampled into cells. (Includes the layer of cells around the center chunk.)
+struct LodGrid {
+    scale: isize,
+    size: (isize, isize, isize), // Cells in the center chunk, on every axis.
+    cells: Vec<Option<BlockType>>, // None if the cell is in a missing neighbor.
+}
+
+impl LodGrid {
+    fn new(neighborhood: &ChunkNeighborhood, lod: u8) -> Self {
+        let scale = lod.max(1) as isize;
+        let size = (CHUNK_SIZE.x as isize / scale, CHUNK_SIZE.y as isize / scale, CHUNK_SIZE.z as isize / scale);
 
-    for cx in xyz_ranges.0.clone() {
-        for cy in xyz_ranges.1.clone() {
-            for cz in xyz_ranges.2.clone() {
-                let chunk_pos = ChunkPos::new(cx, cy, cz);
-                let mut chunk = Chunk::new(chunk_pos);
+        let mut grid = Self {
+            scale,
+            size,
+            cells: Vec::with_capacity(((size.0 + 2) * (size.1 + 2) * (size.2 + 2)) as usize),
+        };
 
-                world_generation::regular(&mut chunk);
+        let ids = BlockType::count();
+        let mut counts: Vec<usize> = vec![0; ids];
 
-                chunk_manager.chunks.insert(chunk_pos, chunk);
+        for x in -1..=size.0 {
+            for y in -1..=size.1 {
+                for z in -1..=size.2 {
+                    grid.cells.push(grid.sample(neighborhood, (x, y, z), &mut counts));
+                }
             }
         }
+
+        return grid;
     }
 
-    for cx in xyz_ranges.0.clone() {
-        for cy in xyz_ranges.1.clone() {
-            for cz in xyz_ranges.2.clone() {
-                let chunk_pos = ChunkPos::new(cx, cy, cz);
-                let chunk = chunk_manager.chunks.get(&chunk_pos).unwrap();
-
-                cmds.spawn((
-                    PbrBundle {
-                        mesh: meshes.add(chunk.mesh()),
-                        transform: Transform::from_xyz(
-                            (cx * CHUNK_SIZE.x as isize) as f32,
-                            (cy * CHUNK_SIZE.y as isize) as f32,
-                            (cz * CHUNK_SIZE.z as isize) as f32,
-                        ),
-                        material: materials.add(StandardMaterial {
-                            // base_color: Color::rgb(0.05, 0.5, 0.35), // The only reason this is still here, is because I think it is a cool color, and it is a secret comment!
-                            base_color_texture: Some(asset_server.load(format!(
-                                "{}/block_atlas.png",
-                                places::custom_built_assets().to_string()
-                            ))),
-                            // double_sided: true, // debug
-                            // cull_mode: None, // debug
-                            reflectance: 0.15,
-                            perceptual_roughness: 0.6,
-                            ..default()
-                        }),
-                        ..default()
-                    },
-                    Name::new(format!("Chunk ({}, {}, {})", cx, cy, cz)),
-                ));
+    // The most common block in a cell that isn't air, so thin layers don't disappear in the distance.
+    fn sample(&self, neighborhood: &ChunkNeighborhood, cell: (isize, isize, isize), counts: &mut Vec<usize>) -> Option<BlockType> {
+        let s = self.scale;
+
+        if s == 1 {
+            return neighborhood.block_at(cell.0, cell.1, cell.2);
+        }
+
+        counts.iter_mut().for_each(|x| *x = 0);
+
+        for x in cell.0 * s..(cell.0 + 1) * s {
+            for y in cell.1 * s..(cell.1 + 1) * s {
+                for z in cell.2 * s..(cell.2 + 1) * s {
+                    counts[neighborhood.block_at(x, y, z)?.id() as usize] += 1;
+                }
             }
         }
+
+        let mut most_common = BlockType::AIR;
+        let mut most = 0;
+
+        for block in BlockType::all() {
+            let count = counts[block.id() as usize];
+
+            if block != BlockType::AIR && count > most {
+                most_common = block;
+                most = count;
+            }
+        }
+
+        return Some(most_common);
+    }
+
+    // Cell at a position relative to the center chunk, in cells. (Works one cell outside of the chunk.)
+    fn get(&self, x: isize, y: isize, z: isize) -> Option<BlockType> {
+        let (sx, sy, sz) = (self.size.0 + 2, self.size.1 + 2, self.size.2 + 2);
+        let (x, y, z) = (x + 1, y + 1, z + 1);
+
+        if x < 0 || y < 0 || z < 0 || x >= sx || y >= sy || z >= sz {
+            return None;
+        }
+
+        return self.cells[(x * sy * sz + y * sz + z) as usize];
+    }
+}
+
+#[derive(Resource)]
+struct ChunkAutosaveTimer(Timer);
+
+// Save modified chunks every once in a while, and when the game is closed.
+fn save_chunks(
+    time: Res<Time>,
+    mut timer: ResMut<ChunkAutosaveTimer>,
+    mut exit_events: EventReader<AppExit>,
+    mut chunk_manager: ResMut<ChunkManager>,
+) {
+    let exiting = exit_events.iter().count() > 0;
+
+    if timer.0.tick(time.delta()).just_finished() == false && exiting == false {
+        return;
+    }
+
+    if let Err(e) = chunk_manager.save_modified() {
+        log::error!("Failed to save chunks: {}", e);
     }
 }
@@ -0,0 +1,522 @@
+#![allow(dead_code)]
+
+use bevy::pbr::NotShadowCaster;
+use bevy::prelude::*;
+use bevy::render::mesh::Indices;
+use bevy::tasks::{AsyncComputeTaskPool, Task};
+use clap::Parser;
+use futures_lite::future;
+use hashbrown::{HashMap, HashSet};
+
+use crate::block::RenderLayer;
+use crate::block_atlas::BlockAtlas;
+use crate::chunk::{ChunkManager, ChunkPos, GlobalBlockPos, MeshingMode, MissingNeighborPolicy, LOD_SCALES};
+use crate::chunk_streaming::{ChunkEntities, ChunkStreamingSet};
+use crate::chunk_visibility::ChunkConnectivity;
+use crate::cli;
+use crate::log;
+use crate::log::macro_deps::*;
+use crate::mesher;
+use crate::voxel_material::{VoxelArrayMaterial, VoxelMaterial};
+use crate::world;
+
+pub struct ChunkMeshingPlugin;
+
+impl Plugin for ChunkMeshingPlugin {
+    fn build(&self, app: &mut App) {
+        let args = cli::Cli::parse();
+
+        app.insert_resource(ChunkMeshingSettings {
+            missing_neighbor_policy: args.missing_neighbors,
+            meshing_mode: args.meshing,
+            weld_vertices: args.weld_vertices,
+            lod: args.no_lod == false,
+            texture_array: args.texture_array,
+            ..default()
+        });
+        app.init_resource::<ChunkMeshState>();
+        app.init_resource::<MeshTasks>();
+        app.add_systems(Startup, setup_chunk_material);
+        app.add_systems(Update, (remesh_on_atlas_change, queue_dirty_chunks, update_chunk_lods, start_mesh_tasks, poll_mesh_tasks).chain().in_set(ChunkMeshingSet).after(ChunkStreamingSet));
+        app.add_systems(Update, (reload_chunk_textures.in_set(ChunkMeshingSet), sort_translucent_faces.after(ChunkMeshingSet)));
+    }
+}
+
+/// Systems that mesh chunks, and put the meshes on their entities.
+#[derive(SystemSet, Debug, Clone, PartialEq, Eq, Hash)]
+pub struct ChunkMeshingSet;
+
+#[derive(Resource)]
+pub struct ChunkMeshingSettings {
+    /// What to do with border faces when a neighboring chunk isn't loaded.
+    pub missing_neighbor_policy: MissingNeighborPolicy,
+    /// One quad per face, or greedy meshing.
+    pub meshing_mode: MeshingMode,
+    /// Merge the vertices that neighboring faces share. (Smaller meshes, but slower meshing.)
+    pub weld_vertices: bool,
+    /// Maximum amount of chunks being meshed in the background at once.
+    pub max_mesh_tasks: usize,
+    /// Use less detail for far away chunks.
+    pub lod: bool,
+    /// How far away from the camera (in chunks) chunks switch to 2x, 4x, and 8x LOD.
+    pub lod_distances: [f32; 3],
+    /// Sample block textures from the texture array instead of the atlas. (Falls back to the atlas if there isn't one.)
+    pub texture_array: bool,
+}
+
+impl ChunkMeshingSettings {
+    /// LOD a chunk should be meshed at, by how far it is from the chunk the camera is in. (See chunk::LOD_SCALES)
+    pub fn lod_for(&self, camera: ChunkPos, chunk_pos: ChunkPos) -> u8 {
+        if self.lod == false {
+            return 1;
+        }
+
+        let (dx, dy, dz) = (chunk_pos.x - camera.x, chunk_pos.y - camera.y, chunk_pos.z - camera.z);
+        let distance = ((dx * dx + dy * dy + dz * dz) as f32).sqrt();
+
+        let level = self.lod_distances.iter().filter(|x| distance >= **x).count();
+
+        return LOD_SCALES[level];
+    }
+
+    /// LODs of the face neighbors of a chunk, by Direction::index().
+    pub fn neighbor_lods_for(&self, camera: ChunkPos, chunk_pos: ChunkPos) -> [u8; 6] {
+        let mut lods = [1; 6];
+
+        for d in world::Direction::all() {
+            lods[d.index()] = self.lod_for(camera, chunk_pos.offset(d));
+        }
+
+        return lods;
+    }
+}
+
+impl Default for ChunkMeshingSettings {
+    fn default() -> Self {
+        Self {
+            missing_neighbor_policy: MissingNeighborPolicy::Solid,
+            meshing_mode: MeshingMode::PerFace,
+            weld_vertices: false,
+            max_mesh_tasks: bevy::tasks::available_parallelism() * 2,
+            lod: true,
+            lod_distances: [3.0, 6.0, 12.0],
+            texture_array: false,
+        }
+    }
+}
+
+/// Keeps track of which chunks need to be (re)meshed.
+#[derive(Resource, Default)]
+pub struct ChunkMeshState {
+    /// Offsets of the neighbors that weren't loaded when each chunk was last meshed. (Including edge and corner neighbors.)
+    missing: HashMap<ChunkPos, Vec<(isize, isize, isize)>>,
+    /// Chunks waiting to be meshed.
+    pending: HashSet<ChunkPos>,
+    /// Chunks that were edited, and should be remeshed before anything else.
+    edited: HashSet<ChunkPos>,
+    /// LOD that each chunk was last meshed at, and the LODs of its face neighbors at the time.
+    lods: HashMap<ChunkPos, (u8, [u8; 6])>,
+    /// Chunk the camera was in, last time LODs were checked.
+    camera: Option<ChunkPos>,
+}
+
+impl ChunkMeshState {
+    /// Mesh a newly loaded chunk, and remesh the neighbors that were missing it.
+    pub fn chunk_loaded(&mut self, chunk_pos: ChunkPos) {
+        self.pending.insert(chunk_pos);
+
+        for o in ChunkPos::neighbor_offsets() {
+            let neighbor = chunk_pos.add(o);
+
+            let was_missing = match self.missing.get(&neighbor) {
+                Some(s) => s.contains(&(-o.0, -o.1, -o.2)),
+                None => false,
+            };
+
+            if was_missing {
+                self.pending.insert(neighbor);
+            }
+        }
+    }
+
+    pub fn chunk_unloaded(&mut self, chunk_pos: ChunkPos) {
+        self.missing.remove(&chunk_pos);
+        self.pending.remove(&chunk_pos);
+        self.edited.remove(&chunk_pos);
+        self.lods.remove(&chunk_pos);
+    }
+
+    /// Remesh a chunk as soon as possible. (Used after edits, so they show up right away.)
+    pub fn request_remesh(&mut self, chunk_pos: ChunkPos) {
+        self.pending.remove(&chunk_pos);
+        self.edited.insert(chunk_pos);
+    }
+
+    /// Remesh a chunk in the background, once there is room for it. (Unless it is already waiting to be remeshed as soon as possible.)
+    pub fn queue_remesh(&mut self, chunk_pos: ChunkPos) {
+        if self.edited.contains(&chunk_pos) == false {
+            self.pending.insert(chunk_pos);
+        }
+    }
+
+    /// Remesh every chunk in the background. (Used when the textures change.)
+    pub fn remesh_all(&mut self, chunks: impl IntoIterator<Item = ChunkPos>) {
+        self.pending.extend(chunks);
+    }
+}
+
+/// Chunks that are being meshed on the async compute task pool. (None = deferred by the missing neighbor policy.)
+#[derive(Resource, Default)]
+pub struct MeshTasks(HashMap<ChunkPos, Task<Option<([Mesh; 3], ChunkConnectivity)>>>);
+
+impl MeshTasks {
+    /// Dropping a task cancels it.
+    pub fn cancel(&mut self, chunk_pos: ChunkPos) {
+        self.0.remove(&chunk_pos);
+    }
+}
+
+/// Materials shared by every chunk, one for each render layer. (Indexed by RenderLayer::index())
+#[derive(Resource)]
+pub enum ChunkMaterials {
+    /// Textures come from the block atlas. (Along with the atlas itself, so it can be replaced when the assets get rebuilt.)
+    Atlas(Handle<Image>, [Handle<VoxelMaterial>; 3]),
+    /// Textures come from the block texture array. (Same as above.)
+    Array(Handle<Image>, [Handle<VoxelArrayMaterial>; 3]),
+}
+
+/// Child entities of a chunk that hold the mesh of each render layer. (Indexed by RenderLayer::index())
+#[derive(Component)]
+pub struct ChunkLayers(pub [Entity; 3]);
+
+/// Which render layer of its chunk this entity draws.
+#[derive(Component)]
+pub struct ChunkLayer(pub RenderLayer);
+
+/// Spawn the render layer entities of a chunk, as children of it.
+pub fn spawn_chunk_layers(cmds: &mut Commands, chunk: Entity) -> ChunkLayers {
+    let layers = RenderLayer::ALL.map(|layer| {
+        let mut entity = cmds.spawn((
+            SpatialBundle::default(),
+            ChunkLayer(layer),
+            Name::new(format!("{:?} Layer", layer)),
+        ));
+
+        // The shadow pass doesn't know about the chunk shader, so it can't cut holes into shadows.
+        if layer != RenderLayer::Opaque {
+            entity.insert(NotShadowCaster);
+        }
+
+        entity.id()
+    });
+
+    cmds.entity(chunk).push_children(&layers);
+
+    return ChunkLayers(layers);
+}
+
+fn setup_chunk_material(
+    mut cmds: Commands,
+    settings: Res<ChunkMeshingSettings>,
+    block_atlas: Res<BlockAtlas>,
+    mut images: ResMut<Assets<Image>>,
+    mut materials: ResMut<Assets<VoxelMaterial>>,
+    mut array_materials: ResMut<Assets<VoxelArrayMaterial>>,
+) {
+    if settings.texture_array {
+        match block_atlas.texture_array_image() {
+            Ok(o) => {
+                let textures = images.add(o);
+
+                cmds.insert_resource(ChunkMaterials::Array(textures.clone(), RenderLayer::ALL.map(|layer| {
+                    array_materials.add(VoxelArrayMaterial::for_layer(textures.clone(), layer))
+                })));
+
+                return;
+            },
+            Err(e) => log::error!("Can't use the block texture array, using the block atlas instead: {}", e),
+        };
+    }
+
+    let atlas = match block_atlas.atlas_image() {
+        Ok(o) => o,
+        Err(e) => {
+            log::error!("Failed to load the block atlas, blocks will be missing their textures: {}", e);
+
+            Image::default()
+        },
+    };
+
+    let atlas: Handle<Image> = images.add(atlas);
+
+    cmds.insert_resource(ChunkMaterials::Atlas(atlas.clone(), RenderLayer::ALL.map(|layer| {
+        materials.add(VoxelMaterial::for_layer(atlas.clone(), layer))
+    })));
+}
+
+// The chunk textures aren't loaded as asset files (they have mip levels in them), so they have to be swapped out by hand after a rebuild.
+fn reload_chunk_textures(
+    atlas: Res<BlockAtlas>,
+    materials: Res<ChunkMaterials>,
+    mut images: ResMut<Assets<Image>>,
+) {
+    if atlas.is_changed() == false || atlas.is_added() {
+        return;
+    }
+
+    let (textures, image) = match materials.as_ref() {
+        ChunkMaterials::Atlas(textures, _) => (textures, atlas.atlas_image()),
+        ChunkMaterials::Array(textures, _) => (textures, atlas.texture_array_image()),
+    };
+
+    match image {
+        Ok(o) => {
+            images.set_untracked(textures, o);
+        },
+        // Keeps the old one, which is better than nothing.
+        Err(e) => log::error!("Failed to reload the block textures: {}", e),
+    };
+}
+
+// The UVs baked into every chunk mesh point to the old atlas layout after a rebuild.
+fn remesh_on_atlas_change(
+    atlas: Res<BlockAtlas>,
+    chunk_entities: Res<ChunkEntities>,
+    mut state: ResMut<ChunkMeshState>,
+) {
+    if atlas.is_changed() == false || atlas.is_added() {
+        return;
+    }
+
+    state.remesh_all(chunk_entities.0.keys().copied());
+}
+
+// Remesh chunks that were edited since last frame. (Many edits to one chunk still only remesh it once.)
+// Chunks that only got light from a newly loaded neighbor wait in line with everything else.
+fn queue_dirty_chunks(
+    mut chunk_manager: ResMut<ChunkManager>,
+    chunk_entities: Res<ChunkEntities>,
+    mut state: ResMut<ChunkMeshState>,
+) {
+    // Chunks without an entity get meshed once streaming spawns one.
+    for chunk_pos in chunk_manager.take_dirty() {
+        if chunk_entities.0.contains_key(&chunk_pos) {
+            state.request_remesh(chunk_pos);
+        }
+    }
+
+    for chunk_pos in chunk_manager.take_relit() {
+        if chunk_entities.0.contains_key(&chunk_pos) {
+            state.queue_remesh(chunk_pos);
+        }
+    }
+}
+
+// Remesh chunks that are at the wrong LOD (or whose neighbors are), once the camera moves to another chunk.
+fn update_chunk_lods(
+    cameras: Query<&GlobalTransform, With<Camera3d>>,
+    settings: Res<ChunkMeshingSettings>,
+    mut state: ResMut<ChunkMeshState>,
+) {
+    let camera = match cameras.get_single() {
+        Ok(o) => ChunkPos::from_world(o.translation()),
+        Err(_) => return,
+    };
+
+    if state.camera == Some(camera) && settings.is_changed() == false {
+        return;
+    }
+
+    state.camera = Some(camera);
+
+    // Chunks that haven't been meshed yet get the right LOD once they are.
+    let outdated: Vec<ChunkPos> = state.lods.iter()
+        .filter(|(p, lods)| (settings.lod_for(camera, **p), settings.neighbor_lods_for(camera, **p)) != **lods)
+        .map(|(p, _)| *p)
+        .collect();
+
+    // The old mesh stays until the new one is done, so there are no holes while switching.
+    state.pending.extend(outdated);
+}
+
+// Snapshot pending chunks and their neighbors, and mesh them in the background.
+fn start_mesh_tasks(
+    settings: Res<ChunkMeshingSettings>,
+    atlas: Res<BlockAtlas>,
+    chunk_manager: Res<ChunkManager>,
+    mut state: ResMut<ChunkMeshState>,
+    mut tasks: ResMut<MeshTasks>,
+) {
+    // Edited chunks skip the task limit, so edits never wait behind newly streamed in chunks.
+    let edited: Vec<ChunkPos> = state.edited.drain().collect();
+
+    for chunk_pos in edited {
+        start_mesh_task(&settings, &atlas, &chunk_manager, &mut state, &mut tasks, chunk_pos);
+    }
+
+    let pending: Vec<ChunkPos> = state.pending.iter().copied().collect();
+
+    for chunk_pos in pending {
+        if tasks.0.len() >= settings.max_mesh_tasks {
+            return;
+        }
+
+        state.pending.remove(&chunk_pos);
+
+        start_mesh_task(&settings, &atlas, &chunk_manager, &mut state, &mut tasks, chunk_pos);
+    }
+}
+
+fn start_mesh_task(
+    settings: &ChunkMeshingSettings,
+    atlas: &BlockAtlas,
+    chunk_manager: &ChunkManager,
+    state: &mut ChunkMeshState,
+    tasks: &mut MeshTasks,
+    chunk_pos: ChunkPos,
+) {
+    let snapshot = match chunk_manager.snapshot(chunk_pos) {
+        Some(s) => s,
+        None => return, // Unloaded before it got meshed.
+    };
+
+    state.missing.insert(chunk_pos, snapshot.missing_offsets());
+
+    let (lod, neighbor_lods) = match state.camera {
+        Some(s) => (settings.lod_for(s, chunk_pos), settings.neighbor_lods_for(s, chunk_pos)),
+        None => (1, [1; 6]),
+    };
+
+    state.lods.insert(chunk_pos, (lod, neighbor_lods));
+
+    let (policy, mode, weld) = (settings.missing_neighbor_policy, settings.meshing_mode, settings.weld_vertices);
+    let atlas = atlas.clone();
+
+    // Replaces (and cancels) any older task for the chunk, since its mesh would be outdated.
+    tasks.0.insert(chunk_pos, AsyncComputeTaskPool::get().spawn(async move {
+        let meshes = snapshot.mesh(policy, mode, weld, lod, neighbor_lods, &atlas)?;
+
+        Some((meshes, ChunkConnectivity::compute(&snapshot.center)))
+    }));
+}
+
+// Swap finished meshes onto their chunk entities.
+fn poll_mesh_tasks(
+    mut cmds: Commands,
+    mut meshes: ResMut<Assets<Mesh>>,
+    materials: Res<ChunkMaterials>,
+    chunk_entities: Res<ChunkEntities>,
+    chunk_layers: Query<&ChunkLayers>,
+    cameras: Query<&GlobalTransform, With<Camera3d>>,
+    mut tasks: ResMut<MeshTasks>,
+) {
+    let camera = cameras.get_single().map(|x| x.translation()).unwrap_or(Vec3::ZERO);
+
+    let finished: Vec<ChunkPos> = tasks.0.iter()
+        .filter(|(_, t)| t.is_finished())
+        .map(|(p, _)| *p)
+        .collect();
+
+    for chunk_pos in finished {
+        let task = tasks.0.remove(&chunk_pos).unwrap(); // Guarenteed value.
+
+        // The task is already finished, so this doesn't actually block.
+        let (mut layer_meshes, connectivity) = match future::block_on(task) {
+            Some(s) => s,
+            None => continue, // Deferred, it gets meshed again once its neighbors show up.
+        };
+
+        let entity = match chunk_entities.0.get(&chunk_pos) {
+            Some(s) => *s,
+            None => continue,
+        };
+
+        let layers = match chunk_layers.get(entity) {
+            Ok(o) => o,
+            Err(_) => continue,
+        };
+
+        cmds.entity(entity).insert(connectivity);
+
+        // Later camera movement gets handled by sort_translucent_faces().
+        mesher::sort_faces_back_to_front(&mut layer_meshes[RenderLayer::Translucent.index()], camera - chunk_pos.to_world());
+
+        for (i, mesh) in layer_meshes.into_iter().enumerate() {
+            set_chunk_mesh(&mut cmds, &mut meshes, &materials, i, layers.0[i], mesh);
+        }
+    }
+}
+
+// Blended faces have to be drawn from back to front, which changes as the camera moves around.
+// Faces are only ever one block apart, so the order doesn't really change until the camera is in another block.
+// Only chunks around the camera get sorted every time, the order of far away chunks barely changes until the camera is in another chunk.
+fn sort_translucent_faces(
+    cameras: Query<&GlobalTransform, With<Camera3d>>,
+    layers: Query<(&ChunkLayer, &Handle<Mesh>, &GlobalTransform)>,
+    mut meshes: ResMut<Assets<Mesh>>,
+    mut last_sorted: Local<Option<GlobalBlockPos>>,
+) {
+    let camera = match cameras.get_single() {
+        Ok(o) => o.translation(),
+        Err(_) => return,
+    };
+
+    let camera_block = GlobalBlockPos::from_world(camera);
+    let camera_chunk = camera_block.to_chunk().0;
+
+    let changed_chunk = match *last_sorted {
+        Some(s) if s == camera_block => return,
+        Some(s) => s.to_chunk().0 != camera_chunk,
+        None => true,
+    };
+
+    *last_sorted = Some(camera_block);
+
+    for (layer, handle, transform) in layers.iter() {
+        if layer.0 != RenderLayer::Translucent {
+            continue;
+        }
+
+        let chunk_pos = ChunkPos::from_world(transform.translation());
+        let nearby = (chunk_pos.x - camera_chunk.x).abs() <= 1 && (chunk_pos.y - camera_chunk.y).abs() <= 1 && (chunk_pos.z - camera_chunk.z).abs() <= 1;
+
+        if nearby == false && changed_chunk == false {
+            continue;
+        }
+
+        // Changing a mesh uploads it again, so it is only changed if the order actually did.
+        let indices = match meshes.get(handle).and_then(|x| mesher::faces_back_to_front(x, camera - transform.translation())) {
+            Some(s) => s,
+            None => continue,
+        };
+
+        if let Some(mesh) = meshes.get_mut(handle) {
+            mesh.set_indices(Some(Indices::U32(indices)));
+        }
+    }
+}
+
+/// Replace the mesh of a chunk layer entity, and give it the material of its render layer. (Layers without any faces don't get a mesh.)
+pub fn set_chunk_mesh(
+    cmds: &mut Commands,
+    meshes: &mut Assets<Mesh>,
+    materials: &ChunkMaterials,
+    layer: usize,
+    entity: Entity,
+    mesh: Mesh,
+) {
+    if mesh.count_vertices() == 0 {
+        cmds.entity(entity).remove::<Handle<Mesh>>();
+
+        return;
+    }
+
+    let mut entity = cmds.entity(entity);
+
+    entity.insert(meshes.add(mesh));
+
+    match materials {
+        ChunkMaterials::Atlas(_, m) => entity.insert(m[layer].clone()),
+        ChunkMaterials::Array(_, m) => entity.insert(m[layer].clone()),
+    };
+}
@@ -0,0 +1,538 @@
+#![allow(dead_code)]
+
+use crate::block::{BlockIdTable, BlockType};
+use crate::chunk::{BlockPos, CHUNK_SIZE};
+
+/// Amount of blocks in a single chunk.
+pub const CHUNK_VOLUME: usize = CHUNK_SIZE.x as usize * CHUNK_SIZE.y as usize * CHUNK_SIZE.z as usize;
+
+/// Dense block storage for a chunk.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum BlockStorage {
+    /// Every block in the chunk is the same, so there is nothing to index. (Empty, or fully solid chunks.)
+    Single(BlockType),
+    /// Every block is a bit-packed index into a per-chunk palette.
+    Paletted(PalettedBlocks),
+}
+
+impl BlockStorage {
+    pub fn new(block: BlockType) -> Self {
+        return Self::Single(block);
+    }
+
+    pub fn get(&self, index: usize) -> BlockType {
+        return match self {
+            Self::Single(block) => *block,
+            Self::Paletted(p) => p.get(index),
+        };
+    }
+
+    pub fn set(&mut self, index: usize, block: BlockType) {
+        match self {
+            Self::Single(current) => {
+                if *current == block {
+                    return;
+                }
+
+                let mut paletted = PalettedBlocks::filled(*current);
+                paletted.set(index, block);
+
+                *self = Self::Paletted(paletted);
+            },
+            Self::Paletted(p) => p.set(index, block),
+        };
+    }
+
+    /// Drop unused palette entries, shrink the bit width, and fall back to a single block if possible.
+    pub fn optimize(&mut self) {
+        let paletted = match self {
+            Self::Single(_) => return,
+            Self::Paletted(p) => p,
+        };
+
+        let mut used: Vec<bool> = vec![false; paletted.palette.len()];
+
+        for i in 0..CHUNK_VOLUME {
+            used[paletted.get_raw(i)] = true;
+        }
+
+        let palette: Vec<BlockType> = paletted.palette.iter()
+            .enumerate()
+            .filter(|(i, _)| used[*i])
+            .map(|(_, b)| *b)
+            .collect();
+
+        if palette.len() == 1 {
+            *self = Self::Single(palette[0]);
+
+            return;
+        }
+
+        if palette.len() == paletted.palette.len() {
+            return;
+        }
+
+        let mut optimized = PalettedBlocks::with_palette(palette);
+
+        for i in 0..CHUNK_VOLUME {
+            optimized.set(i, paletted.get(i));
+        }
+
+        *self = Self::Paletted(optimized);
+    }
+
+    /// Is every block in the chunk the same?
+    pub fn is_uniform(&self) -> bool {
+        return matches!(self, Self::Single(_));
+    }
+
+    /// Approximate amount of heap memory used by the storage in bytes.
+    pub fn heap_size(&self) -> usize {
+        return match self {
+            Self::Single(_) => 0,
+            Self::Paletted(p) => p.heap_size(),
+        };
+    }
+
+    /// Serialize the storage. (Little endian, used for saving chunks to disk.)
+    /// Blocks are saved with their ID in the world's block ID table, instead of the registry's.
+    ///
+    /// Single:   [0, block id (u16)]
+    /// Paletted: [1, bits (u8), palette length (u16), palette ids (u16)..., words (u64)...]
+    pub fn to_bytes(&self, ids: &BlockIdTable) -> Vec<u8> {
+        let mut bytes: Vec<u8> = Vec::new();
+
+        match self {
+            Self::Single(block) => {
+                bytes.push(STORAGE_SINGLE);
+                bytes.extend(ids.saved_id(*block).to_le_bytes());
+            },
+            Self::Paletted(p) => {
+                bytes.push(STORAGE_PALETTED);
+                bytes.push(p.bits);
+                bytes.extend((p.palette.len() as u16).to_le_bytes());
+
+                for block in p.palette.iter() {
+                    bytes.extend(ids.saved_id(*block).to_le_bytes());
+                }
+
+                for word in p.words.iter() {
+                    bytes.extend(word.to_le_bytes());
+                }
+            },
+        };
+
+        return bytes;
+    }
+
+    /// Inverse of to_bytes(). (Returns None if the data is corrupt.)
+    pub fn from_bytes(bytes: &[u8], ids: &BlockIdTable) -> Option<Self> {
+        let mut reader = ByteReader::new(bytes);
+
+        let storage = match reader.u8()? {
+            STORAGE_SINGLE => Self::Single(ids.block(reader.u16()?)?),
+            STORAGE_PALETTED => {
+                let bits = reader.u8()?;
+
+                if bits == 0 || bits > 16 {
+                    return None;
+                }
+
+                let palette_len = reader.u16()? as usize;
+
+                if palette_len == 0 || palette_len > (1usize << bits) {
+                    return None;
+                }
+
+                let mut palette: Vec<BlockType> = Vec::with_capacity(palette_len);
+
+                for _ in 0..palette_len {
+                    palette.push(ids.block(reader.u16()?)?);
+                }
+
+                let mut words: Vec<u64> = Vec::with_capacity(PalettedBlocks::word_count(bits));
+
+                for _ in 0..PalettedBlocks::word_count(bits) {
+                    words.push(reader.u64()?);
+                }
+
+                let paletted = PalettedBlocks { palette, bits, words };
+
+                // Indices pointing outside of the palette would panic later on.
+                for i in 0..CHUNK_VOLUME {
+                    if paletted.get_raw(i) >= paletted.palette.len() {
+                        return None;
+                    }
+                }
+
+                Self::Paletted(paletted)
+            },
+            _ => return None,
+        };
+
+        if reader.remaining() != 0 {
+            return None;
+        }
+
+        return Some(storage);
+    }
+}
+
+impl Default for BlockStorage {
+    fn default() -> Self {
+        return Self::new(BlockType::AIR);
+    }
+}
+
+/// Palette indices packed into 64 bit words. Indices never straddle two words.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PalettedBlocks {
+    palette: Vec<BlockType>,
+    bits: u8,
+    words: Vec<u64>,
+}
+
+impl PalettedBlocks {
+    /// A palette containing only one block, which every index points to.
+    pub fn filled(block: BlockType) -> Self {
+        return Self::with_palette(vec![block]);
+    }
+
+    fn with_palette(palette: Vec<BlockType>) -> Self {
+        let bits = Self::bits_for(palette.len());
+
+        return Self {
+            palette,
+            bits,
+            words: vec![0; Self::word_count(bits)],
+        };
+    }
+
+    // Smallest bit width that can index a palette of this length. (Never less than 1.)
+    fn bits_for(palette_len: usize) -> u8 {
+        let mut bits: u8 = 1;
+
+        while (1usize << bits) < palette_len {
+            bits += 1;
+        }
+
+        return bits;
+    }
+
+    fn entries_per_word(bits: u8) -> usize {
+        return 64 / bits as usize;
+    }
+
+    fn word_count(bits: u8) -> usize {
+        let epw = Self::entries_per_word(bits);
+
+        return (CHUNK_VOLUME + epw - 1) / epw;
+    }
+
+    fn mask(&self) -> u64 {
+        return (1u64 << self.bits) - 1;
+    }
+
+    fn get_raw(&self, index: usize) -> usize {
+        let epw = Self::entries_per_word(self.bits);
+        let shift = (index % epw) * self.bits as usize;
+
+        return ((self.words[index / epw] >> shift) & self.mask()) as usize;
+    }
+
+    fn set_raw(&mut self, index: usize, value: usize) {
+        let epw = Self::entries_per_word(self.bits);
+        let shift = (index % epw) * self.bits as usize;
+        let mask = self.mask();
+
+        let word = &mut self.words[index / epw];
+
+        *word = (*word & !(mask << shift)) | ((value as u64 & mask) << shift);
+    }
+
+    pub fn get(&self, index: usize) -> BlockType {
+        return self.palette[self.get_raw(index)];
+    }
+
+    pub fn set(&mut self, index: usize, block: BlockType) {
+        let palette_index = self.palette_index(block);
+
+        self.set_raw(index, palette_index);
+    }
+
+    // Find a block in the palette, adding it (and growing the bit width) if it is new.
+    fn palette_index(&mut self, block: BlockType) -> usize {
+        if let Some(i) = self.palette.iter().position(|x| *x == block) {
+            return i;
+        }
+
+        self.palette.push(block);
+
+        if self.palette.len() > (1usize << self.bits) {
+            self.repack(self.bits + 1);
+        }
+
+        return self.palette.len() - 1;
+    }
+
+    // Copy every index into a new set of words with a different bit width.
+    fn repack(&mut self, bits: u8) {
+        let mut repacked = Self {
+            palette: Vec::new(),
+            bits,
+            words: vec![0; Self::word_count(bits)],
+        };
+
+        for i in 0..CHUNK_VOLUME {
+            repacked.set_raw(i, self.get_raw(i));
+        }
+
+        self.bits = repacked.bits;
+        self.words = repacked.words;
+    }
+
+    pub fn palette(&self) -> &Vec<BlockType> {
+        return &self.palette;
+    }
+
+    pub fn bits(&self) -> u8 {
+        return self.bits;
+    }
+
+    pub fn heap_size(&self) -> usize {
+        return self.palette.capacity() * std::mem::size_of::<BlockType>()
+            + self.words.capacity() * std::mem::size_of::<u64>();
+    }
+}
+
+/// Position of a block in the dense storage. (Returns None if the position is outside of the chunk.)
+pub fn index_of(block_pos: BlockPos) -> Option<usize> {
+    if block_pos.x >= CHUNK_SIZE.x || block_pos.y >= CHUNK_SIZE.y || block_pos.z >= CHUNK_SIZE.z {
+        return None;
+    }
+
+    let (x, y, z) = (block_pos.x as usize, block_pos.y as usize, block_pos.z as usize);
+
+    return Some((y * CHUNK_SIZE.z as usize + z) * CHUNK_SIZE.x as usize + x);
+}
+
+/// Inverse of index_of().
+pub fn pos_of(index: usize) -> BlockPos {
+    let x = index % CHUNK_SIZE.x as usize;
+    let z = (index / CHUNK_SIZE.x as usize) % CHUNK_SIZE.z as usize;
+    let y = index / (CHUNK_SIZE.x as usize * CHUNK_SIZE.z as usize);
+
+    return BlockPos::new_unchecked(x as u8, y as u8, z as u8);
+}
+
+const STORAGE_SINGLE: u8 = 0;
+const STORAGE_PALETTED: u8 = 1;
+
+// Little endian reader that returns None instead of panicking when it runs out of bytes.
+struct ByteReader<'a> {
+    bytes: &'a [u8],
+}
+
+impl<'a> ByteReader<'a> {
+    fn new(bytes: &'a [u8]) -> Self {
+        return Self { bytes };
+    }
+
+    fn take<const N: usize>(&mut self) -> Option<[u8; N]> {
+        if self.bytes.len() < N {
+            return None;
+        }
+
+        let (head, tail) = self.bytes.split_at(N);
+        self.bytes = tail;
+
+        return head.try_into().ok();
+    }
+
+    fn u8(&mut self) -> Option<u8> {
+        return Some(u8::from_le_bytes(self.take()?));
+    }
+
+    fn u16(&mut self) -> Option<u16> {
+        return Some(u16::from_le_bytes(self.take()?));
+    }
+
+    fn u64(&mut self) -> Option<u64> {
+        return Some(u64::from_le_bytes(self.take()?));
+    }
+
+    fn remaining(&self) -> usize {
+        return self.bytes.len();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::block::{BlockIdTable, BlockType};
+    use crate::chunk_storage::{BlockStorage, PalettedBlocks, CHUNK_VOLUME};
+
+    fn paletted(storage: &BlockStorage) -> &PalettedBlocks {
+        return match storage {
+            BlockStorage::Paletted(p) => p,
+            BlockStorage::Single(_) => panic!("Expected paletted storage, got {:?}", storage),
+        };
+    }
+
+    // Paletted bytes, with every index set to the same value.
+    fn paletted_bytes(bits: u8, palette: &[u16], index: u64) -> Vec<u8> {
+        let mut bytes: Vec<u8> = vec![1, bits];
+
+        bytes.extend((palette.len() as u16).to_le_bytes());
+
+        for id in palette.iter() {
+            bytes.extend(id.to_le_bytes());
+        }
+
+        let epw = 64 / bits as usize;
+        let word = (0..epw).fold(0u64, |w, i| w | (index << (i * bits as usize)));
+
+        for _ in 0..(CHUNK_VOLUME + epw - 1) / epw {
+            bytes.extend(word.to_le_bytes());
+        }
+
+        return bytes;
+    }
+
+    #[test]
+    fn palette_grows_through_every_bit_width() {
+        let mut storage = BlockStorage::new(BlockType::from_id_unchecked(0));
+        let mut expected: Vec<BlockType> = vec![BlockType::from_id_unchecked(0); CHUNK_VOLUME];
+        let mut last_bits: u8 = 0;
+
+        // Enough new blocks to need 13 bits, which is more than a chunk ever needs at once.
+        for id in 1..=(1u16 << 12) {
+            let index = (id as usize * 7) % CHUNK_VOLUME;
+            let block = BlockType::from_id_unchecked(id);
+
+            storage.set(index, block);
+            expected[index] = block;
+
+            let p = paletted(&storage);
+
+            assert_eq!(p.palette().len(), id as usize + 1);
+            assert_eq!(p.bits(), PalettedBlocks::bits_for(p.palette().len()));
+
+            // Every block has to survive being repacked into the new width.
+            if p.bits() != last_bits {
+                for i in 0..CHUNK_VOLUME {
+                    assert_eq!(storage.get(i), expected[i], "Block {} changed when growing to {} bits", i, p.bits());
+                }
+
+                last_bits = p.bits();
+            }
+        }
+
+        assert_eq!(last_bits, 13);
+    }
+
+    #[test]
+    fn set_back_to_a_single_block() {
+        let mut storage = BlockStorage::new(BlockType::AIR);
+
+        // Setting the block that is already there doesn't need a palette.
+        storage.set(0, BlockType::AIR);
+        assert_eq!(storage, BlockStorage::Single(BlockType::AIR));
+
+        storage.set(10, BlockType::STONE);
+        assert_eq!(storage.get(10), BlockType::STONE);
+        assert_eq!(storage.get(11), BlockType::AIR);
+        assert!(storage.is_uniform() == false);
+
+        storage.set(10, BlockType::AIR);
+        storage.optimize();
+
+        assert_eq!(storage, BlockStorage::Single(BlockType::AIR));
+        assert_eq!(storage.heap_size(), 0);
+    }
+
+    #[test]
+    fn optimize_shrinks_palette() {
+        let mut storage = BlockStorage::new(BlockType::AIR);
+
+        for (i, block) in [BlockType::DEBUG, BlockType::GRASS, BlockType::DIRT, BlockType::STONE, BlockType::DIAMOND].into_iter().enumerate() {
+            storage.set(i, block);
+        }
+
+        assert_eq!(paletted(&storage).bits(), 3);
+
+        for i in 1..5 {
+            storage.set(i, BlockType::AIR);
+        }
+
+        let before: Vec<BlockType> = (0..CHUNK_VOLUME).map(|i| storage.get(i)).collect();
+
+        storage.optimize();
+
+        let p = paletted(&storage);
+
+        assert_eq!(p.palette(), &vec![BlockType::AIR, BlockType::DEBUG]);
+        assert_eq!(p.bits(), 1);
+
+        for i in 0..CHUNK_VOLUME {
+            assert_eq!(storage.get(i), before[i]);
+        }
+    }
+
+    #[test]
+    fn bytes_round_trip() {
+        let ids = BlockIdTable::from_registry();
+
+        let mut storage = BlockStorage::new(BlockType::STONE);
+
+        assert_eq!(BlockStorage::from_bytes(&storage.to_bytes(&ids), &ids), Some(storage.clone()));
+
+        for i in (0..CHUNK_VOLUME).step_by(3) {
+            storage.set(i, BlockType::DIRT);
+        }
+
+        storage.set(5, BlockType::DIAMOND);
+
+        assert_eq!(BlockStorage::from_bytes(&storage.to_bytes(&ids), &ids), Some(storage));
+    }
+
+    #[test]
+    fn bytes_reject_corrupt_data() {
+        let ids = BlockIdTable::from_registry();
+        let stone = BlockType::STONE.id();
+        let dirt = BlockType::DIRT.id();
+
+        // Sanity check, so the rest fail for the right reason.
+        assert!(BlockStorage::from_bytes(&paletted_bytes(2, &[stone, dirt, stone], 2), &ids).is_some());
+
+        // Bit widths.
+        let mut zero_bits = paletted_bytes(1, &[stone, dirt], 1);
+        zero_bits[1] = 0;
+        assert!(BlockStorage::from_bytes(&zero_bits, &ids).is_none());
+
+        let mut too_many_bits = paletted_bytes(1, &[stone, dirt], 1);
+        too_many_bits[1] = 17;
+        assert!(BlockStorage::from_bytes(&too_many_bits, &ids).is_none());
+
+        // Palette lengths.
+        assert!(BlockStorage::from_bytes(&paletted_bytes(2, &[], 0), &ids).is_none());
+        assert!(BlockStorage::from_bytes(&paletted_bytes(1, &[stone, dirt, stone], 0), &ids).is_none());
+
+        // Indices outside of the palette.
+        assert!(BlockStorage::from_bytes(&paletted_bytes(2, &[stone, dirt, stone], 3), &ids).is_none());
+
+        // Block IDs that aren't in the table.
+        assert!(BlockStorage::from_bytes(&paletted_bytes(1, &[stone, u16::MAX], 1), &ids).is_none());
+        assert!(BlockStorage::from_bytes(&[0, 255, 255], &ids).is_none());
+
+        // Missing and leftover bytes.
+        let bytes = paletted_bytes(1, &[stone, dirt], 1);
+        assert!(BlockStorage::from_bytes(&bytes[..bytes.len() - 1], &ids).is_none());
+
+        let mut extra = bytes.clone();
+        extra.push(0);
+        assert!(BlockStorage::from_bytes(&extra, &ids).is_none());
+
+        assert!(BlockStorage::from_bytes(&[7], &ids).is_none());
+        assert!(BlockStorage::from_bytes(&[], &ids).is_none());
+    }
+}
@@ -0,0 +1,314 @@
+#![allow(dead_code)]
+
+use bevy::prelude::*;
+use bevy::tasks::{AsyncComputeTaskPool, Task};
+use clap::Parser;
+use futures_lite::future;
+use hashbrown::HashMap;
+
+use crate::chunk::{Chunk, ChunkManager, ChunkPos};
+use crate::chunk_meshing::{self, ChunkMeshState, MeshTasks};
+use crate::cli;
+use crate::light;
+use crate::log;
+use crate::log::macro_deps::*;
+
+pub struct ChunkStreamingPlugin;
+
+impl Plugin for ChunkStreamingPlugin {
+    fn build(&self, app: &mut App) {
+        let args = cli::Cli::parse();
+
+        app.insert_resource(ChunkStreamingSettings {
+            view_distance: args.view_distance,
+            ..default()
+        });
+        app.init_resource::<ChunkStreamingState>();
+        app.init_resource::<ChunkEntities>();
+        app.init_resource::<ChunkTasks>();
+        app.add_systems(Update, (update_streaming_center, unload_far_chunks, poll_chunk_tasks, light_chunk_borders, load_queued_chunks).chain().in_set(ChunkStreamingSet));
+    }
+}
+
+/// Systems that load and unload chunks.
+#[derive(SystemSet, Debug, Clone, PartialEq, Eq, Hash)]
+pub struct ChunkStreamingSet;
+
+#[derive(Resource)]
+pub struct ChunkStreamingSettings {
+    /// Horizontal radius (in chunks) that is kept loaded around the camera.
+    pub view_distance: isize,
+    /// Vertical radius (in chunks) that is kept loaded around the camera.
+    pub vertical_view_distance: isize,
+    /// How far past the view distance a chunk has to be before it gets unloaded.
+    /// (Stops chunks on the border from loading and unloading over and over.)
+    pub unload_margin: isize,
+    /// Maximum amount of chunks being generated in the background at once.
+    pub max_chunk_tasks: usize,
+    /// Maximum amount of newly loaded chunks that spread their light to their neighbors every frame.
+    pub max_border_lights: usize,
+}
+
+impl Default for ChunkStreamingSettings {
+    fn default() -> Self {
+        Self {
+            view_distance: 4,
+            vertical_view_distance: 3,
+            unload_margin: 2,
+            max_chunk_tasks: bevy::tasks::available_parallelism() * 2,
+            max_border_lights: 4,
+        }
+    }
+}
+
+impl ChunkStreamingSettings {
+    /// Should a chunk be loaded when the camera is in the center chunk?
+    pub fn in_view(&self, center: ChunkPos, chunk_pos: ChunkPos) -> bool {
+        Self::in_radius(center, chunk_pos, self.view_distance, self.vertical_view_distance)
+    }
+
+    /// Should a chunk be unloaded when the camera is in the center chunk?
+    pub fn out_of_range(&self, center: ChunkPos, chunk_pos: ChunkPos) -> bool {
+        Self::in_radius(
+            center,
+            chunk_pos,
+            self.view_distance + self.unload_margin,
+            self.vertical_view_distance + self.unload_margin,
+        ) == false
+    }
+
+    // Cylinder around the center chunk.
+    fn in_radius(center: ChunkPos, chunk_pos: ChunkPos, horizontal: isize, vertical: isize) -> bool {
+        let (dx, dy, dz) = (chunk_pos.x - center.x, chunk_pos.y - center.y, chunk_pos.z - center.z);
+
+        dx * dx + dz * dz <= horizontal * horizontal && dy.abs() <= vertical
+    }
+}
+
+#[derive(Resource, Default)]
+pub struct ChunkStreamingState {
+    /// The chunk the camera was in last frame.
+    center: Option<ChunkPos>,
+    /// Chunks waiting to be loaded, furthest first. (So the nearest one can be popped off the end.)
+    queue: Vec<ChunkPos>,
+}
+
+/// Entities of every chunk that has been spawned.
+#[derive(Resource, Default)]
+pub struct ChunkEntities(pub HashMap<ChunkPos, Entity>);
+
+/// Chunks that are being loaded from disk or generated on the async compute task pool.
+#[derive(Resource, Default)]
+pub struct ChunkTasks(HashMap<ChunkPos, Task<ChunkTaskResult>>);
+
+impl ChunkTasks {
+    pub fn contains(&self, chunk_pos: ChunkPos) -> bool {
+        self.0.contains_key(&chunk_pos)
+    }
+
+    pub fn len(&self) -> usize {
+        self.0.len()
+    }
+}
+
+pub struct ChunkTaskResult {
+    /// Already lit by itself. (See light::light_chunk())
+    chunk: Chunk,
+    /// Does the chunk still need to be saved? (True for newly generated chunks.)
+    modified: bool,
+}
+
+#[derive(Component)]
+pub struct ChunkEntity(pub ChunkPos);
+
+// Track the chunk the camera is in, and queue up every chunk around it that isn't loaded yet.
+fn update_streaming_center(
+    cameras: Query<&GlobalTransform, With<Camera3d>>,
+    settings: Res<ChunkStreamingSettings>,
+    chunk_entities: Res<ChunkEntities>,
+    tasks: Res<ChunkTasks>,
+    mut state: ResMut<ChunkStreamingState>,
+) {
+    let camera = match cameras.get_single() {
+        Ok(o) => o,
+        Err(_) => return,
+    };
+
+    let center = ChunkPos::from_world(camera.translation());
+
+    if state.center == Some(center) && settings.is_changed() == false {
+        return;
+    }
+
+    state.center = Some(center);
+    state.queue.clear();
+
+    let (h, v) = (settings.view_distance, settings.vertical_view_distance);
+
+    for x in -h..=h {
+        for y in -v..=v {
+            for z in -h..=h {
+                let chunk_pos = ChunkPos::new(center.x + x, center.y + y, center.z + z);
+
+                // Chunks loaded by something else (like ChunkManager::set_block()) still need an entity.
+                if settings.in_view(center, chunk_pos) && chunk_entities.0.contains_key(&chunk_pos) == false && tasks.contains(chunk_pos) == false {
+                    state.queue.push(chunk_pos);
+                }
+            }
+        }
+    }
+
+    state.queue.sort_by_key(|p| {
+        let (dx, dy, dz) = (p.x - center.x, p.y - center.y, p.z - center.z);
+
+        std::cmp::Reverse(dx * dx + dy * dy + dz * dz)
+    });
+}
+
+// Despawn and unload every chunk that is too far away from the camera.
+fn unload_far_chunks(
+    mut cmds: Commands,
+    settings: Res<ChunkStreamingSettings>,
+    state: Res<ChunkStreamingState>,
+    mut chunk_manager: ResMut<ChunkManager>,
+    mut chunk_entities: ResMut<ChunkEntities>,
+    mut tasks: ResMut<ChunkTasks>,
+    mut mesh_state: ResMut<ChunkMeshState>,
+    mut mesh_tasks: ResMut<MeshTasks>,
+) {
+    let center = match state.center {
+        Some(s) => s,
+        None => return,
+    };
+
+    // Dropping a task cancels it.
+    tasks.0.retain(|p, _| settings.out_of_range(center, *p) == false);
+
+    // Chunks that can't be saved stay loaded, instead of failing to unload every frame.
+    let far: Vec<ChunkPos> = chunk_manager.loaded_chunks()
+        .copied()
+        .filter(|p| settings.out_of_range(center, *p) && chunk_manager.can_unload(*p))
+        .collect();
+
+    for chunk_pos in far {
+        if let Err(e) = chunk_manager.unload(chunk_pos) {
+            log::error!("Failed to unload chunk ({}, {}, {}): {}", chunk_pos.x, chunk_pos.y, chunk_pos.z, e);
+
+            continue;
+        }
+
+        if let Some(entity) = chunk_entities.0.remove(&chunk_pos) {
+            cmds.entity(entity).despawn_recursive();
+        }
+
+        mesh_state.chunk_unloaded(chunk_pos);
+        mesh_tasks.cancel(chunk_pos);
+    }
+}
+
+// Hand finished chunks over to the chunk manager, spawn their entities, and queue them to be meshed.
+fn poll_chunk_tasks(
+    mut cmds: Commands,
+    mut tasks: ResMut<ChunkTasks>,
+    mut chunk_manager: ResMut<ChunkManager>,
+    mut chunk_entities: ResMut<ChunkEntities>,
+    mut mesh_state: ResMut<ChunkMeshState>,
+) {
+    let finished: Vec<ChunkPos> = tasks.0.iter()
+        .filter(|(_, t)| t.is_finished())
+        .map(|(p, _)| *p)
+        .collect();
+
+    for chunk_pos in finished {
+        let task = tasks.0.remove(&chunk_pos).unwrap(); // Guarenteed value.
+
+        // The task is already finished, so this doesn't actually block.
+        let result = future::block_on(task);
+
+        // Something else loaded the chunk in the meantime (and might have changed it), so keep that one.
+        if chunk_manager.is_loaded(chunk_pos) == false {
+            chunk_manager.insert(result.chunk, result.modified);
+        }
+
+        if chunk_entities.0.contains_key(&chunk_pos) == false {
+            chunk_entities.0.insert(chunk_pos, spawn_chunk(&mut cmds, chunk_pos));
+            mesh_state.chunk_loaded(chunk_pos);
+        }
+    }
+}
+
+// Light spreading between chunks needs the chunk manager, so it has to happen here. Only a few chunks get to do it every frame.
+fn light_chunk_borders(settings: Res<ChunkStreamingSettings>, mut chunk_manager: ResMut<ChunkManager>) {
+    chunk_manager.light_queued_borders(settings.max_border_lights);
+}
+
+// Start background tasks for the nearest queued chunks.
+fn load_queued_chunks(
+    mut cmds: Commands,
+    settings: Res<ChunkStreamingSettings>,
+    mut state: ResMut<ChunkStreamingState>,
+    chunk_manager: Res<ChunkManager>,
+    mut chunk_entities: ResMut<ChunkEntities>,
+    mut tasks: ResMut<ChunkTasks>,
+    mut mesh_state: ResMut<ChunkMeshState>,
+) {
+    let pool = AsyncComputeTaskPool::get();
+
+    while tasks.len() < settings.max_chunk_tasks {
+        let chunk_pos = match state.queue.pop() {
+            Some(s) => s,
+            None => return,
+        };
+
+        if chunk_entities.0.contains_key(&chunk_pos) || tasks.contains(chunk_pos) {
+            continue;
+        }
+
+        // Already loaded by something else, so it only needs an entity.
+        if chunk_manager.is_loaded(chunk_pos) {
+            chunk_entities.0.insert(chunk_pos, spawn_chunk(&mut cmds, chunk_pos));
+            mesh_state.chunk_loaded(chunk_pos);
+
+            continue;
+        }
+
+        let source = chunk_manager.chunk_source(chunk_pos);
+
+        // Reading and decoding saved chunks is slow too, so it happens in the task as well.
+        let task = pool.spawn(async move {
+            let (mut chunk, modified) = match source.load() {
+                Ok(Some(chunk)) => (chunk, false),
+                // Generation is random, so new chunks have to be saved to stay the same.
+                Ok(None) => (ChunkManager::generate(chunk_pos), true),
+                Err(e) => {
+                    // Not marked as modified, so the broken chunk on disk doesn't get overwritten.
+                    log::error!("Failed to load chunk ({}, {}, {}), generating it instead: {}", chunk_pos.x, chunk_pos.y, chunk_pos.z, e);
+
+                    (ChunkManager::generate(chunk_pos), false)
+                },
+            };
+
+            // Most of the lighting only needs the chunk itself, so it gets done here too.
+            light::light_chunk(&mut chunk);
+
+            ChunkTaskResult { chunk, modified }
+        });
+
+        tasks.0.insert(chunk_pos, task);
+    }
+}
+
+/// Spawn the entity for a chunk. (Its render layers get their meshes once it has been meshed.)
+pub fn spawn_chunk(cmds: &mut Commands, chunk_pos: ChunkPos) -> Entity {
+    let entity = cmds.spawn((
+        SpatialBundle::from_transform(Transform::from_translation(chunk_pos.to_world())),
+        ChunkEntity(chunk_pos),
+        Name::new(format!("Chunk ({}, {}, {})", chunk_pos.x, chunk_pos.y, chunk_pos.z)),
+    )).id();
+
+    let layers = chunk_meshing::spawn_chunk_layers(cmds, entity);
+
+    cmds.entity(entity).insert(layers);
+
+    return entity;
+}
@@ -0,0 +1,180 @@
+#![allow(dead_code)]
+
+use std::collections::VecDeque;
+use bevy::prelude::*;
+use clap::Parser;
+use hashbrown::HashSet;
+
+use crate::block::BlockType;
+use crate::chunk::{BlockPos, Chunk, ChunkPos, CHUNK_SIZE};
+use crate::chunk_meshing::ChunkMeshingSet;
+use crate::chunk_streaming::{ChunkEntities, ChunkEntity};
+use crate::cli;
+use crate::world;
+
+pub struct ChunkVisibilityPlugin;
+
+impl Plugin for ChunkVisibilityPlugin {
+    fn build(&self, app: &mut App) {
+        let args = cli::Cli::parse();
+
+        app.insert_resource(ChunkVisibilitySettings {
+            enabled: args.no_cave_culling == false,
+        });
+        app.add_systems(Update, update_chunk_visibility.after(ChunkMeshingSet));
+    }
+}
+
+#[derive(Resource)]
+pub struct ChunkVisibilitySettings {
+    /// Hide chunks that can't be seen from the camera, like closed off caves.
+    pub enabled: bool,
+}
+
+/// Which sides of a chunk can see each other through the blocks inside of it.
+/// Chunks that haven't been meshed yet don't have one, and count as completely open.
+#[derive(Component, Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ChunkConnectivity {
+    // Bit j of sides[i] = side i can see side j. (Indexed by world::Direction::index())
+    sides: [u8; 6],
+}
+
+impl ChunkConnectivity {
+    /// Every side can see every other side. (Like an empty chunk.)
+    pub const OPEN: Self = Self { sides: [0b111111; 6] };
+
+    /// No side can see any other side. (Like a solid chunk.)
+    pub const CLOSED: Self = Self { sides: [0; 6] };
+
+    pub fn connected(&self, a: world::Direction, b: world::Direction) -> bool {
+        return self.sides[a.index()] & (1 << b.index()) != 0;
+    }
+
+    /// Flood fill through every group of see-through blocks, and connect all the sides that each group touches.
+    pub fn compute(chunk: &Chunk) -> Self {
+        let transparent: Vec<bool> = BlockType::all().map(|b| b.properties().transparent).collect();
+        let size = (CHUNK_SIZE.x as isize, CHUNK_SIZE.y as isize, CHUNK_SIZE.z as isize);
+        let index = |x: isize, y: isize, z: isize| ((x * size.1 + y) * size.2 + z) as usize;
+
+        let mut connectivity = Self::CLOSED;
+        let mut visited: Vec<bool> = vec![false; (size.0 * size.1 * size.2) as usize];
+        let mut queue: VecDeque<(isize, isize, isize)> = VecDeque::new();
+
+        let open = |x: isize, y: isize, z: isize| transparent[chunk.get_block(BlockPos::new_unchecked(x as u8, y as u8, z as u8)).id() as usize];
+
+        for x in 0..size.0 {
+            for y in 0..size.1 {
+                for z in 0..size.2 {
+                    if visited[index(x, y, z)] || open(x, y, z) == false {
+                        continue;
+                    }
+
+                    visited[index(x, y, z)] = true;
+                    queue.push_back((x, y, z));
+
+                    // Sides that this group of blocks touches.
+                    let mut touched: u8 = 0;
+
+                    while let Some(pos) = queue.pop_front() {
+                        for d in world::Direction::all() {
+                            let (nx, ny, nz) = d.offset_with_position(pos);
+
+                            if nx < 0 || ny < 0 || nz < 0 || nx >= size.0 || ny >= size.1 || nz >= size.2 {
+                                touched |= 1 << d.index();
+
+                                continue;
+                            }
+
+                            if visited[index(nx, ny, nz)] || open(nx, ny, nz) == false {
+                                continue;
+                            }
+
+                            visited[index(nx, ny, nz)] = true;
+                            queue.push_back((nx, ny, nz));
+                        }
+                    }
+
+                    for i in 0..6 {
+                        if touched & (1 << i) != 0 {
+                            connectivity.sides[i] |= touched;
+                        }
+                    }
+                }
+            }
+        }
+
+        return connectivity;
+    }
+}
+
+// Flood fill out from the chunk the camera is in, only going through sides of chunks that can see each other.
+// Chunks that never get reached are hidden. (Based on the "Advanced Cave Culling Algorithm" from Minecraft.)
+fn update_chunk_visibility(
+    settings: Res<ChunkVisibilitySettings>,
+    cameras: Query<&GlobalTransform, With<Camera3d>>,
+    chunk_entities: Res<ChunkEntities>,
+    connectivity: Query<&ChunkConnectivity>,
+    mut chunks: Query<(&ChunkEntity, &mut Visibility)>,
+) {
+    let camera = match cameras.get_single() {
+        Ok(o) => ChunkPos::from_world(o.translation()),
+        Err(_) => return,
+    };
+
+    if settings.enabled == false {
+        for (_, mut visibility) in chunks.iter_mut() {
+            if *visibility != Visibility::Inherited {
+                *visibility = Visibility::Inherited;
+            }
+        }
+
+        return;
+    }
+
+    let mut visited: HashSet<ChunkPos> = HashSet::new();
+
+    // Chunk, the side it was entered from (None for the camera's chunk), and every direction taken to get there.
+    let mut queue: VecDeque<(ChunkPos, Option<world::Direction>, u8)> = VecDeque::new();
+
+    visited.insert(camera);
+    queue.push_back((camera, None, 0));
+
+    while let Some((chunk_pos, entered_from, directions)) = queue.pop_front() {
+        let sides = match chunk_entities.0.get(&chunk_pos).and_then(|x| connectivity.get(*x).ok()) {
+            Some(s) => *s,
+            None => ChunkConnectivity::OPEN,
+        };
+
+        for d in world::Direction::all() {
+            // Never turn back towards the camera, otherwise everything would end up visible.
+            if directions & (1 << d.opposite().index()) != 0 {
+                continue;
+            }
+
+            if let Some(from) = entered_from {
+                if sides.connected(from, d) == false {
+                    continue;
+                }
+            }
+
+            let neighbor = chunk_pos.offset(d);
+
+            // Stops at the edge of the loaded chunks.
+            if visited.contains(&neighbor) || chunk_entities.0.contains_key(&neighbor) == false {
+                continue;
+            }
+
+            visited.insert(neighbor);
+            queue.push_back((neighbor, Some(d.opposite()), directions | (1 << d.index())));
+        }
+    }
+
+    for (chunk, mut visibility) in chunks.iter_mut() {
+        let new = if visited.contains(&chunk.0) { Visibility::Inherited } else { Visibility::Hidden };
+
+        // Only touch it when it changes, so change detection doesn't fire on every chunk every frame.
+        if *visibility != new {
+            *visibility = new;
+        }
+    }
+}
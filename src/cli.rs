@@ -1,5 +1,6 @@
 use clap::Parser;
 use crate::asset_manager::AssetCheckBuildBehavior;
+use crate::chunk::{MeshingMode, MissingNeighborPolicy};
 
 #[derive(Parser)]
 #[command(author, version, about, long_about = None)]
@@ -18,6 +19,10 @@ pub struct Cli {
     /// Quit before the actual game gets started up
     pub quit_before_game: bool,
 
+    #[clap(long)]
+    /// Run the benchmarks, then quit
+    pub benchmark: bool,
+
     #[clap(long)]
     /// Should assets be (re)built or not?
     pub build_assets: Option<AssetCheckBuildBehavior>,
@@ -26,6 +31,42 @@ pub struct Cli {
     /// Use the debug editor?
     pub editor: bool,
 
+    #[clap(long, default_value = "default")]
+    /// Name of the world to load (and save)
+    pub world: String,
+
+    #[clap(long, default_value_t = 4)]
+    /// How many chunks around the camera are kept loaded
+    pub view_distance: isize,
+
+    #[clap(long, value_enum, default_value_t = MissingNeighborPolicy::Solid)]
+    /// How chunk borders are meshed when the neighboring chunk isn't loaded
+    pub missing_neighbors: MissingNeighborPolicy,
+
+    #[clap(long, value_enum, default_value_t = MeshingMode::PerFace)]
+    /// How chunk faces are turned into quads (Greedy merges faces that look the same)
+    pub meshing: MeshingMode,
+
+    #[clap(long)]
+    /// Merge the vertices that neighboring faces share in chunk meshes (Smaller meshes, slower meshing)
+    pub weld_vertices: bool,
+
+    #[clap(long)]
+    /// Mesh every chunk at full detail, even far away ones
+    pub no_lod: bool,
+
+    #[clap(long)]
+    /// Draw every chunk in view, even ones that can't be seen from the camera (like closed off caves)
+    pub no_cave_culling: bool,
+
+    #[clap(long)]
+    /// Texture blocks with a texture array instead of the atlas (Only works if every block texture is the same size)
+    pub texture_array: bool,
+
+    #[clap(long)]
+    /// Start with the free-fly camera instead of the player (Toggle in game with F)
+    pub free_camera: bool,
+
     #[clap(short, long)]
     /// Set the backend for Wgpu
     pub wgpu_backend: Option<String>,
@@ -155,6 +155,22 @@ pub mod file {
         return Ok(contents);
     }
 
+    pub fn read_bytes(path: &Path) -> Result<Vec<u8>, io::Error> {
+        let mut file = match File::open(path.to_string()) {
+            Ok(o) => o,
+            Err(e) => return Err(e),
+        };
+
+        let mut contents: Vec<u8> = Vec::new();
+
+        match file.read_to_end(&mut contents) {
+            Ok(_) => (),
+            Err(e) => return Err(e),
+        };
+
+        return Ok(contents);
+    }
+
     pub fn write(contents: &str, path: &Path) -> Result<(), io::Error> {
         let mut file = match File::create(path.to_string()) {
             Ok(o) => o,
@@ -168,6 +184,20 @@ pub mod file {
 
         return Ok(());
     }
+
+    pub fn write_bytes(contents: &[u8], path: &Path) -> Result<(), io::Error> {
+        let mut file = match File::create(path.to_string()) {
+            Ok(o) => o,
+            Err(e) => return Err(e),
+        };
+
+        match file.write_all(contents) {
+            Ok(_) => (),
+            Err(e) => return Err(e),
+        };
+
+        return Ok(());
+    }
 }
 
 pub mod fs_action {
@@ -183,6 +213,14 @@ pub mod fs_action {
         return Ok(());
     }
 
+    /// Rename a file or directory. (Replaces the target if it is a file.)
+    pub fn rename(path_from: &Path, path_to: &Path) -> Result<(), io::Error> {
+        return match fs::rename(path_from.to_string(), path_to.to_string()) {
+            Ok(_) => Ok(()),
+            Err(e) => Err(e),
+        };
+    }
+
     /// Copy a file.
     pub fn copy(path_from: &Path, path_to: &Path) -> Result<(), io::Error> {
         let path_to_patched: Path;
@@ -0,0 +1,166 @@
+#![allow(dead_code)]
+
+use bevy::input::mouse::MouseMotion;
+use bevy::prelude::*;
+use bevy::window::PrimaryWindow;
+use clap::Parser;
+
+use crate::cli;
+use crate::player::cursor_grabbed;
+
+pub struct FreeCameraPlugin;
+
+impl Plugin for FreeCameraPlugin {
+    fn build(&self, app: &mut App) {
+        let args = cli::Cli::parse();
+
+        app.add_state::<CameraMode>();
+        app.init_resource::<FreeCameraSettings>();
+        app.init_resource::<FreeCamera>();
+
+        if args.free_camera {
+            app.add_systems(Startup, |mut next: ResMut<NextState<CameraMode>>| next.set(CameraMode::FreeFly));
+        }
+
+        app.add_systems(Update, toggle_camera_mode);
+        app.add_systems(OnEnter(CameraMode::FreeFly), start_flying);
+        app.add_systems(Update, fly.run_if(in_state(CameraMode::FreeFly)));
+    }
+}
+
+/// Who is controlling the camera.
+#[derive(States, Default, Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum CameraMode {
+    /// The camera sits in the player's head.
+    #[default]
+    Player,
+    /// The camera flies around on its own, and the player stays where it is.
+    FreeFly,
+}
+
+#[derive(Resource)]
+pub struct FreeCameraSettings {
+    /// Switches between the player and the free-fly camera.
+    pub toggle: KeyCode,
+    pub forward: KeyCode,
+    pub back: KeyCode,
+    pub left: KeyCode,
+    pub right: KeyCode,
+    pub up: KeyCode,
+    pub down: KeyCode,
+    /// Hold to fly faster.
+    pub fast: KeyCode,
+    /// Hold to fly slower.
+    pub slow: KeyCode,
+    /// Blocks per second.
+    pub speed: f32,
+    pub fast_multiplier: f32,
+    pub slow_multiplier: f32,
+    /// Radians turned per pixel of mouse movement.
+    pub mouse_sensitivity: f32,
+}
+
+impl Default for FreeCameraSettings {
+    fn default() -> Self {
+        Self {
+            toggle: KeyCode::F,
+            forward: KeyCode::W,
+            back: KeyCode::S,
+            left: KeyCode::A,
+            right: KeyCode::D,
+            up: KeyCode::Space,
+            down: KeyCode::ControlLeft,
+            fast: KeyCode::ShiftLeft,
+            slow: KeyCode::AltLeft,
+            speed: 10.0,
+            fast_multiplier: 4.0,
+            slow_multiplier: 0.25,
+            mouse_sensitivity: 0.002,
+        }
+    }
+}
+
+/// Where the free-fly camera is looking.
+#[derive(Resource, Default)]
+pub struct FreeCamera {
+    /// Rotation around the Y axis, in radians.
+    pub yaw: f32,
+    /// Rotation up and down, in radians.
+    pub pitch: f32,
+}
+
+fn toggle_camera_mode(
+    keys: Res<Input<KeyCode>>,
+    settings: Res<FreeCameraSettings>,
+    mode: Res<State<CameraMode>>,
+    mut next: ResMut<NextState<CameraMode>>,
+) {
+    if keys.just_pressed(settings.toggle) == false {
+        return;
+    }
+
+    next.set(match mode.get() {
+        CameraMode::Player => CameraMode::FreeFly,
+        CameraMode::FreeFly => CameraMode::Player,
+    });
+}
+
+// Keep looking the same way the camera was, instead of snapping to wherever the free camera was last time.
+fn start_flying(cameras: Query<&Transform, With<Camera3d>>, mut free_camera: ResMut<FreeCamera>) {
+    let camera = match cameras.get_single() {
+        Ok(o) => o,
+        Err(_) => return,
+    };
+
+    let (yaw, pitch, _) = camera.rotation.to_euler(EulerRot::YXZ);
+
+    free_camera.yaw = yaw;
+    free_camera.pitch = pitch;
+}
+
+fn fly(
+    time: Res<Time>,
+    keys: Res<Input<KeyCode>>,
+    windows: Query<&Window, With<PrimaryWindow>>,
+    settings: Res<FreeCameraSettings>,
+    mut motion: EventReader<MouseMotion>,
+    mut free_camera: ResMut<FreeCamera>,
+    mut cameras: Query<&mut Transform, With<Camera3d>>,
+) {
+    let grabbed = match windows.get_single() {
+        Ok(o) => cursor_grabbed(o),
+        Err(_) => false,
+    };
+
+    let delta: Vec2 = motion.iter().map(|m| m.delta).sum();
+
+    if grabbed {
+        free_camera.yaw -= delta.x * settings.mouse_sensitivity;
+        free_camera.pitch = (free_camera.pitch - delta.y * settings.mouse_sensitivity).clamp(-1.54, 1.54);
+    }
+
+    let mut wish = Vec3::ZERO;
+
+    if keys.pressed(settings.forward) { wish.z -= 1.0; }
+    if keys.pressed(settings.back) { wish.z += 1.0; }
+    if keys.pressed(settings.left) { wish.x -= 1.0; }
+    if keys.pressed(settings.right) { wish.x += 1.0; }
+
+    let rotation = Quat::from_euler(EulerRot::YXZ, free_camera.yaw, free_camera.pitch, 0.0);
+
+    // Forwards follows where the camera looks, up and down are always straight up and down.
+    let mut direction = rotation * wish;
+
+    if keys.pressed(settings.up) { direction.y += 1.0; }
+    if keys.pressed(settings.down) { direction.y -= 1.0; }
+
+    let mut speed = settings.speed;
+
+    if keys.pressed(settings.fast) { speed *= settings.fast_multiplier; }
+    if keys.pressed(settings.slow) { speed *= settings.slow_multiplier; }
+
+    for mut camera in cameras.iter_mut() {
+        camera.translation += direction.normalize_or_zero() * speed * time.delta_seconds();
+        camera.rotation = rotation;
+    }
+}
@@ -0,0 +1,206 @@
+#![allow(dead_code)]
+
+use bevy::prelude::*;
+use bevy::window::PrimaryWindow;
+
+use crate::block::{BlockCollisionType, BlockType};
+use crate::chunk::ChunkManager;
+use crate::player::{cursor_grabbed, Aabb, Player, PlayerSettings};
+use crate::raycast::{self, RaycastHit, RaycastOptions};
+
+pub struct BlockInteractionPlugin;
+
+impl Plugin for BlockInteractionPlugin {
+    fn build(&self, app: &mut App) {
+        app.init_resource::<SelectedBlock>();
+        app.init_resource::<TargetedBlock>();
+        app.add_systems(Startup, spawn_crosshair);
+        app.add_systems(Update, (
+            select_block,
+            update_targeted_block,
+            break_and_place_blocks,
+            highlight_targeted_block,
+            update_selected_block_label,
+        ).chain());
+    }
+}
+
+/// How far away blocks can be broken and placed.
+const REACH: f32 = 8.0;
+
+// Keys used to pick a block from placeable_blocks().
+const HOTBAR_KEYS: [KeyCode; 9] = [
+    KeyCode::Key1,
+    KeyCode::Key2,
+    KeyCode::Key3,
+    KeyCode::Key4,
+    KeyCode::Key5,
+    KeyCode::Key6,
+    KeyCode::Key7,
+    KeyCode::Key8,
+    KeyCode::Key9,
+];
+
+/// The block that gets placed with the right mouse button.
+#[derive(Resource)]
+pub struct SelectedBlock(pub BlockType);
+
+impl Default for SelectedBlock {
+    fn default() -> Self {
+        Self(BlockType::STONE)
+    }
+}
+
+/// The block under the crosshair. (Updated every frame.)
+#[derive(Resource, Default)]
+pub struct TargetedBlock(pub Option<RaycastHit>);
+
+#[derive(Component)]
+struct SelectedBlockLabel;
+
+/// Every block that can be picked with the number keys, in order.
+pub fn placeable_blocks() -> Vec<BlockType> {
+    BlockType::all().filter(|b| *b != BlockType::AIR).collect()
+}
+
+fn spawn_crosshair(mut cmds: Commands) {
+    let bar = |width: f32, height: f32| NodeBundle {
+        style: Style {
+            position_type: PositionType::Absolute,
+            left: Val::Percent(50.0),
+            top: Val::Percent(50.0),
+            width: Val::Px(width),
+            height: Val::Px(height),
+            margin: UiRect {
+                left: Val::Px(-width / 2.0),
+                top: Val::Px(-height / 2.0),
+                ..default()
+            },
+            ..default()
+        },
+        background_color: Color::rgba(1.0, 1.0, 1.0, 0.8).into(),
+        ..default()
+    };
+
+    cmds.spawn((bar(16.0, 2.0), Name::new("Crosshair (Horizontal)")));
+    cmds.spawn((bar(2.0, 16.0), Name::new("Crosshair (Vertical)")));
+
+    cmds.spawn((
+        TextBundle::from_section("", TextStyle {
+            font_size: 20.0,
+            color: Color::WHITE,
+            ..default()
+        }).with_style(Style {
+            position_type: PositionType::Absolute,
+            left: Val::Px(10.0),
+            bottom: Val::Px(10.0),
+            ..default()
+        }),
+        SelectedBlockLabel,
+        Name::new("Selected Block"),
+    ));
+}
+
+fn select_block(keys: Res<Input<KeyCode>>, mut selected: ResMut<SelectedBlock>) {
+    let blocks = placeable_blocks();
+
+    for (i, key) in HOTBAR_KEYS.iter().enumerate() {
+        if keys.just_pressed(*key) && i < blocks.len() {
+            selected.0 = blocks[i];
+        }
+    }
+}
+
+fn update_targeted_block(
+    cameras: Query<&GlobalTransform, With<Camera3d>>,
+    chunk_manager: Res<ChunkManager>,
+    mut targeted: ResMut<TargetedBlock>,
+) {
+    let camera = match cameras.get_single() {
+        Ok(o) => o,
+        Err(_) => return,
+    };
+
+    targeted.0 = raycast::raycast(&chunk_manager, camera.translation(), camera.forward(), RaycastOptions {
+        max_distance: REACH,
+        ..default()
+    });
+}
+
+// Left click breaks the targeted block, right click places the selected block against the targeted face.
+// Clicks only count while the cursor is grabbed, and the click that grabs it doesn't count either.
+fn break_and_place_blocks(
+    windows: Query<&Window, With<PrimaryWindow>>,
+    mouse: Res<Input<MouseButton>>,
+    selected: Res<SelectedBlock>,
+    targeted: Res<TargetedBlock>,
+    player_settings: Res<PlayerSettings>,
+    players: Query<&Transform, With<Player>>,
+    mut chunk_manager: ResMut<ChunkManager>,
+    mut was_grabbed: Local<bool>,
+) {
+    let grabbed = match windows.get_single() {
+        Ok(o) => cursor_grabbed(o),
+        Err(_) => false,
+    };
+
+    // The cursor might have been grabbed earlier this frame, by the same click.
+    let grabbed_before = *was_grabbed;
+    *was_grabbed = grabbed;
+
+    if grabbed == false || grabbed_before == false {
+        return;
+    }
+
+    let hit = match targeted.0 {
+        Some(s) => s,
+        None => return,
+    };
+
+    if mouse.just_pressed(MouseButton::Left) {
+        chunk_manager.set_block(hit.pos, BlockType::AIR);
+    }
+
+    else if mouse.just_pressed(MouseButton::Right) {
+        let target = match hit.adjacent() {
+            Some(s) => s,
+            None => return, // Inside of the block, so there is no face to place against.
+        };
+
+        // Only replace blocks that aren't solid. (Air, gasses, and liquids.)
+        let replaceable = match chunk_manager.get_block(target) {
+            Some(s) => s.properties().collision != BlockCollisionType::Solid,
+            None => false,
+        };
+
+        // Don't trap the player inside of the new block.
+        let inside_player = players.iter()
+            .any(|t| Aabb::from_feet(t.translation, player_settings.size).intersects_block(target));
+
+        if replaceable && inside_player == false {
+            chunk_manager.set_block(target, selected.0);
+        }
+    }
+}
+
+fn highlight_targeted_block(targeted: Res<TargetedBlock>, mut gizmos: Gizmos) {
+    if let Some(hit) = targeted.0 {
+        gizmos.cuboid(
+            Transform::from_translation(hit.pos.to_world()).with_scale(Vec3::splat(1.01)),
+            Color::BLACK,
+        );
+    }
+}
+
+fn update_selected_block_label(
+    selected: Res<SelectedBlock>,
+    mut labels: Query<&mut Text, With<SelectedBlockLabel>>,
+) {
+    if selected.is_changed() == false {
+        return;
+    }
+
+    for mut text in labels.iter_mut() {
+        text.sections[0].value = format!("Selected: {}", selected.0.properties().name);
+    }
+}
@@ -0,0 +1,423 @@
+#![allow(dead_code)]
+
+use std::collections::VecDeque;
+
+use crate::block::BlockType;
+use crate::chunk::{BlockPos, Chunk, ChunkManager, ChunkPos, GlobalBlockPos, CHUNK_SIZE};
+use crate::chunk_storage::CHUNK_VOLUME;
+use crate::world;
+
+/// Brightest light level. (Open sky, and the brightest light sources.)
+pub const MAX_LIGHT: u8 = 15;
+
+/// How bright a light level of 0 still is, so caves aren't pitch black.
+const MIN_BRIGHTNESS: f32 = 0.04;
+
+/// Tint of light coming from blocks, as opposed to the sky.
+const BLOCK_LIGHT_TINT: [f32; 3] = [1.0, 0.85, 0.65];
+
+// Same order as world::Direction::all(). (Not using that here, because it allocates.)
+const NEIGHBORS: [(isize, isize, isize); 6] = [
+    (0, 0, -1),
+    (0, 0, 1),
+    (1, 0, 0),
+    (-1, 0, 0),
+    (0, 1, 0),
+    (0, -1, 0),
+];
+
+const UP: usize = 4;
+const DOWN: usize = 5;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LightKind {
+    /// Light from the sky, which goes straight down without getting dimmer.
+    Sky,
+    /// Light from emissive blocks.
+    Block,
+}
+
+/// Light levels of every block in a chunk. (Not saved, it gets recalculated whenever the chunk is loaded.)
+#[derive(Debug, Clone)]
+pub struct ChunkLight {
+    // Sky light in the high 4 bits, block light in the low 4 bits.
+    levels: Vec<u8>,
+}
+
+impl Default for ChunkLight {
+    fn default() -> Self {
+        Self {
+            levels: vec![0; CHUNK_VOLUME],
+        }
+    }
+}
+
+impl ChunkLight {
+    pub fn get(&self, index: usize, kind: LightKind) -> u8 {
+        return match kind {
+            LightKind::Sky => self.levels[index] >> 4,
+            LightKind::Block => self.levels[index] & 0x0F,
+        };
+    }
+
+    pub fn set(&mut self, index: usize, kind: LightKind, level: u8) {
+        let level = level.min(MAX_LIGHT);
+        let old = self.levels[index];
+
+        self.levels[index] = match kind {
+            LightKind::Sky => (old & 0x0F) | (level << 4),
+            LightKind::Block => (old & 0xF0) | level,
+        };
+    }
+}
+
+// BlockType::properties() goes through the block registry every time, which adds up in flood fills.
+struct LightTable {
+    transparent: Vec<bool>,
+    emission: Vec<u8>,
+}
+
+impl LightTable {
+    fn new() -> Self {
+        let mut table = Self {
+            transparent: Vec::new(),
+            emission: Vec::new(),
+        };
+
+        for block in BlockType::all() {
+            let properties = block.properties();
+            let id = block.id() as usize;
+
+            if table.transparent.len() <= id {
+                table.transparent.resize(id + 1, false);
+                table.emission.resize(id + 1, 0);
+            }
+
+            table.transparent[id] = properties.transparent;
+            table.emission[id] = properties.emission.min(MAX_LIGHT);
+        }
+
+        return table;
+    }
+
+    fn transparent(&self, block: BlockType) -> bool {
+        self.transparent[block.id() as usize]
+    }
+
+    fn emission(&self, block: BlockType) -> u8 {
+        self.emission[block.id() as usize]
+    }
+}
+
+fn neighbor(pos: GlobalBlockPos, i: usize) -> GlobalBlockPos {
+    let (dx, dy, dz) = NEIGHBORS[i];
+
+    GlobalBlockPos::new(pos.x + dx, pos.y + dy, pos.z + dz)
+}
+
+// How much light a block makes by itself.
+// Sky light comes in from above the highest loaded chunk, since there is no real top of the world.
+fn source_level(chunk_manager: &ChunkManager, table: &LightTable, pos: GlobalBlockPos, kind: LightKind) -> u8 {
+    let block = match chunk_manager.get_block(pos) {
+        Some(s) => s,
+        None => return 0,
+    };
+
+    return match kind {
+        LightKind::Block => table.emission(block),
+        LightKind::Sky => {
+            let open_above = chunk_manager.get_block(neighbor(pos, UP)).is_none();
+
+            if open_above && table.transparent(block) { MAX_LIGHT } else { 0 }
+        },
+    };
+}
+
+// Spread light outwards from every queued block, until it runs out.
+fn propagate(chunk_manager: &mut ChunkManager, table: &LightTable, mut queue: VecDeque<GlobalBlockPos>, kind: LightKind) {
+    while let Some(pos) = queue.pop_front() {
+        let level = chunk_manager.get_light(pos, kind).unwrap_or(0);
+
+        if level == 0 {
+            continue;
+        }
+
+        for i in 0..6 {
+            let n = neighbor(pos, i);
+
+            let block = match chunk_manager.get_block(n) {
+                Some(s) => s,
+                None => continue,
+            };
+
+            if table.transparent(block) == false {
+                continue;
+            }
+
+            // Full sky light goes straight down forever.
+            let new_level = if kind == LightKind::Sky && i == DOWN && level == MAX_LIGHT {
+                MAX_LIGHT
+            } else {
+                level - 1
+            };
+
+            if new_level > chunk_manager.get_light(n, kind).unwrap_or(MAX_LIGHT) {
+                chunk_manager.set_light(n, kind, new_level);
+                queue.push_back(n);
+            }
+        }
+    }
+}
+
+// Take away the light of some blocks, and all the light that came from them.
+// Returns the blocks that light has to be spread from again, to fill the hole back up.
+fn remove(chunk_manager: &mut ChunkManager, table: &LightTable, seeds: &[GlobalBlockPos], kind: LightKind) -> VecDeque<GlobalBlockPos> {
+    let mut queue: VecDeque<(GlobalBlockPos, u8)> = VecDeque::new();
+    let mut relight: VecDeque<GlobalBlockPos> = VecDeque::new();
+    let mut cleared: Vec<GlobalBlockPos> = Vec::new();
+
+    for pos in seeds.iter() {
+        let level = chunk_manager.get_light(*pos, kind).unwrap_or(0);
+
+        if level > 0 {
+            chunk_manager.set_light(*pos, kind, 0);
+            queue.push_back((*pos, level));
+            cleared.push(*pos);
+        }
+    }
+
+    while let Some((pos, level)) = queue.pop_front() {
+        for i in 0..6 {
+            let n = neighbor(pos, i);
+
+            let n_level = match chunk_manager.get_light(n, kind) {
+                Some(s) => s,
+                None => continue,
+            };
+
+            if n_level == 0 {
+                continue;
+            }
+
+            let straight_down = kind == LightKind::Sky && i == DOWN && level == MAX_LIGHT && n_level == MAX_LIGHT;
+
+            // Dimmer neighbors (and sky light below) got their light from here, brighter ones have another source.
+            if n_level < level || straight_down {
+                chunk_manager.set_light(n, kind, 0);
+                queue.push_back((n, n_level));
+                cleared.push(n);
+            } else {
+                relight.push_back(n);
+            }
+        }
+    }
+
+    // Light sources that got cleared make their own light again.
+    for pos in cleared {
+        let source = source_level(chunk_manager, table, pos, kind);
+
+        if source > 0 {
+            chunk_manager.set_light(pos, kind, source);
+            relight.push_back(pos);
+        }
+    }
+
+    return relight;
+}
+
+/// Light a chunk by itself, as if no other chunk was loaded. (Doesn't need the chunk manager, so it can run on any thread.)
+/// The top of the chunk counts as open sky, and light stops at its borders. light_borders() fixes both once the chunk is loaded.
+pub fn light_chunk(chunk: &mut Chunk) {
+    let table = LightTable::new();
+
+    let mut sky_queue: VecDeque<BlockPos> = VecDeque::new();
+    let mut block_queue: VecDeque<BlockPos> = VecDeque::new();
+
+    for x in 0..CHUNK_SIZE.x {
+        for y in 0..CHUNK_SIZE.y {
+            for z in 0..CHUNK_SIZE.z {
+                let pos = BlockPos::new_unchecked(x, y, z);
+                let block = chunk.get_block(pos);
+
+                // Full sky light goes down from here. (See propagate_in_chunk())
+                if y == CHUNK_SIZE.y - 1 && table.transparent(block) {
+                    chunk.set_light(pos, LightKind::Sky, MAX_LIGHT);
+                    sky_queue.push_back(pos);
+                }
+
+                if table.emission(block) > 0 {
+                    chunk.set_light(pos, LightKind::Block, table.emission(block));
+                    block_queue.push_back(pos);
+                }
+            }
+        }
+    }
+
+    propagate_in_chunk(chunk, &table, sky_queue, LightKind::Sky);
+    propagate_in_chunk(chunk, &table, block_queue, LightKind::Block);
+}
+
+// Same as propagate(), but light that would leave the chunk is dropped.
+fn propagate_in_chunk(chunk: &mut Chunk, table: &LightTable, mut queue: VecDeque<BlockPos>, kind: LightKind) {
+    let size = (CHUNK_SIZE.x as isize, CHUNK_SIZE.y as isize, CHUNK_SIZE.z as isize);
+
+    while let Some(pos) = queue.pop_front() {
+        let level = chunk.get_light(pos, kind);
+
+        if level == 0 {
+            continue;
+        }
+
+        for (i, (dx, dy, dz)) in NEIGHBORS.iter().enumerate() {
+            let (x, y, z) = (pos.x as isize + dx, pos.y as isize + dy, pos.z as isize + dz);
+
+            if x < 0 || y < 0 || z < 0 || x >= size.0 || y >= size.1 || z >= size.2 {
+                continue;
+            }
+
+            let n = BlockPos::new_unchecked(x as u8, y as u8, z as u8);
+
+            if table.transparent(chunk.get_block(n)) == false {
+                continue;
+            }
+
+            // Full sky light goes straight down forever.
+            let new_level = if kind == LightKind::Sky && i == DOWN && level == MAX_LIGHT {
+                MAX_LIGHT
+            } else {
+                level - 1
+            };
+
+            if new_level > chunk.get_light(n, kind) {
+                chunk.set_light(n, kind, new_level);
+                queue.push_back(n);
+            }
+        }
+    }
+}
+
+/// Spread light between a chunk that was lit by light_chunk() and its loaded neighbors, once it is loaded.
+pub fn light_borders(chunk_manager: &mut ChunkManager, chunk_pos: ChunkPos) {
+    let table = LightTable::new();
+
+    let mut sky_queue: VecDeque<GlobalBlockPos> = VecDeque::new();
+    let mut block_queue: VecDeque<GlobalBlockPos> = VecDeque::new();
+
+    // The chunk thought it was open to the sky, which isn't true if there is a chunk above it.
+    // (Whatever sky light that chunk lets through comes back in from its border below.)
+    if chunk_manager.is_loaded(chunk_pos.offset(world::Direction::Up)) {
+        let top = border_layer(chunk_pos, world::Direction::Up);
+
+        sky_queue.extend(remove(chunk_manager, &table, &top, LightKind::Sky));
+    }
+
+    // The chunk below thought the same.
+    let below = chunk_pos.offset(world::Direction::Down);
+
+    if chunk_manager.is_loaded(below) {
+        let top = border_layer(below, world::Direction::Up);
+
+        sky_queue.extend(remove(chunk_manager, &table, &top, LightKind::Sky));
+    }
+
+    // Light on both sides of every border shines across it.
+    for d in world::Direction::all() {
+        let neighbor_pos = chunk_pos.offset(d);
+
+        if chunk_manager.is_loaded(neighbor_pos) == false {
+            continue;
+        }
+
+        let mut layers = border_layer(neighbor_pos, d.opposite());
+
+        layers.extend(border_layer(chunk_pos, d));
+
+        sky_queue.extend(layers.iter().copied());
+        block_queue.extend(layers);
+    }
+
+    propagate(chunk_manager, &table, sky_queue, LightKind::Sky);
+    propagate(chunk_manager, &table, block_queue, LightKind::Block);
+}
+
+/// Fix the light around blocks that were just changed. (Works across chunk borders.)
+pub fn update_blocks(chunk_manager: &mut ChunkManager, positions: &[GlobalBlockPos]) {
+    let table = LightTable::new();
+
+    for kind in [LightKind::Sky, LightKind::Block] {
+        let mut queue = remove(chunk_manager, &table, positions, kind);
+
+        for pos in positions.iter() {
+            // The block might be a light source now.
+            let source = source_level(chunk_manager, &table, *pos, kind);
+
+            if source > chunk_manager.get_light(*pos, kind).unwrap_or(MAX_LIGHT) {
+                chunk_manager.set_light(*pos, kind, source);
+            }
+
+            // Or it might let its neighbors' light through now.
+            queue.push_back(*pos);
+
+            for i in 0..6 {
+                queue.push_back(neighbor(*pos, i));
+            }
+        }
+
+        propagate(chunk_manager, &table, queue, kind);
+    }
+}
+
+// Every block on one side of a chunk.
+fn border_layer(chunk_pos: ChunkPos, side: world::Direction) -> Vec<GlobalBlockPos> {
+    let size = (CHUNK_SIZE.x as isize, CHUNK_SIZE.y as isize, CHUNK_SIZE.z as isize);
+
+    let (x_range, y_range, z_range) = match side {
+        world::Direction::North => (0..size.0, 0..size.1, 0..1),
+        world::Direction::South => (0..size.0, 0..size.1, size.2 - 1..size.2),
+        world::Direction::East => (size.0 - 1..size.0, 0..size.1, 0..size.2),
+        world::Direction::West => (0..1, 0..size.1, 0..size.2),
+        world::Direction::Up => (0..size.0, size.1 - 1..size.1, 0..size.2),
+        world::Direction::Down => (0..size.0, 0..1, 0..size.2),
+    };
+
+    let mut layer: Vec<GlobalBlockPos> = Vec::new();
+
+    for x in x_range {
+        for y in y_range.clone() {
+            for z in z_range.clone() {
+                layer.push(GlobalBlockPos::from_chunk(chunk_pos, BlockPos::new_unchecked(x as u8, y as u8, z as u8)));
+            }
+        }
+    }
+
+    return layer;
+}
+
+/// Vertex color of a face, from the light levels of the block in front of it.
+/// Faces are also shaded by the way they are facing, so blocks don't look flat.
+pub fn face_color(sky: u8, block: u8, direction: world::Direction) -> [f32; 4] {
+    let shade = match direction {
+        world::Direction::Up => 1.0,
+        world::Direction::Down => 0.5,
+        world::Direction::North | world::Direction::South => 0.8,
+        world::Direction::East | world::Direction::West => 0.6,
+    };
+
+    let sky = brightness(sky);
+    let block = brightness(block);
+
+    let mut color = [1.0; 4];
+
+    for i in 0..3 {
+        color[i] = sky.max(block * BLOCK_LIGHT_TINT[i]) * shade;
+    }
+
+    return color;
+}
+
+// Every light level is a bit dimmer than the one above it.
+fn brightness(level: u8) -> f32 {
+    let level = level.min(MAX_LIGHT);
+
+    MIN_BRIGHTNESS + (1.0 - MIN_BRIGHTNESS) * 0.8_f32.powi((MAX_LIGHT - level) as i32)
+}
@@ -1,11 +1,15 @@
 mod block;
 mod voxel;
+mod block_atlas;
+mod block_animation;
+mod voxel_material;
 mod world;
 mod mesher;
 mod random;
 mod noise;
 mod filesystem;
 mod asset_manager;
+mod atlas_packing;
 mod dir;
 mod places;
 mod log;
@@ -15,6 +19,17 @@ mod hash_boilerplate;
 mod editor_mode;
 mod world_generation;
 mod chunk;
+mod chunk_storage;
+mod region;
+mod chunk_streaming;
+mod chunk_meshing;
+mod chunk_visibility;
+mod light;
+mod raycast;
+mod interaction;
+mod player;
+mod free_camera;
+mod bench;
 
 use std::env;
 use bevy::prelude::*;
@@ -25,7 +40,16 @@ use bevy::log::LogPlugin;
 use clap::Parser;
 
 use editor_mode::EditorModePlugin;
-use chunk::{ChunkManagerPlugin, CHUNK_SIZE};
+use chunk::{ChunkManager, ChunkManagerPlugin};
+use chunk_meshing::ChunkMeshingPlugin;
+use chunk_visibility::ChunkVisibilityPlugin;
+use chunk_streaming::ChunkStreamingPlugin;
+use voxel_material::VoxelMaterialPlugin;
+use block_atlas::BlockAtlasPlugin;
+use block_animation::BlockAnimationPlugin;
+use interaction::BlockInteractionPlugin;
+use player::PlayerPlugin;
+use free_camera::FreeCameraPlugin;
 
 macro_rules! run_exit_code_function {
     (
@@ -60,17 +84,30 @@ fn app() -> ExitCode {
         None => (),
     };
 
+    if args.benchmark {
+        bench::run();
+
+        return ExitCode::Success;
+    }
+
     env::set_var("BEVY_ASSET_ROOT", places::cache().to_string());
 
     run_exit_code_function!(places::delete_temp_dirs());
     run_exit_code_function!(places::create_all_dirs());
     run_exit_code_function!(asset_manager::refresh_asset_packs_checksum());
     run_exit_code_function!(asset_manager::build_assets_if_needed());
+    run_exit_code_function!(block::load_registry());
 
     if args.quit_before_game {
         return ExitCode::Success;
     }
 
+    // Needs the block registry, to read the world's block IDs.
+    let chunk_manager = match ChunkManager::open(&args.world) {
+        Ok(o) => o,
+        Err(_) => return ExitCode::Fail,
+    };
+
     App::new()
         .add_plugins((DefaultPlugins.set(
             WindowPlugin {
@@ -94,66 +131,16 @@ fn app() -> ExitCode {
             }
         ).set(
             ImagePlugin::default_nearest()
-        ), EditorModePlugin, ChunkManagerPlugin))
+        ), EditorModePlugin, VoxelMaterialPlugin, BlockAtlasPlugin, ChunkManagerPlugin, ChunkStreamingPlugin, ChunkMeshingPlugin, ChunkVisibilityPlugin, BlockAnimationPlugin, BlockInteractionPlugin, PlayerPlugin, FreeCameraPlugin))
+        .insert_resource(chunk_manager)
         .insert_resource(ClearColor(Color::rgb(0.3, 0.3, 0.3)))
         .insert_resource(Msaa::Off)
         .add_systems(Startup, spawn_camera)
-        .add_systems(Startup, spawn_random_shit)
         .run();
 
     return ExitCode::Success;
 }
 
-fn spawn_random_shit(
-    mut cmds: Commands,
-    mut materials: ResMut<Assets<StandardMaterial>>,
-    mut meshes: ResMut<Assets<Mesh>>,
-    asset_server: ResMut<AssetServer>,
-) {
-    cmds.spawn(
-        PointLightBundle {
-            point_light: PointLight {
-                intensity: 99000.0,
-                shadows_enabled: true,
-                range: 5000.0,
-                ..default()
-            },
-            transform: Transform::from_xyz(-25.0, 39.0, 40.0),
-            ..default()
-        }
-    );
-
-    cmds.spawn(
-        PbrBundle {
-            mesh: meshes.add(Mesh::from(shape::Cube { size: 1.0 })),
-            transform: Transform::from_xyz(-1.0, -1.0, -1.0),
-            material: materials.add(StandardMaterial {
-                // base_color: Color::rgb(0.05, 0.5, 0.35),
-                base_color_texture: Some(asset_server.load("textures/block/diamond.png")),
-                // double_sided: true, // debug
-                // cull_mode: None, // debug
-                ..default()
-            }),
-            ..default()
-        }
-    );
-
-    cmds.spawn(
-        PbrBundle {
-            mesh: meshes.add(Mesh::from(shape::Cube { size: 1.0 })),
-            transform: Transform::from_xyz(CHUNK_SIZE.x as f32, CHUNK_SIZE.y as f32, CHUNK_SIZE.z as f32),
-            material: materials.add(StandardMaterial {
-                // base_color: Color::rgb(0.05, 0.5, 0.35),
-                base_color_texture: Some(asset_server.load("textures/block/oak_log_end.png")),
-                // double_sided: true, // debug
-                // cull_mode: None, // debug
-                ..default()
-            }),
-            ..default()
-        }
-    );
-}
-
 fn spawn_camera(mut cmds: Commands) {
     cmds.spawn(
         Camera3dBundle {
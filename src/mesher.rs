@@ -2,45 +2,54 @@
 
 use bevy::prelude::*;
 use bevy::render::mesh::Indices;
+use bevy::render::mesh::MeshVertexAttribute;
 use bevy::render::mesh::PrimitiveTopology;
+use bevy::render::mesh::VertexAttributeValues;
+use bevy::render::render_resource::VertexFormat;
 
-pub mod optimize {
-    use hashbrown::*;
-    use crate::mesher::{MeshData, MeshDataHashable};
-
-    // This seems to just be a useless function. (Shit! That is around 8 hours wasted!)
-    pub fn share_vertices(mesh_data: &Vec<MeshData>, indices: &Vec<u32>) -> (Vec<MeshData>, Vec<u32>) {
-        let mesh_data_hashable: Vec<MeshDataHashable> = mesh_data.iter().map(|x| x.to_hashable()).collect();
-
-        let mut new_mesh_data: Vec<MeshData> = Vec::new();
-        let mut new_indices: Vec<u32> = Vec::new();
-        
-        let mut mapping: HashMap<MeshDataHashable, u32> = HashMap::new();
+use crate::block::RenderLayer;
 
-        for i in indices.iter() {
-            let mdh = mesh_data_hashable[*i as usize];
+/// Where a vertex's texture is in the block atlas. (Left, top, width, height, in atlas UVs.)
+pub const ATTRIBUTE_ATLAS_TILE: MeshVertexAttribute = MeshVertexAttribute::new("Vertex_AtlasTile", 988540917, VertexFormat::Float32x4);
 
-            if mapping.contains_key(&mdh) == false {
-                new_mesh_data.push(mdh.to_mesh_data());
-                new_indices.push((new_mesh_data.len() - 1) as u32);
-                mapping.insert(mdh, (new_mesh_data.len() - 1) as u32);
-            }
-
-            else {
-                new_indices.push(*mapping.get(&mdh).unwrap());
-            }
-        }
+/// Which layer of the block texture array a vertex's texture is in.
+pub const ATTRIBUTE_TEXTURE_LAYER: MeshVertexAttribute = MeshVertexAttribute::new("Vertex_TextureLayer", 988540918, VertexFormat::Uint32);
 
-        return (new_mesh_data, new_indices);
+pub mod optimize {
+    use hashbrown::*;
+    use crate::mesher::{MeshBuilder, MeshData, MeshDataHashable};
+
+    /// Merge vertices that are exactly the same, bit for bit, and point the indices at the merged ones.
+    /// The triangles don't change at all, there are just less vertices.
+    pub fn share_vertices(builder: &MeshBuilder) -> MeshBuilder {
+        let mut new_mesh_data: Vec<MeshData> = Vec::with_capacity(builder.mesh_data.len());
+        let mut mapping: HashMap<MeshDataHashable, u32> = HashMap::with_capacity(builder.mesh_data.len());
+
+        // Where every old vertex ended up.
+        let remap: Vec<u32> = builder.mesh_data.iter()
+            .map(|x| *mapping.entry(x.to_hashable()).or_insert_with(|| {
+                new_mesh_data.push(*x);
+
+                (new_mesh_data.len() - 1) as u32
+            }))
+            .collect();
+
+        return MeshBuilder {
+            mesh_data: new_mesh_data,
+            indices: builder.indices.iter().map(|i| remap[*i as usize]).collect(),
+        };
     }
 }
 
-// This is just a cleaner way of representing vertices, normals, and uvs all in one object.
+// This is just a cleaner way of representing vertices, normals, uvs, and colors all in one object.
 #[derive(Debug, Clone, Copy)]
 pub struct MeshData {
     vertex: [f32; 3],
     normal: [f32; 3],
     uv: [f32; 2],
+    color: [f32; 4], // Baked lighting. (White means fully lit.)
+    tile: [f32; 4], // See ATTRIBUTE_ATLAS_TILE.
+    layer: u32, // See ATTRIBUTE_TEXTURE_LAYER.
 }
 
 impl MeshData {
@@ -49,9 +58,30 @@ impl MeshData {
             vertex,
             normal,
             uv,
+            color: [1.0; 4],
+            tile: [0.0, 0.0, 1.0, 1.0],
+            layer: 0,
         };
     }
 
+    pub fn with_color(mut self, color: [f32; 4]) -> Self {
+        self.color = color;
+
+        return self;
+    }
+
+    pub fn with_tile(mut self, tile: [f32; 4]) -> Self {
+        self.tile = tile;
+
+        return self;
+    }
+
+    pub fn with_layer(mut self, layer: u32) -> Self {
+        self.layer = layer;
+
+        return self;
+    }
+
     pub fn from_general(general: ([f32; 3], [f32; 3], [f32; 2])) -> Self {
         let vertex: [f32; 3];
         let normal: [f32; 3];
@@ -75,104 +105,300 @@ impl MeshData {
     }
 
     pub fn to_hashable(&self) -> MeshDataHashable {
-        let sv = self.vertex;
-        let sn = self.normal;
-        let su = self.uv;
-        
-        return MeshDataHashable {
-            vertex: (MeshDataF32Hashable::new(sv[0]), MeshDataF32Hashable::new(sv[1]), MeshDataF32Hashable::new(sv[2])),
-            normal: (MeshDataF32Hashable::new(sn[0]), MeshDataF32Hashable::new(sn[1]), MeshDataF32Hashable::new(sn[2])),
-            uv: (MeshDataF32Hashable::new(su[0]), MeshDataF32Hashable::new(su[1]))
-        };
+        let mut bits = [0u32; 17];
+
+        let values = self.vertex.iter()
+            .chain(self.normal.iter())
+            .chain(self.uv.iter())
+            .chain(self.color.iter())
+            .chain(self.tile.iter());
+
+        for (b, v) in bits.iter_mut().zip(values) {
+            *b = v.to_bits();
+        }
+
+        bits[16] = self.layer;
+
+        return MeshDataHashable { bits };
     }
 }
 
+/// The raw bits of every value in a MeshData, so vertices can be hashed and compared exactly.
+/// (So 0.0 and -0.0 count as different vertices.)
 #[derive(Debug, Eq, PartialEq, Clone, Copy, Hash)]
 pub struct MeshDataHashable {
-    vertex: (MeshDataF32Hashable, MeshDataF32Hashable, MeshDataF32Hashable),
-    normal: (MeshDataF32Hashable, MeshDataF32Hashable, MeshDataF32Hashable),
-    uv: (MeshDataF32Hashable, MeshDataF32Hashable),
+    bits: [u32; 17],
 }
 
 impl MeshDataHashable {
     pub fn to_mesh_data(&self) -> MeshData {
-        let sv = self.vertex;
-        let sn = self.normal;
-        let su = self.uv;
-        
-        return MeshData::new([sv.0.to_f32(), sv.1.to_f32(), sv.2.to_f32()], [sn.0.to_f32(), sn.1.to_f32(), sn.2.to_f32()], [su.0.to_f32(), su.1.to_f32()]);
+        let f = |i: usize| f32::from_bits(self.bits[i]);
+
+        return MeshData::new([f(0), f(1), f(2)], [f(3), f(4), f(5)], [f(6), f(7)])
+            .with_color([f(8), f(9), f(10), f(11)])
+            .with_tile([f(12), f(13), f(14), f(15)])
+            .with_layer(self.bits[16]);
     }
 }
 
-#[derive(Debug, Eq, PartialEq, Clone, Copy, Hash)]
-struct MeshDataF32Hashable {
-    value: (i32, i32),
-    is_negative: bool, // For whatever reason, .parse() does not support parsing negative numbers! ( assert!("-2".parse().unwrap() == 2); )
+pub fn create_mesh(mesh_data: &Vec<MeshData>, indices: &Vec<u32>) -> Mesh {
+    let mut mesh = Mesh::new(PrimitiveTopology::TriangleList);
+
+    let positions: Vec<_> = mesh_data.iter().map(|x| x.vertex).collect();
+    let normals: Vec<_> = mesh_data.iter().map(|x| x.normal).collect();
+    let uvs: Vec<_> = mesh_data.iter().map(|x| x.uv).collect();
+    let colors: Vec<_> = mesh_data.iter().map(|x| x.color).collect();
+    let tiles: Vec<_> = mesh_data.iter().map(|x| x.tile).collect();
+    let layers: Vec<_> = mesh_data.iter().map(|x| x.layer).collect();
+
+    mesh.insert_attribute(Mesh::ATTRIBUTE_POSITION, positions);
+    mesh.insert_attribute(Mesh::ATTRIBUTE_NORMAL, normals);
+    mesh.insert_attribute(Mesh::ATTRIBUTE_UV_0, uvs);
+    mesh.insert_attribute(Mesh::ATTRIBUTE_COLOR, colors);
+    mesh.insert_attribute(ATTRIBUTE_ATLAS_TILE, tiles);
+    mesh.insert_attribute(ATTRIBUTE_TEXTURE_LAYER, layers);
+
+    mesh.set_indices(Some(Indices::U32(indices.clone())));
+
+    return mesh;
+}
+
+/// Puts a mesh together out of lots of small pieces, like the faces of voxels.
+/// Every piece has its own indices starting from 0, which get moved past the vertices that are already in the mesh.
+#[derive(Debug, Clone, Default)]
+pub struct MeshBuilder {
+    mesh_data: Vec<MeshData>,
+    indices: Vec<u32>,
 }
 
-impl MeshDataF32Hashable {
-    pub fn new(v: f32) -> Self {
-        let mut tnum: Vec<i32> = Vec::new();
+impl MeshBuilder {
+    pub fn new() -> Self {
+        return Self::default();
+    }
+
+    pub fn with_capacity(vertices: usize, indices: usize) -> Self {
+        return Self {
+            mesh_data: Vec::with_capacity(vertices),
+            indices: Vec::with_capacity(indices),
+        };
+    }
+
+    /// Append a piece of mesh.
+    pub fn push(&mut self, mesh_data: &[MeshData], indices: &[u32]) {
+        let base = self.mesh_data.len() as u32;
+
+        self.mesh_data.extend_from_slice(mesh_data);
+        self.indices.extend(indices.iter().map(|i| i + base));
+    }
+
+    /// Append everything from another builder.
+    pub fn append(&mut self, other: &MeshBuilder) {
+        self.push(&other.mesh_data, &other.indices);
+    }
+
+    pub fn vertex_count(&self) -> usize {
+        return self.mesh_data.len();
+    }
+
+    pub fn index_count(&self) -> usize {
+        return self.indices.len();
+    }
+
+    pub fn is_empty(&self) -> bool {
+        return self.indices.is_empty();
+    }
+
+    pub fn mesh_data(&self) -> &Vec<MeshData> {
+        return &self.mesh_data;
+    }
+
+    pub fn indices(&self) -> &Vec<u32> {
+        return &self.indices;
+    }
 
-        // This is for fixing an issue where the "-" sign is lost on numbers starting with -0.x.
-        let is_negative_0_starting = v.to_string().starts_with("-0");
+    pub fn into_parts(self) -> (Vec<MeshData>, Vec<u32>) {
+        return (self.mesh_data, self.indices);
+    }
 
-        for i in v.to_string().trim().split(".") {
-            let int: i32 = i.parse().unwrap();
-            
-            tnum.push(int);
+    /// Scale every vertex position, then move it by an offset on every axis.
+    pub fn scale_positions(&mut self, scale: f32, offset: f32) {
+        for x in self.mesh_data.iter_mut() {
+            x.vertex = x.vertex.map(|v| v * scale + offset);
         }
+    }
 
-        if tnum.len() == 1 {
-            tnum.push(0);
+    /// Scale every UV, so textures repeat that many more times.
+    pub fn scale_uvs(&mut self, scale: f32) {
+        for x in self.mesh_data.iter_mut() {
+            x.uv = x.uv.map(|v| v * scale);
         }
+    }
+
+    pub fn build(&self) -> Mesh {
+        return create_mesh(&self.mesh_data, &self.indices);
+    }
+}
 
+/// A MeshBuilder for every render layer, since each layer gets drawn with its own material.
+#[derive(Debug, Clone, Default)]
+pub struct LayeredMeshBuilder {
+    layers: [MeshBuilder; 3], // Indexed by RenderLayer::index().
+}
+
+impl LayeredMeshBuilder {
+    pub fn new() -> Self {
+        return Self::default();
+    }
+
+    pub fn layer(&self, layer: RenderLayer) -> &MeshBuilder {
+        return &self.layers[layer.index()];
+    }
+
+    pub fn layer_mut(&mut self, layer: RenderLayer) -> &mut MeshBuilder {
+        return &mut self.layers[layer.index()];
+    }
+
+    /// Run something over the builder of every layer.
+    pub fn map(&self, f: impl Fn(&MeshBuilder) -> MeshBuilder) -> Self {
         return Self {
-            value: (tnum[0], tnum[1]),
-            is_negative: is_negative_0_starting,
+            layers: [f(&self.layers[0]), f(&self.layers[1]), f(&self.layers[2])],
         };
     }
 
-    pub fn to_f32(&self) -> f32 {
-        let mut return_value: f32 = format!("{}.{}", self.value.0, self.value.1).trim().parse().unwrap();
+    pub fn scale_positions(&mut self, scale: f32, offset: f32) {
+        for x in self.layers.iter_mut() {
+            x.scale_positions(scale, offset);
+        }
+    }
 
-        if self.is_negative {
-            return_value = -return_value;
+    pub fn scale_uvs(&mut self, scale: f32) {
+        for x in self.layers.iter_mut() {
+            x.scale_uvs(scale);
         }
+    }
 
-        return return_value;
+    /// Meshes of every layer, in RenderLayer::ALL order. (Layers without faces still get an empty mesh.)
+    pub fn build(&self) -> [Mesh; 3] {
+        return RenderLayer::ALL.map(|x| self.layer(x).build());
     }
 }
 
-pub fn create_mesh(mesh_data: &Vec<MeshData>, indices: &Vec<u32>) -> Mesh {
-    let mut mesh = Mesh::new(PrimitiveTopology::TriangleList);
+/// Sort the faces of a mesh from furthest to closest to a point (in the mesh's own space), so blended faces are drawn in the right order.
+/// Every 6 indices are one face, like MeshBuilder puts them together.
+pub fn sort_faces_back_to_front(mesh: &mut Mesh, eye: Vec3) {
+    if let Some(indices) = faces_back_to_front(mesh, eye) {
+        mesh.set_indices(Some(Indices::U32(indices)));
+    }
+}
 
-    let positions: Vec<_> = mesh_data.iter().map(|x| x.vertex).collect();
-    let normals: Vec<_> = mesh_data.iter().map(|x| x.normal).collect();
-    let uvs: Vec<_> = mesh_data.iter().map(|x| x.uv).collect();
+/// Indices of a mesh with its faces sorted like sort_faces_back_to_front(), without changing the mesh.
+/// (Returns None if the faces are already in that order, so meshes that don't change don't have to be uploaded again.)
+pub fn faces_back_to_front(mesh: &Mesh, eye: Vec3) -> Option<Vec<u32>> {
+    let positions: Vec<Vec3> = match mesh.attribute(Mesh::ATTRIBUTE_POSITION) {
+        Some(VertexAttributeValues::Float32x3(p)) => p.iter().map(|x| Vec3::from(*x)).collect(),
+        _ => return None,
+    };
 
-    mesh.insert_attribute(Mesh::ATTRIBUTE_POSITION, positions);
-    mesh.insert_attribute(Mesh::ATTRIBUTE_NORMAL, normals);
-    mesh.insert_attribute(Mesh::ATTRIBUTE_UV_0, uvs);
+    let indices = match mesh.indices() {
+        Some(Indices::U32(i)) => i,
+        _ => return None,
+    };
 
-    mesh.set_indices(Some(Indices::U32(indices.clone())));
+    // Sorting is stable, so faces that are just as far away keep their order, and don't count as a change.
+    let mut faces: Vec<(f32, &[u32])> = indices.chunks_exact(6)
+        .map(|f| {
+            let center = f.iter().map(|i| positions[*i as usize]).sum::<Vec3>() / 6.0;
 
-    return mesh;
+            (center.distance_squared(eye), f)
+        })
+        .collect();
+
+    faces.sort_by(|a, b| b.0.total_cmp(&a.0));
+
+    let sorted: Vec<u32> = faces.into_iter().flat_map(|x| x.1.iter().copied()).collect();
+
+    if &sorted == indices {
+        return None;
+    }
+
+    return Some(sorted);
 }
 
-pub fn combine_indices(indices_vec: &Vec<Vec<u32>>) -> Vec<u32> {
-    let mut new_vec: Vec<u32> = Vec::new();
+#[cfg(test)]
+mod tests {
+    use crate::block::{BlockType, RenderLayer};
+    use crate::block_atlas::BlockAtlas;
+    use crate::chunk::{BlockPos, Chunk, ChunkNeighborhood, ChunkPos, MeshingMode, MissingNeighborPolicy, CHUNK_SIZE};
+    use crate::light;
+    use crate::mesher::{optimize, MeshBuilder};
+
+    // Hilly ground with an ore in it, always the same so failures can be reproduced.
+    fn test_chunk() -> Chunk {
+        let mut chunk = Chunk::new(ChunkPos::default());
+
+        for x in 0..CHUNK_SIZE.x {
+            for z in 0..CHUNK_SIZE.z {
+                let height = 4 + (x as usize * 3 + z as usize * 5) % 7;
+
+                for y in 0..height {
+                    let block = match height - y {
+                        1 => BlockType::GRASS,
+                        2 | 3 => BlockType::DIRT,
+                        _ => BlockType::STONE,
+                    };
+
+                    chunk.set_block(BlockPos::new_unchecked(x, y as u8, z), block);
+                }
+            }
+        }
 
-    for v in indices_vec.iter() {
-        let offset: u32 = match new_vec.iter().max() {
-            Some(s) => *s + 1,
-            None => 0,
-        };
+        chunk.set_block(BlockPos::new_unchecked(7, 1, 7), BlockType::DIAMOND);
 
-        for i in v.iter() {
-            new_vec.push(i + offset);
+        return chunk;
+    }
+
+    // Mesh of the test chunk, like the chunk meshing task makes it, just without welding.
+    fn test_mesh(mode: MeshingMode) -> MeshBuilder {
+        let mut chunk = test_chunk();
+
+        light::light_chunk(&mut chunk);
+
+        let builder = ChunkNeighborhood::alone(chunk)
+            .mesh_builder(MissingNeighborPolicy::Air, mode, false, 1, [1; 6], &BlockAtlas::placeholder())
+            .unwrap(); // Guarenteed value.
+
+        return builder.layer(RenderLayer::Opaque).clone();
+    }
+
+    fn assert_welded(builder: &MeshBuilder) {
+        let welded = optimize::share_vertices(builder);
+
+        assert!(builder.vertex_count() > 0);
+        assert!(welded.vertex_count() < builder.vertex_count());
+        assert_eq!(welded.index_count(), builder.index_count());
+
+        // Every triangle still has to point at exactly the same vertices.
+        for (a, b) in builder.indices().iter().zip(welded.indices().iter()) {
+            assert_eq!(builder.mesh_data()[*a as usize].to_hashable(), welded.mesh_data()[*b as usize].to_hashable());
         }
     }
 
-    return new_vec;
+    #[test]
+    fn share_vertices_per_face() {
+        assert_welded(&test_mesh(MeshingMode::PerFace));
+    }
+
+    #[test]
+    fn share_vertices_greedy() {
+        assert_welded(&test_mesh(MeshingMode::Greedy));
+    }
+
+    #[test]
+    fn share_vertices_twice_changes_nothing() {
+        let welded = optimize::share_vertices(&test_mesh(MeshingMode::PerFace));
+        let again = optimize::share_vertices(&welded);
+
+        assert_eq!(again.vertex_count(), welded.vertex_count());
+        assert_eq!(again.indices(), welded.indices());
+    }
 }
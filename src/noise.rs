@@ -40,14 +40,31 @@ impl NoiseSettings {
     }
 }
 
-pub fn noise_3d(position: (f32, f32, f32), seed: u64, ns: &NoiseSettings) -> f32 {
-    let mut noise = FastNoise::seeded(seed);
+/// Noise generator that can be reused for many samples. (Creating one is not free, so don't do it per block!)
+pub struct NoiseSampler {
+    noise: FastNoise,
+    amp: f32,
+}
 
-    noise.set_noise_type(ns.ntype);
-    noise.set_frequency(ns.freq);
-    noise.set_fractal_octaves(ns.octaves);
+impl NoiseSampler {
+    pub fn new(seed: u64, ns: &NoiseSettings) -> Self {
+        let mut noise = FastNoise::seeded(seed);
 
-    let value = noise.get_noise3d(position.0, position.1, position.2) * ns.amp;
+        noise.set_noise_type(ns.ntype);
+        noise.set_frequency(ns.freq);
+        noise.set_fractal_octaves(ns.octaves);
 
-    return value;
+        return Self {
+            noise,
+            amp: ns.amp,
+        };
+    }
+
+    pub fn sample_3d(&self, position: (f32, f32, f32)) -> f32 {
+        return self.noise.get_noise3d(position.0, position.1, position.2) * self.amp;
+    }
+}
+
+pub fn noise_3d(position: (f32, f32, f32), seed: u64, ns: &NoiseSettings) -> f32 {
+    return NoiseSampler::new(seed, ns).sample_3d(position);
 }
@@ -24,6 +24,11 @@ pub fn cache() -> Path {
     return_path!("simplay", dir::cache().to_string());
 }
 
+/// Saved worlds.
+pub fn worlds() -> Path {
+    return_path!("worlds", base().to_string());
+}
+
 /// Built assets.
 pub fn assets() -> Path {
     return_path!("assets", cache().to_string());
@@ -54,6 +59,7 @@ pub fn create_all_dirs() -> Result<(), io::Error> {
     let directories = vec![
         base(),
         asset_packs(),
+        worlds(),
 
         cache(),
         assets(),
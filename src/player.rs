@@ -0,0 +1,369 @@
+#![allow(dead_code)]
+
+use bevy::input::mouse::MouseMotion;
+use bevy::prelude::*;
+use bevy::window::{CursorGrabMode, PrimaryWindow};
+
+use crate::block::BlockCollisionType;
+use crate::chunk::{ChunkManager, GlobalBlockPos};
+use crate::free_camera::CameraMode;
+
+pub struct PlayerPlugin;
+
+impl Plugin for PlayerPlugin {
+    fn build(&self, app: &mut App) {
+        app.init_resource::<PlayerSettings>();
+        app.add_systems(Startup, spawn_player);
+        app.add_systems(Update, grab_cursor);
+        app.add_systems(Update, (look_around, move_player, follow_player).chain().run_if(in_state(CameraMode::Player)));
+    }
+}
+
+/// Where the player shows up when the game starts. (Feet position.)
+const SPAWN_POINT: Vec3 = Vec3::new(-41.0, 8.0, 7.0);
+
+/// Longest time step used for movement. (Long frames get split up, so the player doesn't fall through the floor.)
+const MAX_STEP_SECONDS: f32 = 0.05;
+
+// Keeps float errors from making the player collide with blocks they are only touching.
+const EPSILON: f32 = 0.001;
+
+#[derive(Resource)]
+pub struct PlayerSettings {
+    /// Width, height, and depth of the player's bounding box.
+    pub size: Vec3,
+    /// Height of the camera above the player's feet.
+    pub eye_height: f32,
+    pub walk_speed: f32,
+    pub sprint_speed: f32,
+    /// Upwards speed of a jump.
+    pub jump_speed: f32,
+    pub gravity: f32,
+    pub max_fall_speed: f32,
+    /// Highest ledge the player walks up without jumping.
+    pub step_height: f32,
+    /// Movement speed is multiplied by this while touching a liquid.
+    pub liquid_speed_multiplier: f32,
+    /// Gravity is multiplied by this while touching a liquid.
+    pub liquid_gravity_multiplier: f32,
+    /// Upwards speed while holding jump in a liquid.
+    pub swim_speed: f32,
+    /// Radians turned per pixel of mouse movement.
+    pub mouse_sensitivity: f32,
+}
+
+impl Default for PlayerSettings {
+    fn default() -> Self {
+        Self {
+            size: Vec3::new(0.6, 1.8, 0.6),
+            eye_height: 1.6,
+            walk_speed: 4.3,
+            sprint_speed: 6.5,
+            jump_speed: 8.0,
+            gravity: 25.0,
+            max_fall_speed: 50.0,
+            step_height: 1.0,
+            liquid_speed_multiplier: 0.5,
+            liquid_gravity_multiplier: 0.25,
+            swim_speed: 3.0,
+            mouse_sensitivity: 0.002,
+        }
+    }
+}
+
+/// The player. (Its transform is the position of its feet.)
+#[derive(Component, Default)]
+pub struct Player {
+    pub velocity: Vec3,
+    pub on_ground: bool,
+    pub in_liquid: bool,
+    /// Rotation around the Y axis, in radians.
+    pub yaw: f32,
+    /// Rotation up and down, in radians.
+    pub pitch: f32,
+}
+
+/// Axis-aligned bounding box, in world space.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Aabb {
+    pub min: Vec3,
+    pub max: Vec3,
+}
+
+impl Aabb {
+    /// Bounding box of something standing at a position. (Position is the bottom center.)
+    pub fn from_feet(position: Vec3, size: Vec3) -> Self {
+        let half = Vec3::new(size.x / 2.0, 0.0, size.z / 2.0);
+
+        Self {
+            min: position - half,
+            max: position + half + Vec3::new(0.0, size.y, 0.0),
+        }
+    }
+
+    pub fn offset(&self, offset: Vec3) -> Self {
+        Self {
+            min: self.min + offset,
+            max: self.max + offset,
+        }
+    }
+
+    /// Does the box overlap a block? (Only touching doesn't count.)
+    pub fn intersects_block(&self, pos: GlobalBlockPos) -> bool {
+        let block = Self::block(pos);
+
+        (self.min.cmplt(block.max) & self.max.cmpgt(block.min)).all()
+    }
+
+    /// Every block that the box overlaps.
+    pub fn blocks(&self) -> impl Iterator<Item = GlobalBlockPos> {
+        // Blocks are centered on whole numbers, so block p covers (p - 0.5)..(p + 0.5).
+        let lo = (self.min + Vec3::splat(0.5)).floor();
+        let hi = (self.max - Vec3::splat(0.5)).ceil();
+
+        let (lo_x, lo_y, lo_z) = (lo.x as isize, lo.y as isize, lo.z as isize);
+        let (hi_x, hi_y, hi_z) = (hi.x as isize, hi.y as isize, hi.z as isize);
+
+        (lo_y..=hi_y).flat_map(move |y| {
+            (lo_z..=hi_z).flat_map(move |z| {
+                (lo_x..=hi_x).map(move |x| GlobalBlockPos::new(x, y, z))
+            })
+        })
+    }
+
+    fn block(pos: GlobalBlockPos) -> Self {
+        let center = pos.to_world();
+
+        Self {
+            min: center - Vec3::splat(0.5),
+            max: center + Vec3::splat(0.5),
+        }
+    }
+}
+
+fn spawn_player(mut cmds: Commands) {
+    cmds.spawn((
+        SpatialBundle::from_transform(Transform::from_translation(SPAWN_POINT)),
+        Player {
+            // Face the same way the camera used to.
+            yaw: -std::f32::consts::FRAC_PI_2,
+            ..default()
+        },
+        Name::new("Player"),
+    ));
+}
+
+/// Is the cursor grabbed by the game? (Mouse movement only turns the camera while it is.)
+pub fn cursor_grabbed(window: &Window) -> bool {
+    window.cursor.grab_mode != CursorGrabMode::None
+}
+
+// Click to grab the cursor, escape to let it go.
+fn grab_cursor(
+    mut windows: Query<&mut Window, With<PrimaryWindow>>,
+    mouse: Res<Input<MouseButton>>,
+    keys: Res<Input<KeyCode>>,
+) {
+    let mut window = match windows.get_single_mut() {
+        Ok(o) => o,
+        Err(_) => return,
+    };
+
+    if mouse.just_pressed(MouseButton::Left) && cursor_grabbed(&window) == false {
+        window.cursor.grab_mode = CursorGrabMode::Locked;
+        window.cursor.visible = false;
+    }
+
+    if keys.just_pressed(KeyCode::Escape) {
+        window.cursor.grab_mode = CursorGrabMode::None;
+        window.cursor.visible = true;
+    }
+}
+
+fn look_around(
+    windows: Query<&Window, With<PrimaryWindow>>,
+    settings: Res<PlayerSettings>,
+    mut motion: EventReader<MouseMotion>,
+    mut players: Query<&mut Player>,
+) {
+    let grabbed = match windows.get_single() {
+        Ok(o) => cursor_grabbed(o),
+        Err(_) => false,
+    };
+
+    let delta: Vec2 = motion.iter().map(|m| m.delta).sum();
+
+    if grabbed == false {
+        return;
+    }
+
+    for mut player in players.iter_mut() {
+        player.yaw -= delta.x * settings.mouse_sensitivity;
+        player.pitch = (player.pitch - delta.y * settings.mouse_sensitivity).clamp(-1.54, 1.54);
+    }
+}
+
+fn move_player(
+    time: Res<Time>,
+    keys: Res<Input<KeyCode>>,
+    settings: Res<PlayerSettings>,
+    chunk_manager: Res<ChunkManager>,
+    mut players: Query<(&mut Player, &mut Transform)>,
+) {
+    let mut wish = Vec3::ZERO;
+
+    if keys.pressed(KeyCode::W) { wish.z -= 1.0; }
+    if keys.pressed(KeyCode::S) { wish.z += 1.0; }
+    if keys.pressed(KeyCode::A) { wish.x -= 1.0; }
+    if keys.pressed(KeyCode::D) { wish.x += 1.0; }
+
+    let sprinting = keys.pressed(KeyCode::ShiftLeft);
+    let jumping = keys.pressed(KeyCode::Space);
+
+    for (mut player, mut transform) in players.iter_mut() {
+        let mut remaining = time.delta_seconds();
+
+        while remaining > 0.0 {
+            let dt = remaining.min(MAX_STEP_SECONDS);
+            remaining -= dt;
+
+            let direction = Quat::from_rotation_y(player.yaw) * wish.normalize_or_zero();
+
+            let mut speed = if sprinting { settings.sprint_speed } else { settings.walk_speed };
+            let mut gravity = settings.gravity;
+
+            if player.in_liquid {
+                speed *= settings.liquid_speed_multiplier;
+                gravity *= settings.liquid_gravity_multiplier;
+            }
+
+            player.velocity.x = direction.x * speed;
+            player.velocity.z = direction.z * speed;
+            player.velocity.y = (player.velocity.y - gravity * dt).max(-settings.max_fall_speed);
+
+            if jumping {
+                if player.in_liquid {
+                    player.velocity.y = settings.swim_speed;
+                } else if player.on_ground {
+                    player.velocity.y = settings.jump_speed;
+                }
+            }
+
+            let velocity = player.velocity;
+            let aabb = Aabb::from_feet(transform.translation, settings.size);
+
+            // Vertical first, so the player knows if they're standing on something before walking.
+            let dy = sweep(&chunk_manager, &aabb, 1, velocity.y * dt);
+            let aabb = aabb.offset(Vec3::new(0.0, dy, 0.0));
+
+            let blocked_y = dy != velocity.y * dt;
+
+            player.on_ground = blocked_y && velocity.y < 0.0;
+
+            if blocked_y {
+                player.velocity.y = 0.0;
+            }
+
+            let horizontal = Vec3::new(velocity.x * dt, 0.0, velocity.z * dt);
+            let mut moved = move_horizontal(&chunk_manager, &aabb, horizontal);
+
+            // Walk up ledges instead of stopping at them.
+            if player.on_ground && settings.step_height > 0.0 && moved != horizontal {
+                let up = sweep(&chunk_manager, &aabb, 1, settings.step_height);
+                let raised = aabb.offset(Vec3::new(0.0, up, 0.0));
+                let stepped = move_horizontal(&chunk_manager, &raised, horizontal);
+
+                if stepped.length_squared() > moved.length_squared() + EPSILON {
+                    // Settle back down on top of the ledge.
+                    let down = sweep(&chunk_manager, &raised.offset(stepped), 1, -up);
+
+                    moved = stepped + Vec3::new(0.0, up + down, 0.0);
+                }
+            }
+
+            transform.translation = aabb.offset(moved).min + Vec3::new(settings.size.x / 2.0, 0.0, settings.size.z / 2.0);
+
+            player.in_liquid = touching_liquid(&chunk_manager, &Aabb::from_feet(transform.translation, settings.size));
+        }
+    }
+}
+
+// Move along X, then Z, stopping at solid blocks. (Returns how far the box actually moved.)
+fn move_horizontal(chunk_manager: &ChunkManager, aabb: &Aabb, movement: Vec3) -> Vec3 {
+    let dx = sweep(chunk_manager, aabb, 0, movement.x);
+    let dz = sweep(chunk_manager, &aabb.offset(Vec3::new(dx, 0.0, 0.0)), 2, movement.z);
+
+    Vec3::new(dx, 0.0, dz)
+}
+
+/// How far a box can move along one axis (0 = X, 1 = Y, 2 = Z) before hitting a solid block.
+/// Unloaded chunks count as solid, and blocks the box is already inside of are ignored (so it can't get stuck).
+pub fn sweep(chunk_manager: &ChunkManager, aabb: &Aabb, axis: usize, distance: f32) -> f32 {
+    if distance == 0.0 {
+        return 0.0;
+    }
+
+    // Every block the box passes through, shrunk a bit on the other axes so touching blocks aren't included.
+    let mut swept = Aabb {
+        min: aabb.min + Vec3::splat(EPSILON),
+        max: aabb.max - Vec3::splat(EPSILON),
+    };
+
+    if distance > 0.0 {
+        swept.max[axis] = aabb.max[axis] + distance;
+    } else {
+        swept.min[axis] = aabb.min[axis] + distance;
+    }
+
+    let mut allowed = distance;
+
+    for pos in swept.blocks() {
+        let solid = match chunk_manager.get_block(pos) {
+            Some(s) => s.properties().collision == BlockCollisionType::Solid,
+            None => true,
+        };
+
+        if solid == false {
+            continue;
+        }
+
+        let block = Aabb::block(pos);
+
+        if distance > 0.0 && block.min[axis] >= aabb.max[axis] - EPSILON {
+            allowed = allowed.min(block.min[axis] - aabb.max[axis]);
+        } else if distance < 0.0 && block.max[axis] <= aabb.min[axis] + EPSILON {
+            allowed = allowed.max(block.max[axis] - aabb.min[axis]);
+        }
+    }
+
+    // Float errors can make the box back up a tiny bit, which would look like a collision every frame.
+    if allowed.signum() != distance.signum() {
+        return 0.0;
+    }
+
+    return allowed;
+}
+
+fn touching_liquid(chunk_manager: &ChunkManager, aabb: &Aabb) -> bool {
+    aabb.blocks().any(|pos| match chunk_manager.get_block(pos) {
+        Some(s) => s.properties().collision == BlockCollisionType::Liquid,
+        None => false,
+    })
+}
+
+// Put the camera in the player's head.
+fn follow_player(
+    settings: Res<PlayerSettings>,
+    players: Query<(&Player, &Transform)>,
+    mut cameras: Query<&mut Transform, (With<Camera3d>, Without<Player>)>,
+) {
+    let (player, player_transform) = match players.get_single() {
+        Ok(o) => o,
+        Err(_) => return,
+    };
+
+    for mut camera in cameras.iter_mut() {
+        camera.translation = player_transform.translation + Vec3::new(0.0, settings.eye_height, 0.0);
+        camera.rotation = Quat::from_euler(EulerRot::YXZ, player.yaw, player.pitch, 0.0);
+    }
+}
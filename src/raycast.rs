@@ -0,0 +1,119 @@
+#![allow(dead_code)]
+
+use bevy::prelude::*;
+
+use crate::block::{BlockCollisionType, BlockType};
+use crate::chunk::{ChunkManager, GlobalBlockPos};
+use crate::world;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct RaycastOptions {
+    /// How far the ray goes before giving up.
+    pub max_distance: f32,
+    /// Should liquid blocks stop the ray? (Gas blocks never do.)
+    pub hit_liquids: bool,
+}
+
+impl Default for RaycastOptions {
+    fn default() -> Self {
+        Self {
+            max_distance: 8.0,
+            hit_liquids: false,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct RaycastHit {
+    pub pos: GlobalBlockPos,
+    pub block: BlockType,
+    /// Face of the block that the ray went in through. (None if the ray started inside of the block.)
+    pub face: Option<world::Direction>,
+    /// Distance from the origin to where the ray hit the block.
+    pub distance: f32,
+}
+
+impl RaycastHit {
+    /// The block in front of the face that was hit. (Where a new block would be placed.)
+    pub fn adjacent(&self) -> Option<GlobalBlockPos> {
+        self.face.map(|f| self.pos.offset(f))
+    }
+}
+
+/// Find the first block a ray hits, by stepping through every block it passes (DDA).
+/// Stops early (returning None) if the ray reaches a chunk that isn't loaded.
+pub fn raycast(chunk_manager: &ChunkManager, origin: Vec3, direction: Vec3, options: RaycastOptions) -> Option<RaycastHit> {
+    let direction = direction.normalize_or_zero();
+
+    if direction == Vec3::ZERO {
+        return None;
+    }
+
+    // Blocks are centered on whole numbers, so shift everything by half a block to get a regular grid.
+    let start = origin + Vec3::splat(0.5);
+
+    let mut cell = [start.x.floor() as isize, start.y.floor() as isize, start.z.floor() as isize];
+    let dir = direction.to_array();
+    let pos = start.to_array();
+
+    let mut step = [0isize; 3];
+    let mut t_max = [f32::INFINITY; 3];
+    let mut t_delta = [f32::INFINITY; 3];
+
+    for axis in 0..3 {
+        if dir[axis] > 0.0 {
+            step[axis] = 1;
+            t_max[axis] = (cell[axis] as f32 + 1.0 - pos[axis]) / dir[axis];
+            t_delta[axis] = 1.0 / dir[axis];
+        } else if dir[axis] < 0.0 {
+            step[axis] = -1;
+            t_max[axis] = (pos[axis] - cell[axis] as f32) / -dir[axis];
+            t_delta[axis] = 1.0 / -dir[axis];
+        }
+    }
+
+    // Faces that the ray goes in through, when stepping along each axis in the positive direction.
+    let entry_faces = [
+        (world::Direction::West, world::Direction::East),
+        (world::Direction::Down, world::Direction::Up),
+        (world::Direction::North, world::Direction::South),
+    ];
+
+    let mut distance: f32 = 0.0;
+    let mut face: Option<world::Direction> = None;
+
+    loop {
+        let block_pos = GlobalBlockPos::new(cell[0], cell[1], cell[2]);
+        let block = chunk_manager.get_block(block_pos)?;
+
+        let hit = match block.properties().collision {
+            BlockCollisionType::Solid => true,
+            BlockCollisionType::Liquid => options.hit_liquids,
+            BlockCollisionType::Gas => false,
+        };
+
+        if hit {
+            return Some(RaycastHit { pos: block_pos, block, face, distance });
+        }
+
+        // Step along the axis whose next block boundary is closest.
+        let axis = if t_max[0] < t_max[1] && t_max[0] < t_max[2] {
+            0
+        } else if t_max[1] < t_max[2] {
+            1
+        } else {
+            2
+        };
+
+        distance = t_max[axis];
+
+        if distance > options.max_distance {
+            return None;
+        }
+
+        cell[axis] += step[axis];
+        t_max[axis] += t_delta[axis];
+
+        face = Some(if step[axis] > 0 { entry_faces[axis].0 } else { entry_faces[axis].1 });
+    }
+}
@@ -0,0 +1,669 @@
+#![allow(dead_code)]
+
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::sync::Arc;
+use flate2::Compression;
+use flate2::read::ZlibDecoder;
+use flate2::write::ZlibEncoder;
+use hashbrown::{HashMap, HashSet};
+use crate::block::BlockIdTable;
+use crate::chunk::{Chunk, ChunkPos};
+use crate::chunk_storage::BlockStorage;
+use crate::filesystem::*;
+use crate::places;
+
+/// Amount of chunks on each axis of a region.
+pub const REGION_SIZE: isize = 8;
+
+/// Amount of chunks in a region.
+pub const REGION_CHUNKS: usize = (REGION_SIZE * REGION_SIZE * REGION_SIZE) as usize;
+
+/// Bump this whenever the layout of a region file (or a chunk inside of it) changes.
+pub const REGION_FORMAT_VERSION: u32 = 1;
+
+const REGION_MAGIC: [u8; 4] = *b"SPRG";
+
+// Magic, format version, then an (offset, length) pair for every chunk.
+const HEADER_SIZE: usize = 4 + 4 + REGION_CHUNKS * 8;
+
+/// How a single chunk is compressed inside of a region file. (First byte of every chunk entry.)
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ChunkCompression {
+    None,
+    Zlib,
+}
+
+impl ChunkCompression {
+    fn id(&self) -> u8 {
+        return match self {
+            Self::None => 0,
+            Self::Zlib => 1,
+        };
+    }
+
+    fn from_id(id: u8) -> Option<Self> {
+        return match id {
+            0 => Some(Self::None),
+            1 => Some(Self::Zlib),
+            _ => None,
+        };
+    }
+
+    fn compress(&self, bytes: &[u8]) -> Result<Vec<u8>, io::Error> {
+        return match self {
+            Self::None => Ok(bytes.to_vec()),
+            Self::Zlib => {
+                let mut encoder = ZlibEncoder::new(Vec::new(), Compression::default());
+
+                encoder.write_all(bytes)?;
+                encoder.finish()
+            },
+        };
+    }
+
+    fn decompress(&self, bytes: &[u8]) -> Result<Vec<u8>, io::Error> {
+        return match self {
+            Self::None => Ok(bytes.to_vec()),
+            Self::Zlib => {
+                let mut decompressed: Vec<u8> = Vec::new();
+
+                ZlibDecoder::new(bytes).read_to_end(&mut decompressed)?;
+
+                Ok(decompressed)
+            },
+        };
+    }
+}
+
+#[derive(Default, Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct RegionPos {
+    pub x: isize,
+    pub y: isize,
+    pub z: isize,
+}
+
+impl RegionPos {
+    pub const fn new(x: isize, y: isize, z: isize) -> Self {
+        Self { x, y, z }
+    }
+
+    /// The region that a chunk belongs to.
+    pub fn from_chunk(chunk_pos: ChunkPos) -> Self {
+        return Self::new(
+            chunk_pos.x.div_euclid(REGION_SIZE),
+            chunk_pos.y.div_euclid(REGION_SIZE),
+            chunk_pos.z.div_euclid(REGION_SIZE),
+        );
+    }
+
+    /// Index of a chunk in the region header.
+    pub fn chunk_index(chunk_pos: ChunkPos) -> usize {
+        let x = chunk_pos.x.rem_euclid(REGION_SIZE) as usize;
+        let y = chunk_pos.y.rem_euclid(REGION_SIZE) as usize;
+        let z = chunk_pos.z.rem_euclid(REGION_SIZE) as usize;
+
+        return (y * REGION_SIZE as usize + z) * REGION_SIZE as usize + x;
+    }
+
+    pub fn file_name(&self) -> String {
+        return format!("r.{}.{}.{}.region", self.x, self.y, self.z);
+    }
+}
+
+/// A group of REGION_SIZE³ chunks, saved together in one file.
+///
+/// Layout: [magic, version (u32), (offset (u32), length (u32)) * REGION_CHUNKS, chunk entries...]
+/// Every chunk entry is [compression (u8), compressed chunk bytes...]. A length of 0 means the chunk was never saved.
+pub struct RegionFile {
+    pub pos: RegionPos,
+    path: Path,
+    entries: Vec<Option<Vec<u8>>>,
+    dirty: bool,
+}
+
+impl RegionFile {
+    /// Open a region file, or start an empty one if it doesn't exist yet.
+    pub fn open(directory: &Path, pos: RegionPos) -> Result<Self, io::Error> {
+        let path = directory.add_str(&pos.file_name());
+
+        let mut region = Self {
+            pos,
+            path,
+            entries: vec![None; REGION_CHUNKS],
+            dirty: false,
+        };
+
+        if region.path.exists() == false {
+            return Ok(region);
+        }
+
+        let bytes = file::read_bytes(&region.path)?;
+
+        if bytes.len() < HEADER_SIZE {
+            return Err(invalid_data(&format!("Not a region file: {}", region.path.to_string())));
+        }
+
+        check_header(&bytes, &region.path)?;
+
+        for i in 0..REGION_CHUNKS {
+            let offset = read_u32(&bytes, 8 + i * 8) as usize;
+            let length = read_u32(&bytes, 12 + i * 8) as usize;
+
+            if length == 0 {
+                continue;
+            }
+
+            if offset < HEADER_SIZE || offset + length > bytes.len() {
+                return Err(invalid_data(&format!("Chunk entry {} is out of bounds: {}", i, region.path.to_string())));
+            }
+
+            region.entries[i] = Some(bytes[offset..offset + length].to_vec());
+        }
+
+        return Ok(region);
+    }
+
+    /// Read a chunk from the region. (Returns None if it was never saved.)
+    pub fn read_chunk(&self, chunk_pos: ChunkPos, ids: &BlockIdTable) -> Result<Option<Chunk>, io::Error> {
+        self.check_contains(chunk_pos)?;
+
+        return match &self.entries[RegionPos::chunk_index(chunk_pos)] {
+            Some(s) => Ok(Some(decode_entry(s, chunk_pos, &self.path, ids)?)),
+            None => Ok(None),
+        };
+    }
+
+    /// Replace a chunk in the region. (Not written to disk until save() is called.)
+    pub fn write_chunk(&mut self, chunk: &Chunk, compression: ChunkCompression, ids: &BlockIdTable) -> Result<(), io::Error> {
+        self.check_contains(chunk.pos)?;
+
+        let mut entry: Vec<u8> = vec![compression.id()];
+
+        entry.extend(compression.compress(&chunk.storage().to_bytes(ids))?);
+
+        self.entries[RegionPos::chunk_index(chunk.pos)] = Some(entry);
+        self.dirty = true;
+
+        return Ok(());
+    }
+
+    // Chunks from other regions would silently overwrite one of ours.
+    fn check_contains(&self, chunk_pos: ChunkPos) -> Result<(), io::Error> {
+        if RegionPos::from_chunk(chunk_pos) != self.pos {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, format!("Chunk ({}, {}, {}) is not part of region {}", chunk_pos.x, chunk_pos.y, chunk_pos.z, self.pos.file_name())));
+        }
+
+        return Ok(());
+    }
+
+    /// Write the whole region to disk, if anything changed.
+    pub fn save(&mut self) -> Result<(), io::Error> {
+        if self.dirty == false {
+            return Ok(());
+        }
+
+        let mut header: Vec<u8> = Vec::with_capacity(HEADER_SIZE);
+        let mut body: Vec<u8> = Vec::new();
+
+        header.extend(REGION_MAGIC);
+        header.extend(REGION_FORMAT_VERSION.to_le_bytes());
+
+        for entry in self.entries.iter() {
+            let (offset, length) = match entry {
+                Some(s) => {
+                    let offset = HEADER_SIZE + body.len();
+                    body.extend(s);
+
+                    (offset as u32, s.len() as u32)
+                },
+                None => (0, 0),
+            };
+
+            header.extend(offset.to_le_bytes());
+            header.extend(length.to_le_bytes());
+        }
+
+        header.extend(body);
+
+        // Write to a temporary file first, so a crash never leaves half a region behind.
+        let temp_path = Path::new(&format!("{}.tmp", self.path.to_string()));
+
+        file::write_bytes(&header, &temp_path)?;
+        fs_action::rename(&temp_path, &self.path)?;
+
+        self.dirty = false;
+
+        return Ok(());
+    }
+}
+
+/// All the region files of a saved world, and the block IDs they are saved with.
+/// Regions stay in memory while they are in use, and have to be closed once they aren't. (See close_region())
+pub struct WorldStorage {
+    directory: Path,
+    regions: HashMap<RegionPos, RegionFile>,
+    failed: HashSet<RegionPos>, // Regions that couldn't be opened. (Not tried again until the game restarts, so a broken file doesn't get read over and over.)
+    ids: Arc<BlockIdTable>, // Shared with chunk sources, so chunks can be decoded on other threads.
+    ids_path: Path,
+    ids_saved: bool,
+    pub compression: ChunkCompression,
+}
+
+impl WorldStorage {
+    /// Fails if the world's block ID table can't be read, since none of its chunks could be.
+    pub fn open(world_name: &str) -> Result<Self, io::Error> {
+        let world = places::worlds().add_str(world_name);
+        let ids_path = world.add_str("blocks.toml");
+        let ids = BlockIdTable::load(&ids_path)?;
+
+        return Ok(Self {
+            directory: world.add_str("regions"),
+            regions: HashMap::new(),
+            failed: HashSet::new(),
+            ids_saved: ids.changed() == false,
+            ids: Arc::new(ids),
+            ids_path,
+            compression: ChunkCompression::Zlib,
+        });
+    }
+
+    fn region(&mut self, region_pos: RegionPos) -> Result<&mut RegionFile, io::Error> {
+        if self.failed.contains(&region_pos) {
+            return Err(io::Error::new(io::ErrorKind::Other, format!("Region {} failed to open earlier, so it can't be saved to", region_pos.file_name())));
+        }
+
+        if self.regions.contains_key(&region_pos) == false {
+            let region = match RegionFile::open(&self.directory, region_pos) {
+                Ok(o) => o,
+                Err(e) => {
+                    self.failed.insert(region_pos);
+
+                    return Err(e);
+                },
+            };
+
+            self.regions.insert(region_pos, region);
+        }
+
+        return Ok(self.regions.get_mut(&region_pos).unwrap()); // Guarenteed value.
+    }
+
+    /// Load a chunk from disk. (Returns None if it was never saved.)
+    pub fn load_chunk(&self, chunk_pos: ChunkPos) -> Result<Option<Chunk>, io::Error> {
+        return self.chunk_source(chunk_pos).load();
+    }
+
+    /// Everything needed to load a chunk, without actually reading or decoding anything yet. (See ChunkSource::load())
+    pub fn chunk_source(&self, chunk_pos: ChunkPos) -> ChunkSource {
+        let region_pos = RegionPos::from_chunk(chunk_pos);
+
+        // Open regions can have changes that aren't on disk yet.
+        return match self.regions.get(&region_pos) {
+            Some(s) => ChunkSource::Entry(chunk_pos, s.entries[RegionPos::chunk_index(chunk_pos)].clone(), s.path.clone(), self.ids.clone()),
+            None => ChunkSource::File(chunk_pos, self.directory.add_str(&region_pos.file_name()), self.ids.clone()),
+        };
+    }
+
+    /// Queue a chunk to be saved. (Call flush() to actually write it to disk.)
+    pub fn save_chunk(&mut self, chunk: &Chunk) -> Result<(), io::Error> {
+        let compression = self.compression;
+        let ids = self.ids.clone();
+
+        return self.region(RegionPos::from_chunk(chunk.pos))?.write_chunk(chunk, compression, &ids);
+    }
+
+    /// Write every changed region to disk. (A region that fails to save doesn't stop the others, the first error is returned after all of them were tried.)
+    pub fn flush(&mut self) -> Result<(), io::Error> {
+        self.prepare_directory()?;
+
+        let mut error: Option<io::Error> = None;
+
+        for region in self.regions.values_mut() {
+            if let Err(e) = region.save() {
+                error.get_or_insert(e);
+            }
+        }
+
+        return match error {
+            Some(e) => Err(e),
+            None => Ok(()),
+        };
+    }
+
+    /// Did a region fail to open? (Chunks can't be saved to it until the game restarts.)
+    pub fn region_failed(&self, region_pos: RegionPos) -> bool {
+        return self.failed.contains(&region_pos);
+    }
+
+    /// Save a region if it changed, and drop it from memory. (Does nothing if it isn't open.)
+    pub fn close_region(&mut self, region_pos: RegionPos) -> Result<(), io::Error> {
+        let dirty = match self.regions.get(&region_pos) {
+            Some(s) => s.dirty,
+            None => return Ok(()),
+        };
+
+        // Stays open if it couldn't be saved, so the changes aren't lost.
+        if dirty {
+            self.prepare_directory()?;
+            self.regions.get_mut(&region_pos).unwrap().save()?; // Guarenteed value.
+        }
+
+        self.regions.remove(&region_pos);
+
+        return Ok(());
+    }
+
+    /// Every region that is in memory right now.
+    pub fn open_regions(&self) -> Vec<RegionPos> {
+        return self.regions.keys().copied().collect();
+    }
+
+    // Create the directory, and save the block ID table if it changed. (The table has to be on disk before any chunk that uses its new IDs.)
+    fn prepare_directory(&mut self) -> Result<(), io::Error> {
+        if self.directory.exists() == false {
+            directory::create(&self.directory)?;
+        }
+
+        if self.ids_saved == false {
+            self.ids.save(&self.ids_path)?;
+            self.ids_saved = true;
+        }
+
+        return Ok(());
+    }
+}
+
+/// Where a chunk gets loaded from. Loading reads and decodes it, which is slow, so it can be moved to another thread.
+/// Both come with the world's block ID table, to decode the chunk with.
+pub enum ChunkSource {
+    /// The chunk's entry in a region that is already open. (None if it was never saved.)
+    Entry(ChunkPos, Option<Vec<u8>>, Path, Arc<BlockIdTable>),
+    /// The region file of the chunk, which only the chunk's entry gets read from.
+    File(ChunkPos, Path, Arc<BlockIdTable>),
+}
+
+impl ChunkSource {
+    /// Read and decode the chunk. (Returns None if it was never saved.)
+    pub fn load(self) -> Result<Option<Chunk>, io::Error> {
+        let (chunk_pos, entry, path, ids) = match self {
+            Self::Entry(chunk_pos, entry, path, ids) => (chunk_pos, entry, path, ids),
+            Self::File(chunk_pos, path, ids) => (chunk_pos, read_entry(&path, chunk_pos)?, path, ids),
+        };
+
+        return match entry {
+            Some(s) => Ok(Some(decode_entry(&s, chunk_pos, &path, &ids)?)),
+            None => Ok(None),
+        };
+    }
+}
+
+// Magic and format version at the start of a region file.
+fn check_header(bytes: &[u8], path: &Path) -> Result<(), io::Error> {
+    if bytes.len() < 8 || bytes[0..4] != REGION_MAGIC {
+        return Err(invalid_data(&format!("Not a region file: {}", path.to_string())));
+    }
+
+    let version = read_u32(bytes, 4);
+
+    if version != REGION_FORMAT_VERSION {
+        return Err(invalid_data(&format!("Unsupported region format version {} (expected {}): {}", version, REGION_FORMAT_VERSION, path.to_string())));
+    }
+
+    return Ok(());
+}
+
+// Read the entry of a single chunk out of a region file, without reading the rest of it. (None if the chunk or the file doesn't exist.)
+fn read_entry(path: &Path, chunk_pos: ChunkPos) -> Result<Option<Vec<u8>>, io::Error> {
+    if path.exists() == false {
+        return Ok(None);
+    }
+
+    let mut file = std::fs::File::open(path.to_string())?;
+    let file_length = file.metadata()?.len() as usize;
+
+    if file_length < HEADER_SIZE {
+        return Err(invalid_data(&format!("Not a region file: {}", path.to_string())));
+    }
+
+    let mut header = [0u8; 8];
+
+    file.read_exact(&mut header)?;
+    check_header(&header, path)?;
+
+    let i = RegionPos::chunk_index(chunk_pos);
+    let mut location = [0u8; 8];
+
+    file.seek(SeekFrom::Start((8 + i * 8) as u64))?;
+    file.read_exact(&mut location)?;
+
+    let offset = read_u32(&location, 0) as usize;
+    let length = read_u32(&location, 4) as usize;
+
+    if length == 0 {
+        return Ok(None);
+    }
+
+    if offset < HEADER_SIZE || offset + length > file_length {
+        return Err(invalid_data(&format!("Chunk entry {} is out of bounds: {}", i, path.to_string())));
+    }
+
+    let mut entry: Vec<u8> = vec![0; length];
+
+    file.seek(SeekFrom::Start(offset as u64))?;
+    file.read_exact(&mut entry)?;
+
+    return Ok(Some(entry));
+}
+
+// Turn a chunk entry back into a chunk. (The path is only used for error messages.)
+fn decode_entry(entry: &[u8], chunk_pos: ChunkPos, path: &Path, ids: &BlockIdTable) -> Result<Chunk, io::Error> {
+    let compression = match entry.first().and_then(|x| ChunkCompression::from_id(*x)) {
+        Some(s) => s,
+        None => return Err(invalid_data(&format!("Unknown chunk compression in: {}", path.to_string()))),
+    };
+
+    let storage = match BlockStorage::from_bytes(&compression.decompress(&entry[1..])?, ids) {
+        Some(s) => s,
+        None => return Err(invalid_data(&format!("Corrupt chunk ({}, {}, {}) in: {}", chunk_pos.x, chunk_pos.y, chunk_pos.z, path.to_string()))),
+    };
+
+    return Ok(Chunk::from_storage(chunk_pos, storage));
+}
+
+fn read_u32(bytes: &[u8], at: usize) -> u32 {
+    return u32::from_le_bytes([bytes[at], bytes[at + 1], bytes[at + 2], bytes[at + 3]]);
+}
+
+fn invalid_data(message: &str) -> io::Error {
+    return io::Error::new(io::ErrorKind::InvalidData, message.to_string());
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+    use crate::block::{BlockIdTable, BlockType};
+    use crate::chunk::{BlockPos, Chunk, ChunkPos};
+    use crate::filesystem::*;
+    use crate::region::{ChunkCompression, ChunkSource, RegionFile, RegionPos, HEADER_SIZE, REGION_FORMAT_VERSION};
+
+    // An empty directory of its own for every test, since they run at the same time.
+    fn temp_directory(name: &str) -> Path {
+        let directory = Path::new(&std::env::temp_dir().join(format!("simplay_region_test_{}", name)).display().to_string());
+
+        if directory.exists() {
+            fs_action::delete(&directory).unwrap();
+        }
+
+        directory::create(&directory).unwrap();
+
+        return directory;
+    }
+
+    fn test_chunk(pos: ChunkPos) -> Chunk {
+        let mut chunk = Chunk::new(pos);
+
+        for i in 0..16 {
+            chunk.set_block(BlockPos::new_unchecked(i, i, 15 - i), BlockType::STONE);
+            chunk.set_block(BlockPos::new_unchecked(i, 0, 0), BlockType::DIAMOND);
+        }
+
+        return chunk;
+    }
+
+    // A saved region with one chunk in it, as bytes.
+    fn region_bytes(directory: &Path) -> Vec<u8> {
+        let ids = BlockIdTable::from_registry();
+        let mut region = RegionFile::open(directory, RegionPos::new(0, 0, 0)).unwrap();
+
+        region.write_chunk(&test_chunk(ChunkPos::new(1, 2, 3)), ChunkCompression::Zlib, &ids).unwrap();
+        region.save().unwrap();
+
+        return file::read_bytes(&directory.add_str(&RegionPos::new(0, 0, 0).file_name())).unwrap();
+    }
+
+    // Overwrite a region file, and check that it can't be opened or have a chunk read out of it.
+    fn assert_rejected(directory: &Path, bytes: &[u8]) {
+        let path = directory.add_str(&RegionPos::new(0, 0, 0).file_name());
+        let ids = Arc::new(BlockIdTable::from_registry());
+
+        file::write_bytes(bytes, &path).unwrap();
+
+        assert!(RegionFile::open(directory, RegionPos::new(0, 0, 0)).is_err());
+        assert!(ChunkSource::File(ChunkPos::new(1, 2, 3), path, ids).load().is_err());
+    }
+
+    #[test]
+    fn round_trip() {
+        let directory = temp_directory("round_trip");
+        let ids = BlockIdTable::from_registry();
+
+        let chunks = [test_chunk(ChunkPos::new(-8, -1, 0)), Chunk::new(ChunkPos::new(-1, -8, 7)), test_chunk(ChunkPos::new(-5, -3, 2))];
+
+        for compression in [ChunkCompression::None, ChunkCompression::Zlib] {
+            let mut region = RegionFile::open(&directory, RegionPos::new(-1, -1, 0)).unwrap();
+
+            for chunk in chunks.iter() {
+                region.write_chunk(chunk, compression, &ids).unwrap();
+            }
+
+            region.save().unwrap();
+
+            let region = RegionFile::open(&directory, RegionPos::new(-1, -1, 0)).unwrap();
+            let path = directory.add_str(&RegionPos::new(-1, -1, 0).file_name());
+
+            for chunk in chunks.iter() {
+                assert_eq!(region.read_chunk(chunk.pos, &ids).unwrap().unwrap().storage(), chunk.storage());
+
+                let loaded = ChunkSource::File(chunk.pos, path.clone(), Arc::new(ids.clone())).load().unwrap().unwrap();
+
+                assert_eq!(loaded.storage(), chunk.storage());
+            }
+
+            // Never saved.
+            assert!(region.read_chunk(ChunkPos::new(-2, -2, 2), &ids).unwrap().is_none());
+            assert!(ChunkSource::File(ChunkPos::new(-2, -2, 2), path, Arc::new(ids.clone())).load().unwrap().is_none());
+        }
+
+        fs_action::delete(&directory).unwrap();
+    }
+
+    #[test]
+    fn chunks_from_other_regions() {
+        let directory = temp_directory("other_regions");
+        let ids = BlockIdTable::from_registry();
+
+        let mut region = RegionFile::open(&directory, RegionPos::new(0, 0, 0)).unwrap();
+
+        assert!(region.write_chunk(&Chunk::new(ChunkPos::new(8, 0, 0)), ChunkCompression::None, &ids).is_err());
+        assert!(region.read_chunk(ChunkPos::new(0, -1, 0), &ids).is_err());
+
+        fs_action::delete(&directory).unwrap();
+    }
+
+    #[test]
+    fn rejects_bad_headers() {
+        let directory = temp_directory("bad_headers");
+        let bytes = region_bytes(&directory);
+
+        let mut bad_magic = bytes.clone();
+        bad_magic[0] = b'X';
+        assert_rejected(&directory, &bad_magic);
+
+        let mut bad_version = bytes.clone();
+        bad_version[4..8].copy_from_slice(&(REGION_FORMAT_VERSION + 1).to_le_bytes());
+        assert_rejected(&directory, &bad_version);
+
+        assert_rejected(&directory, &bytes[..HEADER_SIZE - 1]);
+        assert_rejected(&directory, &bytes[..6]);
+
+        fs_action::delete(&directory).unwrap();
+    }
+
+    #[test]
+    fn rejects_out_of_bounds_entries() {
+        let directory = temp_directory("out_of_bounds");
+        let bytes = region_bytes(&directory);
+
+        let at = 8 + RegionPos::chunk_index(ChunkPos::new(1, 2, 3)) * 8;
+        let length = u32::from_le_bytes(bytes[at + 4..at + 8].try_into().unwrap());
+
+        // Past the end of the file.
+        let mut bad_offset = bytes.clone();
+        bad_offset[at..at + 4].copy_from_slice(&(bytes.len() as u32).to_le_bytes());
+        assert_rejected(&directory, &bad_offset);
+
+        // Inside of the header.
+        let mut in_header = bytes.clone();
+        in_header[at..at + 4].copy_from_slice(&4u32.to_le_bytes());
+        assert_rejected(&directory, &in_header);
+
+        let mut bad_length = bytes.clone();
+        bad_length[at + 4..at + 8].copy_from_slice(&(length + 1).to_le_bytes());
+        assert_rejected(&directory, &bad_length);
+
+        // Cut off in the middle of the chunk.
+        assert_rejected(&directory, &bytes[..bytes.len() - 1]);
+
+        fs_action::delete(&directory).unwrap();
+    }
+
+    #[test]
+    fn remaps_block_ids() {
+        let directory = temp_directory("remap");
+        let table_path = directory.add_str("blocks.toml");
+
+        // A world that was saved when stone and diamond had each other's IDs.
+        file::write("blocks = [\"air\", \"debug\", \"grass\", \"dirt\", \"diamond\", \"stone\", \"uninstalled\"]\n", &table_path).unwrap();
+
+        let ids = BlockIdTable::load(&table_path).unwrap();
+
+        assert_eq!(ids.saved_id(BlockType::STONE), 5);
+        assert_eq!(ids.saved_id(BlockType::DIAMOND), 4);
+        assert_eq!(ids.block(6), Some(BlockType::AIR));
+
+        let chunk = test_chunk(ChunkPos::new(0, 0, 0));
+        let mut region = RegionFile::open(&directory, RegionPos::new(0, 0, 0)).unwrap();
+
+        region.write_chunk(&chunk, ChunkCompression::Zlib, &ids).unwrap();
+        region.save().unwrap();
+
+        let region = RegionFile::open(&directory, RegionPos::new(0, 0, 0)).unwrap();
+
+        // The world's own table gets the same blocks back.
+        let loaded = region.read_chunk(chunk.pos, &ids).unwrap().unwrap();
+        assert_eq!(loaded.storage(), chunk.storage());
+
+        // The registry's IDs would mix them up, which is what the table is for.
+        let wrong = region.read_chunk(chunk.pos, &BlockIdTable::from_registry()).unwrap().unwrap();
+        assert_eq!(wrong.get_block(BlockPos::new_unchecked(5, 5, 10)), BlockType::DIAMOND);
+        assert_eq!(wrong.get_block(BlockPos::new_unchecked(5, 0, 0)), BlockType::STONE);
+
+        // Blocks that aren't registered anymore load as air. (An uncompressed chunk full of saved ID 6.)
+        let mut region = RegionFile::open(&directory, RegionPos::new(0, 0, 0)).unwrap();
+        region.entries[0] = Some(vec![ChunkCompression::None.id(), 0, 6, 0]);
+
+        assert_eq!(region.read_chunk(ChunkPos::new(0, 0, 0), &ids).unwrap().unwrap().get_block(BlockPos::new_unchecked(3, 3, 3)), BlockType::AIR);
+
+        fs_action::delete(&directory).unwrap();
+    }
+}
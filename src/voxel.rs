@@ -3,16 +3,17 @@
 use crate::block::*;
 use crate::world;
 use crate::mesher::MeshData;
-use crate::filesystem::*;
-use crate::places;
-use crate::asset_manager::{AtlasUVMapElement, BlockAtlasInfo};
+use crate::block_atlas::{AtlasTile, BlockAtlas};
 
-const ATLAS_PIXEL_CROP: f32 = 0.0;
+// How bright a face corner is, by how many of its three neighboring blocks are in the way. (Index is 3 - occluders.)
+const AO_BRIGHTNESS: [f32; 4] = [0.45, 0.65, 0.82, 1.0];
 
 pub struct Voxel {
     pub block: BlockType,
     pub position: (u8, u8, u8),
     sides: Vec<world::Direction>,
+    colors: [[f32; 4]; 6], // Vertex color of each side, in world::Direction::all() order.
+    occluders: u32, // Which of the 26 surrounding blocks cast ambient occlusion. (See occluder_bit())
 }
 
 impl Voxel {
@@ -21,6 +22,8 @@ impl Voxel {
             block,
             position,
             sides: Vec::new(),
+            colors: [[1.0; 4]; 6],
+            occluders: 0,
         };
     }
 
@@ -56,44 +59,89 @@ impl Voxel {
         }
     }
 
+    pub fn set_side_color(&mut self, direction: world::Direction, color: [f32; 4]) {
+        self.colors[direction.index()] = color;
+    }
+
+    pub fn set_occluders(&mut self, occluders: u32) {
+        self.occluders = occluders;
+    }
+
+    fn is_occluder(&self, offset: (isize, isize, isize)) -> bool {
+        self.occluders & occluder_bit(offset) != 0
+    }
+
+    // Ambient occlusion of a face corner (0 = darkest, 3 = none), from the blocks touching it in front of the face.
+    fn corner_ao(&self, normal: (isize, isize, isize), corner: (isize, isize, isize)) -> u8 {
+        // Move the corner in front of the face, then drop one of the other axes at a time to get the two sides.
+        let corner = (
+            if normal.0 != 0 { normal.0 } else { corner.0 },
+            if normal.1 != 0 { normal.1 } else { corner.1 },
+            if normal.2 != 0 { normal.2 } else { corner.2 },
+        );
+
+        let (side_1, side_2) = if normal.0 != 0 {
+            ((corner.0, corner.1, 0), (corner.0, 0, corner.2))
+        } else if normal.1 != 0 {
+            ((corner.0, corner.1, 0), (0, corner.1, corner.2))
+        } else {
+            ((corner.0, 0, corner.2), (0, corner.1, corner.2))
+        };
+
+        let (side_1, side_2) = (self.is_occluder(side_1), self.is_occluder(side_2));
+
+        if side_1 && side_2 {
+            return 0;
+        }
+
+        return 3 - side_1 as u8 - side_2 as u8 - self.is_occluder(corner) as u8;
+    }
+
+    /// Ambient occlusion of every corner of a side, in the same order as the vertices from get_side_as_mdi(). (0 = darkest, 3 = none)
+    pub fn side_ao(&self, direction: world::Direction) -> [u8; 4] {
+        let o = direction.offset();
+        let normal = (o.0 as isize, o.1 as isize, o.2 as isize);
+
+        return face_corners(direction).map(|c| self.corner_ao(normal, c));
+    }
+
     pub fn get_sides(&self) -> Vec<world::Direction> {
         return self.sides.clone();
     }
 
-    pub fn get_side_as_mdi(&self, direction: world::Direction, size: (u8, u8, u8)) -> Option<(Vec<MeshData>, Vec<u32>)> {
+    pub fn get_side_as_mdi(&self, direction: world::Direction, size: (u8, u8, u8), atlas: &BlockAtlas) -> Option<(Vec<MeshData>, Vec<u32>)> {
         if self.side_enabled(direction) == false {
             return None;
         }
 
-        let atlas_toml: BlockAtlasInfo = match toml::from_str(&match file::read(&places::custom_built_assets().add_str("block_atlas.toml")) {
-            Ok(o) => o,
-            Err(_) => return None, // Kind of a weird way to handle this error, I know. (This error should never trigger.)
-        }) {
-            Ok(o) => o,
-            Err(_) => return None,
-        };
-
-        let texture_name: String = match self.block.properties().textures.get(direction) {
+        // Where the texture is in the atlas. (The chunk shader keeps the UVs inside of it.)
+        let tile: AtlasTile = match atlas.tile(self.block, direction) {
             Some(s) => s,
             None => return None,
         };
 
-        let uv_mod: AtlasUVMapElement = *atlas_toml.uv_map.get(&texture_name).unwrap_or(&AtlasUVMapElement { corner: (0, 0) , size: (0, 0) });
-        let atlas_size: (u32, u32) = atlas_toml.size;
-
-        drop(atlas_toml);
-        drop(texture_name);
-
+        // Merged faces grow from the voxel towards the negative side of every axis.
         let (min_x, min_y, min_z) = (-0.5 - (size.0 - 1) as f32, -0.5 - (size.1 - 1) as f32, -0.5 - (size.2 - 1) as f32);
         let (max_x, max_y, max_z) = (0.5, 0.5, 0.5);
 
-        let indices = vec![0, 1, 2, 2, 3, 0];
+        // UVs count in textures instead of atlas coordinates, so the texture repeats once per block on merged faces.
+        let (repeat_x, repeat_y) = match direction {
+            world::Direction::North | world::Direction::South => (size.0, size.1),
+            world::Direction::East | world::Direction::West => (size.2, size.1),
+            world::Direction::Up | world::Direction::Down => (size.0, size.2),
+        };
 
-        let uv_locate_info_1: (u32, u32) = (uv_mod.corner.0, uv_mod.corner.1); // Top left.
-        let uv_locate_info_2: (u32, u32) = (uv_locate_info_1.0 + uv_mod.size.0, uv_locate_info_1.1 + uv_mod.size.1); // Bottom right.
+        // They also follow the block grid instead of starting over on every face, so neighboring faces share the exact same vertices.
+        // (Position along the UV's axis, and if the UV goes down as the position goes up.)
+        let ((pos_x, flip_x), (pos_y, flip_y)) = match direction {
+            world::Direction::North | world::Direction::South => ((self.position.0, true), (self.position.1, true)),
+            world::Direction::East | world::Direction::West => ((self.position.2, false), (self.position.1, true)),
+            world::Direction::Up => ((self.position.0, false), (self.position.2, false)),
+            world::Direction::Down => ((self.position.0, true), (self.position.2, true)),
+        };
 
-        let uv_limits_x: (f32, f32) = ((uv_locate_info_1.0 as f32 + ATLAS_PIXEL_CROP) / atlas_size.0 as f32, (uv_locate_info_2.0 as f32 - ATLAS_PIXEL_CROP * 1.0) / atlas_size.0 as f32);
-        let uv_limits_y: (f32, f32) = ((uv_locate_info_1.1 as f32 + ATLAS_PIXEL_CROP) / atlas_size.1 as f32, (uv_locate_info_2.1 as f32 - ATLAS_PIXEL_CROP * 1.0) / atlas_size.1 as f32);
+        let uv_limits_x: (f32, f32) = grid_uv_limits(pos_x, repeat_x, flip_x);
+        let uv_limits_y: (f32, f32) = grid_uv_limits(pos_y, repeat_y, flip_y);
 
         let mut general: Vec<([f32; 3], [f32; 3], [f32; 2])> = Vec::new();
 
@@ -160,47 +208,276 @@ impl Voxel {
             },
         };
 
-        let mesh_data: Vec<MeshData> = MeshData::array_from_general_array(&general);
+        let color = self.colors[direction.index()];
+        let ao = self.side_ao(direction);
+
+        // Split the quad along its brighter diagonal, otherwise the darkness gets smeared across the whole face.
+        let indices = if ao[1] as u32 + ao[3] as u32 > ao[0] as u32 + ao[2] as u32 {
+            vec![1, 2, 3, 3, 0, 1]
+        } else {
+            vec![0, 1, 2, 2, 3, 0]
+        };
+
+        let mesh_data: Vec<MeshData> = MeshData::array_from_general_array(&general)
+            .into_iter()
+            .zip(ao.iter())
+            .map(|(x, a)| {
+                let b = AO_BRIGHTNESS[*a as usize];
+
+                x.with_color([color[0] * b, color[1] * b, color[2] * b, color[3]])
+                    .with_tile(tile.to_array())
+                    .with_layer(tile.layer)
+            })
+            .collect();
 
         return Some((mesh_data, indices));
     }
 }
 
+// Where the UVs of a face start and end along one axis. (The voxel is at the end of the face, since merged faces grow towards the negative side.)
+fn grid_uv_limits(position: u8, repeat: u8, flipped: bool) -> (f32, f32) {
+    let end = position as i32 + 1;
+    let start = if flipped { -end } else { end - repeat as i32 };
+
+    return (start as f32, (start + repeat as i32) as f32);
+}
+
+// Which corner of the block each vertex of a side is on. (Same order as get_side_as_mdi())
+fn face_corners(direction: world::Direction) -> [(isize, isize, isize); 4] {
+    return match direction {
+        world::Direction::North => [(-1, 1, -1), (1, 1, -1), (1, -1, -1), (-1, -1, -1)],
+        world::Direction::South => [(-1, -1, 1), (1, -1, 1), (1, 1, 1), (-1, 1, 1)],
+        world::Direction::East => [(1, -1, -1), (1, 1, -1), (1, 1, 1), (1, -1, 1)],
+        world::Direction::West => [(-1, -1, 1), (-1, 1, 1), (-1, 1, -1), (-1, -1, -1)],
+        world::Direction::Up => [(1, 1, -1), (-1, 1, -1), (-1, 1, 1), (1, 1, 1)],
+        world::Direction::Down => [(1, -1, 1), (-1, -1, 1), (-1, -1, -1), (1, -1, -1)],
+    };
+}
+
+/// Bit of a neighboring block in Voxel occluder masks. (Offsets go from -1 to 1 on every axis.)
+pub fn occluder_bit(offset: (isize, isize, isize)) -> u32 {
+    1 << ((offset.0 + 1) * 9 + (offset.1 + 1) * 3 + (offset.2 + 1))
+}
+
+/// Build an occluder mask for Voxel::set_occluders(), by asking if the block at each offset is opaque.
+pub fn occluders_from(is_opaque: impl Fn((isize, isize, isize)) -> bool) -> u32 {
+    let mut occluders: u32 = 0;
+
+    for x in -1..=1 {
+        for y in -1..=1 {
+            for z in -1..=1 {
+                if (x, y, z) != (0, 0, 0) && is_opaque((x, y, z)) {
+                    occluders |= occluder_bit((x, y, z));
+                }
+            }
+        }
+    }
+
+    return occluders;
+}
+
 // MDI = MeshData and Indices
 pub mod mdi_from {
-    use crate::mesher::{self, MeshData};
+    use std::sync::atomic::{AtomicBool, Ordering};
+    use hashbrown::HashMap;
+    use crate::block_atlas::AtlasTile;
+    use crate::log;
+    use crate::log::macro_deps::*;
+    use crate::mesher::{LayeredMeshBuilder, MeshBuilder};
     use crate::voxel::*;
 
-    pub fn voxel_array(voxels: &Vec<Voxel>) -> (Vec<MeshData>, Vec<u32>) {
-        let mut mesh_data: Vec<MeshData> = Vec::new();
-        let mut indices: Vec<u32> = Vec::new();
-    
-        for v in voxels.iter() {
-            let mdi = voxel(v);
+    // Chunks get meshed all the time, so faces without a texture are only warned about once.
+    static WARNED_MISSING_TILE: AtomicBool = AtomicBool::new(false);
+
+    // Faces can only be merged if all of this is the same.
+    #[derive(PartialEq, Clone)]
+    struct FaceKey {
+        layer: RenderLayer,
+        tile: Option<AtlasTile>,
+        color: [f32; 4],
+        ao: u8,
+    }
+
+    /// Merge neighboring faces that look the same into bigger quads. (Greedy meshing)
+    /// Faces with uneven ambient occlusion can't be stretched, so they are left as they are.
+    pub fn greedy(voxels: &Vec<Voxel>, atlas: &BlockAtlas) -> LayeredMeshBuilder {
+        let mut builder = LayeredMeshBuilder::new();
+        let layers = render_layers();
+
+        let mut lookup: HashMap<(u8, u8, u8), usize> = HashMap::new();
+        let mut bounds: [usize; 3] = [0; 3];
 
-            mesh_data.extend(mdi.0);
+        for (i, v) in voxels.iter().enumerate() {
+            if v.sides.is_empty() {
+                continue;
+            }
 
-            indices = mesher::combine_indices(&vec![indices, mdi.1]);
+            lookup.insert(v.position, i);
+
+            bounds[0] = bounds[0].max(v.position.0 as usize + 1);
+            bounds[1] = bounds[1].max(v.position.1 as usize + 1);
+            bounds[2] = bounds[2].max(v.position.2 as usize + 1);
         }
-    
-        return (mesh_data, indices);
+
+        for direction in world::Direction::all() {
+            // Axis the faces point along, then the axes of the texture's width and height. (Same as get_side_as_mdi())
+            let (normal, u_axis, v_axis) = match direction {
+                world::Direction::North | world::Direction::South => (2, 0, 1),
+                world::Direction::East | world::Direction::West => (0, 2, 1),
+                world::Direction::Up | world::Direction::Down => (1, 0, 2),
+            };
+
+            let (width, height) = (bounds[u_axis], bounds[v_axis]);
+
+            for slice in 0..bounds[normal] {
+                let mut mask: Vec<Option<(FaceKey, usize)>> = vec![None; width * height];
+
+                for u in 0..width {
+                    for v in 0..height {
+                        let mut pos = [0u8; 3];
+
+                        pos[normal] = slice as u8;
+                        pos[u_axis] = u as u8;
+                        pos[v_axis] = v as u8;
+
+                        let i = match lookup.get(&(pos[0], pos[1], pos[2])) {
+                            Some(s) => *s,
+                            None => continue,
+                        };
+
+                        let voxel = &voxels[i];
+
+                        if voxel.side_enabled(direction) == false {
+                            continue;
+                        }
+
+                        let ao = voxel.side_ao(direction);
+                        let layer = layers[voxel.block.id() as usize];
+
+                        if ao.iter().any(|x| *x != ao[0]) {
+                            if let Some(mdi) = side(voxel, direction, (1, 1, 1), atlas) {
+                                builder.layer_mut(layer).push(&mdi.0, &mdi.1);
+                            }
+
+                            continue;
+                        }
+
+                        mask[v * width + u] = Some((FaceKey {
+                            layer,
+                            tile: atlas.tile(voxel.block, direction),
+                            color: voxel.colors[direction.index()],
+                            ao: ao[0],
+                        }, i));
+                    }
+                }
+
+                // Grow every face as far as it goes along the width, then along the height.
+                for v in 0..height {
+                    for u in 0..width {
+                        let key = match &mask[v * width + u] {
+                            Some(s) => s.0.clone(),
+                            None => continue,
+                        };
+
+                        let same = |m: &Option<(FaceKey, usize)>| match m {
+                            Some(s) => s.0 == key,
+                            None => false,
+                        };
+
+                        let mut w = 1;
+
+                        while u + w < width && same(&mask[v * width + u + w]) {
+                            w += 1;
+                        }
+
+                        let mut h = 1;
+
+                        while v + h < height && (u..u + w).all(|x| same(&mask[(v + h) * width + x])) {
+                            h += 1;
+                        }
+
+                        // Merged faces grow towards the negative side, so the last voxel draws the whole quad.
+                        let owner = mask[(v + h - 1) * width + u + w - 1].as_ref().unwrap().1; // Guarenteed value.
+
+                        for y in v..v + h {
+                            for x in u..u + w {
+                                mask[y * width + x] = None;
+                            }
+                        }
+
+                        let mut size = [1u8; 3];
+
+                        size[u_axis] = w as u8;
+                        size[v_axis] = h as u8;
+
+                        if let Some(mdi) = side(&voxels[owner], direction, (size[0], size[1], size[2]), atlas) {
+                            builder.layer_mut(key.layer).push(&mdi.0, &mdi.1);
+                        }
+                    }
+                }
+            }
+        }
+
+        return builder;
     }
 
-    pub fn voxel(v: &Voxel) -> (Vec<MeshData>, Vec<u32>) {
-        let mut mesh_data: Vec<MeshData> = Vec::new();
-        let mut indices: Vec<u32> = Vec::new();
+    // An enabled side of a voxel. (None if it has no texture, in which case the face is left out.)
+    // Blocks can leave out faces on purpose, so only textures that are missing from the atlas get warned about.
+    fn side(v: &Voxel, direction: world::Direction, size: (u8, u8, u8), atlas: &BlockAtlas) -> Option<(Vec<MeshData>, Vec<u32>)> {
+        let mdi = v.get_side_as_mdi(direction, size, atlas);
 
-        for s in v.get_sides() {
-            let mdi = match v.get_side_as_mdi(s, (1, 1, 1)) {
-                Some(s) => s,
-                None => panic!("Oh shoot! I couldn't get a face for: {:?} (File this as a bug report!)", s), // If this is ever triggered, there is a bug.
-            };
+        if mdi.is_none() && v.block.properties().textures.get(direction).is_some() && WARNED_MISSING_TILE.swap(true, Ordering::Relaxed) == false {
+            log::warning!("The {:?} face of '{}' has no texture in the block atlas, so it isn't drawn! (Other missing textures won't be warned about.)", direction, v.block.properties().name);
+        }
+
+        return mdi;
+    }
+
+    // BlockType.properties() goes through the block registry, so the render layer of every block is looked up once. (Indexed by block ID.)
+    fn render_layers() -> Vec<RenderLayer> {
+        let mut layers: Vec<RenderLayer> = Vec::new();
 
-            mesh_data.extend(mdi.0);
+        for block in BlockType::all() {
+            let id = block.id() as usize;
 
-            indices = mesher::combine_indices(&vec![indices, mdi.1]);
+            if layers.len() <= id {
+                layers.resize(id + 1, RenderLayer::Opaque);
+            }
+
+            layers[id] = block.properties().render_layer;
         }
 
-        return (mesh_data, indices);
+        return layers;
+    }
+
+    pub fn voxel_array(voxels: &Vec<Voxel>, atlas: &BlockAtlas) -> LayeredMeshBuilder {
+        let layers = render_layers();
+
+        // Every face is 4 vertices and 2 triangles.
+        let mut faces: [usize; 3] = [0; 3];
+
+        for v in voxels.iter() {
+            faces[layers[v.block.id() as usize].index()] += v.sides.len();
+        }
+
+        let mut builder = LayeredMeshBuilder::new();
+
+        for layer in RenderLayer::ALL {
+            *builder.layer_mut(layer) = MeshBuilder::with_capacity(faces[layer.index()] * 4, faces[layer.index()] * 6);
+        }
+
+        for v in voxels.iter() {
+            voxel(v, atlas, builder.layer_mut(layers[v.block.id() as usize]));
+        }
+
+        return builder;
+    }
+
+    pub fn voxel(v: &Voxel, atlas: &BlockAtlas, builder: &mut MeshBuilder) {
+        for s in v.get_sides() {
+            if let Some(mdi) = side(v, s, (1, 1, 1), atlas) {
+                builder.push(&mdi.0, &mdi.1);
+            }
+        }
     }
 }
@@ -0,0 +1,49 @@
+#import bevy_pbr::mesh_bindings    mesh
+#import bevy_pbr::mesh_functions   mesh_position_local_to_clip
+
+@group(1) @binding(0)
+var block_textures: texture_2d_array<f32>;
+@group(1) @binding(1)
+var block_sampler: sampler;
+
+struct Vertex {
+    @location(0) position: vec3<f32>,
+    @location(1) uv: vec2<f32>,
+    @location(2) color: vec4<f32>,
+    @location(3) layer: u32,
+};
+
+struct VertexOutput {
+    @builtin(position) clip_position: vec4<f32>,
+    @location(0) uv: vec2<f32>,
+    @location(1) color: vec4<f32>,
+    @location(2) @interpolate(flat) layer: u32,
+};
+
+@vertex
+fn vertex(vertex: Vertex) -> VertexOutput {
+    var out: VertexOutput;
+
+    out.clip_position = mesh_position_local_to_clip(mesh.model, vec4<f32>(vertex.position, 1.0));
+    out.uv = vertex.uv;
+    out.color = vertex.color;
+    out.layer = vertex.layer;
+
+    return out;
+}
+
+@fragment
+fn fragment(in: VertexOutput) -> @location(0) vec4<f32> {
+    // The UVs count in textures, and the sampler repeats them, so merged faces tile without any extra work.
+    let color = textureSample(block_textures, block_sampler, in.uv, in.layer);
+
+#ifdef MAY_DISCARD
+    // Cutout blocks. (Set by AlphaMode::Mask)
+    if color.a < 0.5 {
+        discard;
+    }
+#endif
+
+    // Lighting and ambient occlusion are baked into the vertex colors.
+    return color * in.color;
+}
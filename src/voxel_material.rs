@@ -0,0 +1,143 @@
+#![allow(dead_code)]
+
+use bevy::asset::load_internal_asset;
+use bevy::pbr::{MaterialPipeline, MaterialPipelineKey};
+use bevy::prelude::*;
+use bevy::reflect::{TypePath, TypeUuid};
+use bevy::render::mesh::MeshVertexBufferLayout;
+use bevy::render::render_resource::{AsBindGroup, RenderPipelineDescriptor, ShaderRef, SpecializedMeshPipelineError};
+
+use crate::block::RenderLayer;
+use crate::mesher;
+
+pub struct VoxelMaterialPlugin;
+
+impl Plugin for VoxelMaterialPlugin {
+    fn build(&self, app: &mut App) {
+        load_internal_asset!(app, VOXEL_SHADER_HANDLE, "voxel_material.wgsl", Shader::from_wgsl);
+        load_internal_asset!(app, VOXEL_ARRAY_SHADER_HANDLE, "voxel_array_material.wgsl", Shader::from_wgsl);
+
+        app.add_plugins((MaterialPlugin::<VoxelMaterial>::default(), MaterialPlugin::<VoxelArrayMaterial>::default()));
+    }
+}
+
+const VOXEL_SHADER_HANDLE: HandleUntyped = HandleUntyped::weak_from_u64(Shader::TYPE_UUID, 7194506417731263542);
+const VOXEL_ARRAY_SHADER_HANDLE: HandleUntyped = HandleUntyped::weak_from_u64(Shader::TYPE_UUID, 7194506417731263543);
+
+// Every render layer of a chunk uses its own alpha mode.
+fn layer_alpha_mode(layer: RenderLayer) -> AlphaMode {
+    return match layer {
+        RenderLayer::Opaque => AlphaMode::Opaque,
+        RenderLayer::Cutout => AlphaMode::Mask(0.5),
+        RenderLayer::Translucent => AlphaMode::Blend,
+    };
+}
+
+/// Material for chunk meshes. Textures repeat inside of their spot in the block atlas, so merged faces tile correctly.
+/// (Needs the vertex colors and atlas tiles from mesher::create_mesh().)
+#[derive(AsBindGroup, TypeUuid, TypePath, Debug, Clone)]
+#[uuid = "3c4f6a0e-2d5b-4c1e-9f7a-8b2e5d6c1a93"]
+pub struct VoxelMaterial {
+    #[texture(0)]
+    #[sampler(1)]
+    pub atlas: Handle<Image>,
+    /// Every render layer of a chunk uses its own alpha mode. (Masks always cut off at half alpha, see the shader.)
+    pub alpha_mode: AlphaMode,
+}
+
+impl VoxelMaterial {
+    /// Material for one of the render layers of chunks.
+    pub fn for_layer(atlas: Handle<Image>, layer: RenderLayer) -> Self {
+        return Self {
+            atlas,
+            alpha_mode: layer_alpha_mode(layer),
+        };
+    }
+}
+
+impl Material for VoxelMaterial {
+    fn alpha_mode(&self) -> AlphaMode {
+        self.alpha_mode
+    }
+
+    fn vertex_shader() -> ShaderRef {
+        VOXEL_SHADER_HANDLE.typed().into()
+    }
+
+    fn fragment_shader() -> ShaderRef {
+        VOXEL_SHADER_HANDLE.typed().into()
+    }
+
+    fn specialize(
+        _pipeline: &MaterialPipeline<Self>,
+        descriptor: &mut RenderPipelineDescriptor,
+        layout: &MeshVertexBufferLayout,
+        _key: MaterialPipelineKey<Self>,
+    ) -> Result<(), SpecializedMeshPipelineError> {
+        let vertex_layout = layout.get_layout(&[
+            Mesh::ATTRIBUTE_POSITION.at_shader_location(0),
+            Mesh::ATTRIBUTE_UV_0.at_shader_location(1),
+            Mesh::ATTRIBUTE_COLOR.at_shader_location(2),
+            mesher::ATTRIBUTE_ATLAS_TILE.at_shader_location(3),
+        ])?;
+
+        descriptor.vertex.buffers = vec![vertex_layout];
+
+        Ok(())
+    }
+}
+
+/// Material for chunk meshes, that samples the block texture array instead of the atlas.
+/// Every texture has a whole layer to itself, so mipmaps and repeating never bleed between textures.
+/// (Needs the vertex colors and texture layers from mesher::create_mesh().)
+#[derive(AsBindGroup, TypeUuid, TypePath, Debug, Clone)]
+#[uuid = "8d1e2b7a-5f3c-4a6e-b0d9-4c7f1e8a2b65"]
+pub struct VoxelArrayMaterial {
+    #[texture(0, dimension = "2d_array")]
+    #[sampler(1)]
+    pub textures: Handle<Image>,
+    /// Same as VoxelMaterial::alpha_mode.
+    pub alpha_mode: AlphaMode,
+}
+
+impl VoxelArrayMaterial {
+    /// Material for one of the render layers of chunks.
+    pub fn for_layer(textures: Handle<Image>, layer: RenderLayer) -> Self {
+        return Self {
+            textures,
+            alpha_mode: layer_alpha_mode(layer),
+        };
+    }
+}
+
+impl Material for VoxelArrayMaterial {
+    fn alpha_mode(&self) -> AlphaMode {
+        self.alpha_mode
+    }
+
+    fn vertex_shader() -> ShaderRef {
+        VOXEL_ARRAY_SHADER_HANDLE.typed().into()
+    }
+
+    fn fragment_shader() -> ShaderRef {
+        VOXEL_ARRAY_SHADER_HANDLE.typed().into()
+    }
+
+    fn specialize(
+        _pipeline: &MaterialPipeline<Self>,
+        descriptor: &mut RenderPipelineDescriptor,
+        layout: &MeshVertexBufferLayout,
+        _key: MaterialPipelineKey<Self>,
+    ) -> Result<(), SpecializedMeshPipelineError> {
+        let vertex_layout = layout.get_layout(&[
+            Mesh::ATTRIBUTE_POSITION.at_shader_location(0),
+            Mesh::ATTRIBUTE_UV_0.at_shader_location(1),
+            Mesh::ATTRIBUTE_COLOR.at_shader_location(2),
+            mesher::ATTRIBUTE_TEXTURE_LAYER.at_shader_location(3),
+        ])?;
+
+        descriptor.vertex.buffers = vec![vertex_layout];
+
+        Ok(())
+    }
+}
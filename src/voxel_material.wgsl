@@ -0,0 +1,52 @@
+#import bevy_pbr::mesh_bindings    mesh
+#import bevy_pbr::mesh_functions   mesh_position_local_to_clip
+
+@group(1) @binding(0)
+var atlas_texture: texture_2d<f32>;
+@group(1) @binding(1)
+var atlas_sampler: sampler;
+
+struct Vertex {
+    @location(0) position: vec3<f32>,
+    @location(1) uv: vec2<f32>,
+    @location(2) color: vec4<f32>,
+    @location(3) tile: vec4<f32>,
+};
+
+struct VertexOutput {
+    @builtin(position) clip_position: vec4<f32>,
+    @location(0) uv: vec2<f32>,
+    @location(1) color: vec4<f32>,
+    @location(2) tile: vec4<f32>,
+};
+
+@vertex
+fn vertex(vertex: Vertex) -> VertexOutput {
+    var out: VertexOutput;
+
+    out.clip_position = mesh_position_local_to_clip(mesh.model, vec4<f32>(vertex.position, 1.0));
+    out.uv = vertex.uv;
+    out.color = vertex.color;
+    out.tile = vertex.tile;
+
+    return out;
+}
+
+@fragment
+fn fragment(in: VertexOutput) -> @location(0) vec4<f32> {
+    // The UVs count in textures, so wrapping them repeats the texture without leaving its spot in the atlas.
+    let atlas_uv = in.tile.xy + fract(in.uv) * in.tile.zw;
+
+    // Gradients come from the unwrapped UVs, otherwise the seams between repeats pick the smallest mip level.
+    let color = textureSampleGrad(atlas_texture, atlas_sampler, atlas_uv, dpdx(in.uv) * in.tile.zw, dpdy(in.uv) * in.tile.zw);
+
+#ifdef MAY_DISCARD
+    // Cutout blocks. (Set by AlphaMode::Mask)
+    if color.a < 0.5 {
+        discard;
+    }
+#endif
+
+    // Lighting and ambient occlusion are baked into the vertex colors.
+    return color * in.color;
+}
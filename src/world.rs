@@ -37,6 +37,29 @@ impl Direction {
         return (position.0 + o.0, position.1 + o.1, position.2 + o.2);
     }
 
+    pub fn opposite(&self) -> Self {
+        return match self {
+            Self::North => Self::South,
+            Self::South => Self::North,
+            Self::East => Self::West,
+            Self::West => Self::East,
+            Self::Up => Self::Down,
+            Self::Down => Self::Up,
+        };
+    }
+
+    /// Position of the direction in Direction::all().
+    pub fn index(&self) -> usize {
+        return match self {
+            Self::North => 0,
+            Self::South => 1,
+            Self::East => 2,
+            Self::West => 3,
+            Self::Up => 4,
+            Self::Down => 5,
+        };
+    }
+
     pub fn all() -> Vec<Self> {
         return vec![
             Self::North,
@@ -17,19 +17,21 @@ pub fn regular(chunk: &mut Chunk) {
     ns.set_octaves(8);
     ns.set_noise_type(NoiseType::Perlin);
 
+    let sampler = NoiseSampler::new(SEED, &ns);
+
     for x in 0..CHUNK_SIZE.x {
         for y in 0..CHUNK_SIZE.y {
             for z in 0..CHUNK_SIZE.z {
                 let block_pos = BlockPos::new_unchecked(x, y, z);
                 let (wx, wy, wz) = chunk.pos_local_to_global(block_pos);
 
-                let a = noise_3d((wx as f32, wy as f32, wz as f32), SEED, &ns);
+                let a = sampler.sample_3d((wx as f32, wy as f32, wz as f32));
 
                 if a > 25.0 {
                     let block = random::choice(&vec![
-                        BlockType::Dirt,
-                        BlockType::Stone,
-                        BlockType::Diamond,
+                        BlockType::DIRT,
+                        BlockType::STONE,
+                        BlockType::DIAMOND,
                     ]);
 
                     chunk.set_block(block_pos, block);
@@ -37,4 +39,6 @@ pub fn regular(chunk: &mut Chunk) {
             }
         }
     }
+
+    chunk.optimize_storage();
 }